@@ -0,0 +1,16 @@
+#![no_main]
+
+use dh_p2p::cloud::{DHResponse, P2PChannel, P2PServer, RelayAgent, RelayServer};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(Some(response)) = DHResponse::parse_response(data) else {
+        return;
+    };
+
+    // Every body the handshake deserializes
+    let _ = response.body::<P2PServer>("fuzz");
+    let _ = response.body::<RelayServer>("fuzz");
+    let _ = response.body::<RelayAgent>("fuzz");
+    let _ = response.body::<P2PChannel>("fuzz");
+});
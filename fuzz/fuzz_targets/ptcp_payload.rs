@@ -0,0 +1,8 @@
+#![no_main]
+
+use dh_p2p::ptcp::PTCPPayload;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = PTCPPayload::parse(data);
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use dh_p2p::ptcp::PTCPPacket;
+use libfuzzer_sys::fuzz_target;
+
+// Every packet the decoder accepts is encoded back into the same packet
+fuzz_target!(|data: &[u8]| {
+    let Ok(packet) = PTCPPacket::parse(data) else {
+        return;
+    };
+
+    let decoded = PTCPPacket::parse(&packet.serialize()).expect("serialized packet rejected");
+    assert_eq!(decoded, packet);
+});
@@ -0,0 +1,261 @@
+use base64::Engine;
+use md5::{Digest, Md5};
+
+/**
+ * Username and password used to log in to the device
+ */
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/**
+ * Authentication challenge from a `WWW-Authenticate` header
+ */
+#[derive(Clone, Debug)]
+pub enum Challenge {
+    Basic,
+    Digest {
+        realm: String,
+        nonce: String,
+        qop: Option<String>,
+        opaque: Option<String>,
+    },
+}
+
+fn md5_hex(data: &str) -> String {
+    Md5::digest(data.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/**
+ * Split `key="value", key=value` pairs, keeping commas inside quotes
+ */
+fn parse_params(s: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = s.trim();
+
+    while !rest.is_empty() {
+        let Some(eq) = rest.find('=') else {
+            break;
+        };
+        let key = rest[..eq].trim().to_lowercase();
+        rest = rest[eq + 1..].trim_start();
+
+        let value;
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            value = quoted[..end].to_string();
+            rest = quoted.get(end + 1..).unwrap_or("");
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            value = rest[..end].trim().to_string();
+            rest = &rest[end..];
+        }
+
+        params.push((key, value));
+        rest = rest.trim_start().trim_start_matches(',').trim_start();
+    }
+
+    params
+}
+
+impl Challenge {
+    /**
+     * Pick the strongest challenge among the `WWW-Authenticate` header values
+     */
+    pub fn parse<'a>(headers: impl Iterator<Item = &'a str>) -> Option<Challenge> {
+        let mut basic = None;
+
+        for header in headers {
+            let (scheme, params) = header.trim().split_once(' ').unwrap_or((header, ""));
+
+            if scheme.eq_ignore_ascii_case("basic") {
+                basic = Some(Challenge::Basic);
+            } else if scheme.eq_ignore_ascii_case("digest") {
+                let params = parse_params(params);
+                let get = |k: &str| {
+                    params
+                        .iter()
+                        .find(|(key, _)| key == k)
+                        .map(|(_, v)| v.clone())
+                };
+
+                return Some(Challenge::Digest {
+                    realm: get("realm").unwrap_or_default(),
+                    nonce: get("nonce").unwrap_or_default(),
+                    // Only "auth" is supported, "auth-int" would need the body
+                    qop: get("qop").filter(|q| q.split(',').any(|q| q.trim() == "auth")),
+                    opaque: get("opaque"),
+                });
+            }
+        }
+
+        basic
+    }
+}
+
+impl Credentials {
+    /**
+     * Build the `Authorization` header value answering the challenge
+     */
+    pub fn authorization(&self, challenge: &Challenge, method: &str, uri: &str, nc: u32) -> String {
+        match challenge {
+            Challenge::Basic => format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD
+                    .encode(format!("{}:{}", self.username, self.password))
+            ),
+            Challenge::Digest {
+                realm,
+                nonce,
+                qop,
+                opaque,
+            } => {
+                let ha1 = md5_hex(&format!("{}:{}:{}", self.username, realm, self.password));
+                let ha2 = md5_hex(&format!("{}:{}", method, uri));
+
+                let mut header = format!(
+                    "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\"",
+                    self.username, realm, nonce, uri
+                );
+
+                match qop {
+                    Some(_) => {
+                        let cnonce = format!("{:016x}", rand::random::<u64>());
+                        let response = md5_hex(&format!(
+                            "{}:{}:{:08x}:{}:auth:{}",
+                            ha1, nonce, nc, cnonce, ha2
+                        ));
+                        header += &format!(
+                            ", qop=auth, nc={:08x}, cnonce=\"{}\", response=\"{}\"",
+                            nc, cnonce, response
+                        );
+                    }
+                    None => {
+                        let response = md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2));
+                        header += &format!(", response=\"{}\"", response);
+                    }
+                }
+
+                if let Some(opaque) = opaque {
+                    header += &format!(", opaque=\"{}\"", opaque);
+                }
+
+                header
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Example of RFC 2617 section 3.5
+    const CHALLENGE: &str = "Digest realm=\"testrealm@host.com\", qop=\"auth,auth-int\", \
+        nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"";
+
+    fn credentials() -> Credentials {
+        Credentials {
+            username: "Mufasa".to_string(),
+            password: "Circle Of Life".to_string(),
+        }
+    }
+
+    fn param<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+        let start = header.find(&format!(" {}=", key))? + key.len() + 2;
+        let value = header[start..].split(',').next()?;
+        Some(value.trim_matches('"'))
+    }
+
+    #[test]
+    fn parse_challenges() {
+        let challenge = Challenge::parse(["Basic realm=\"device\"", CHALLENGE].into_iter());
+        match challenge {
+            Some(Challenge::Digest {
+                realm,
+                nonce,
+                qop,
+                opaque,
+            }) => {
+                assert_eq!(realm, "testrealm@host.com");
+                assert_eq!(nonce, "dcd98b7102dd2f0e8b11d0f600bfb0c093");
+                assert_eq!(qop.as_deref(), Some("auth,auth-int"));
+                assert_eq!(opaque.as_deref(), Some("5ccc069c403ebaf9f0171e9517f40e41"));
+            }
+            _ => panic!("expected a digest challenge"),
+        }
+
+        assert!(matches!(
+            Challenge::parse(["Basic realm=\"device\""].into_iter()),
+            Some(Challenge::Basic)
+        ));
+        assert!(Challenge::parse(["Negotiate"].into_iter()).is_none());
+
+        // auth-int alone is not supported, the digest is computed without qop
+        let challenge =
+            Challenge::parse(["Digest realm=\"r\", nonce=\"n\", qop=auth-int"].into_iter());
+        assert!(matches!(
+            challenge,
+            Some(Challenge::Digest { qop: None, .. })
+        ));
+    }
+
+    #[test]
+    fn basic() {
+        let credentials = Credentials {
+            username: "Aladdin".to_string(),
+            password: "open sesame".to_string(),
+        };
+
+        assert_eq!(
+            credentials.authorization(&Challenge::Basic, "GET", "/", 1),
+            "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+        );
+    }
+
+    #[test]
+    fn digest() {
+        let challenge = Challenge::parse([CHALLENGE].into_iter()).unwrap();
+        let header = credentials().authorization(&challenge, "GET", "/dir/index.html", 1);
+
+        assert!(header.starts_with("Digest username=\"Mufasa\""));
+        assert_eq!(param(&header, "uri"), Some("/dir/index.html"));
+        assert_eq!(param(&header, "qop"), Some("auth"));
+        assert_eq!(param(&header, "nc"), Some("00000001"));
+        assert_eq!(
+            param(&header, "opaque"),
+            Some("5ccc069c403ebaf9f0171e9517f40e41")
+        );
+
+        // HA1 and HA2 of the RFC example, with the cnonce picked by the client
+        let cnonce = param(&header, "cnonce").unwrap();
+        let expected = md5_hex(&format!(
+            "939e7578ed9e3c518a452acee763bce9:dcd98b7102dd2f0e8b11d0f600bfb0c093:00000001:{}:auth:39aff3a2bab6126f332b942af96d3366",
+            cnonce
+        ));
+        assert_eq!(param(&header, "response"), Some(expected.as_str()));
+    }
+
+    #[test]
+    fn digest_without_qop() {
+        let challenge = Challenge::Digest {
+            realm: "testrealm@host.com".to_string(),
+            nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093".to_string(),
+            qop: None,
+            opaque: None,
+        };
+        let header = credentials().authorization(&challenge, "GET", "/dir/index.html", 1);
+
+        assert_eq!(
+            header,
+            "Digest username=\"Mufasa\", realm=\"testrealm@host.com\", \
+            nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", uri=\"/dir/index.html\", \
+            response=\"670fd8c2df070c60b045671b8b24ff02\""
+        );
+    }
+}
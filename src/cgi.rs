@@ -0,0 +1,470 @@
+use std::{io, sync::Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream};
+
+use crate::{
+    auth::{Challenge, Credentials},
+    tunnel::Tunnel,
+};
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub code: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn headers<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.headers
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub type Body = BufReader<DuplexStream>;
+
+/// Largest body or multipart part accepted, snapshots stay well below it
+const MAX_BODY: usize = 16 * 1024 * 1024;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/**
+ * Client of the device's HTTP CGI API, each request runs over a new realm
+ */
+pub struct CgiClient {
+    tunnel: Tunnel,
+    remote_port: u16,
+    credentials: Option<Credentials>,
+    /// Last challenge and its nonce count, reused to skip the 401 round trip
+    challenge: Mutex<Option<(Challenge, u32)>>,
+}
+
+async fn read_head(reader: &mut Body) -> io::Result<HttpResponse> {
+    let mut status = String::new();
+    if reader.read_line(&mut status).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut parts = status.trim().splitn(3, ' ');
+    if !parts.next().unwrap_or_default().starts_with("HTTP/") {
+        return Err(invalid_data(format!(
+            "Invalid HTTP status line: {}",
+            status
+        )));
+    }
+    let code = parts
+        .next()
+        .and_then(|c| c.parse().ok())
+        .ok_or_else(|| invalid_data(format!("Invalid HTTP status line: {}", status)))?;
+    let reason = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((k, v)) = line.split_once(':') {
+            headers.push((k.trim().to_string(), v.trim().to_string()));
+        }
+    }
+
+    Ok(HttpResponse {
+        code,
+        reason,
+        headers,
+    })
+}
+
+/**
+ * Read the whole body, by length, chunked or until the device closes the realm
+ */
+pub async fn read_body(res: &HttpResponse, reader: &mut Body) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+
+    if res
+        .header("Transfer-Encoding")
+        .is_some_and(|t| t.eq_ignore_ascii_case("chunked"))
+    {
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await?;
+            let size = line.trim().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| invalid_data(format!("Invalid chunk size: {}", line.trim())))?;
+
+            if size == 0 {
+                return Ok(body);
+            }
+            if size > MAX_BODY - body.len() {
+                return Err(invalid_data(format!("HTTP body too large: {} bytes", size)));
+            }
+
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..]).await?;
+
+            // CRLF after the chunk
+            line.clear();
+            reader.read_line(&mut line).await?;
+        }
+    }
+
+    match res.header("Content-Length").and_then(|l| l.parse().ok()) {
+        Some(length) if length > MAX_BODY => {
+            return Err(invalid_data(format!(
+                "HTTP body too large: {} bytes",
+                length
+            )));
+        }
+        Some(length) => {
+            body.resize(length, 0);
+            reader.read_exact(&mut body).await?;
+        }
+        None => {
+            reader
+                .take(MAX_BODY as u64 + 1)
+                .read_to_end(&mut body)
+                .await?;
+            if body.len() > MAX_BODY {
+                return Err(invalid_data("HTTP body too large".to_string()));
+            }
+        }
+    }
+
+    Ok(body)
+}
+
+/**
+ * Parse `key=value` lines, as answered by `magicBox.cgi` and `configManager.cgi`
+ */
+fn parse_table(body: &str) -> Vec<(String, String)> {
+    body.lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect()
+}
+
+impl CgiClient {
+    pub fn new(tunnel: Tunnel, remote_port: u16, credentials: Option<Credentials>) -> CgiClient {
+        CgiClient {
+            tunnel,
+            remote_port,
+            credentials,
+            challenge: Mutex::new(None),
+        }
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        uri: &str,
+        headers: &[(&str, String)],
+    ) -> io::Result<(HttpResponse, Body)> {
+        let mut stream = self.tunnel.open(self.remote_port).await?;
+
+        let mut req = format!("{} {} HTTP/1.1\r\n", method, uri);
+        req += &format!("Host: 127.0.0.1:{}\r\n", self.remote_port);
+        req += "User-Agent: dh-p2p\r\n";
+        req += "Connection: close\r\n";
+
+        let authorization = match (&self.credentials, &mut *self.challenge.lock().unwrap()) {
+            (Some(credentials), Some((challenge, nc))) => {
+                *nc += 1;
+                Some(credentials.authorization(challenge, method, uri, *nc))
+            }
+            _ => None,
+        };
+        if let Some(authorization) = authorization {
+            req += &format!("Authorization: {}\r\n", authorization);
+        }
+
+        for (k, v) in headers {
+            req += &format!("{}: {}\r\n", k, v);
+        }
+        req += "\r\n";
+
+        stream.write_all(req.as_bytes()).await?;
+
+        let mut reader = BufReader::new(stream);
+        let res = read_head(&mut reader).await?;
+
+        Ok((res, reader))
+    }
+
+    /**
+     * Send a request and return the response head with the unread body,
+     * answering an authentication challenge when needed
+     */
+    pub async fn open(
+        &self,
+        method: &str,
+        uri: &str,
+        headers: &[(&str, String)],
+    ) -> io::Result<(HttpResponse, Body)> {
+        let (mut res, mut reader) = self.send(method, uri, headers).await?;
+
+        if res.code == 401 && self.credentials.is_some() {
+            // The nonce may have expired, start over with the new challenge
+            if let Some(challenge) = Challenge::parse(res.headers("WWW-Authenticate")) {
+                *self.challenge.lock().unwrap() = Some((challenge, 0));
+                (res, reader) = self.send(method, uri, headers).await?;
+            }
+        }
+
+        if res.code >= 300 {
+            return Err(io::Error::other(format!(
+                "HTTP {} {} failed: {} {}",
+                method, uri, res.code, res.reason
+            )));
+        }
+
+        Ok((res, reader))
+    }
+
+    /**
+     * GET a resource and return its body
+     */
+    pub async fn get(&self, uri: &str) -> io::Result<Vec<u8>> {
+        let (res, mut reader) = self.open("GET", uri, &[]).await?;
+        read_body(&res, &mut reader).await
+    }
+
+    /**
+     * GET a `key=value` table
+     */
+    pub async fn get_table(&self, uri: &str) -> io::Result<Vec<(String, String)>> {
+        let body = self.get(uri).await?;
+        Ok(parse_table(&String::from_utf8_lossy(&body)))
+    }
+}
+
+/**
+ * Parts of a `multipart/x-mixed-replace` body, as streamed by `eventManager.cgi`
+ */
+pub struct Multipart {
+    reader: Body,
+    delimiter: String,
+    /// The delimiter of the next part was already consumed
+    in_part: bool,
+    ended: bool,
+}
+
+impl Multipart {
+    pub fn new(res: &HttpResponse, reader: Body) -> io::Result<Multipart> {
+        let boundary = res
+            .header("Content-Type")
+            .and_then(|t| {
+                t.split(';')
+                    .find_map(|p| p.trim().strip_prefix("boundary="))
+            })
+            .ok_or_else(|| invalid_data("Missing multipart boundary".to_string()))?;
+
+        Ok(Multipart {
+            reader,
+            delimiter: format!("--{}", boundary.trim_matches('"').trim_start_matches("--")),
+            in_part: false,
+            ended: false,
+        })
+    }
+
+    /**
+     * Read the next part, `None` at the end of the stream
+     */
+    pub async fn next(&mut self) -> io::Result<Option<Vec<u8>>> {
+        while !self.in_part {
+            let mut line = String::new();
+            if self.ended || self.reader.read_line(&mut line).await? == 0 {
+                return Ok(None);
+            }
+
+            let line = line.trim();
+            self.ended = line == format!("{}--", self.delimiter);
+            self.in_part = line == self.delimiter;
+        }
+        self.in_part = false;
+
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).await? == 0 {
+                return Ok(None);
+            }
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+
+            if let Some((k, v)) = line.split_once(':') {
+                if k.trim().eq_ignore_ascii_case("Content-Length") {
+                    length = v.trim().parse::<usize>().ok();
+                }
+            }
+        }
+
+        if let Some(length) = length {
+            if length > MAX_BODY {
+                return Err(invalid_data(format!("Part too large: {} bytes", length)));
+            }
+            let mut body = vec![0u8; length];
+            self.reader.read_exact(&mut body).await?;
+            return Ok(Some(body));
+        }
+
+        // Without a length the part ends at the next delimiter
+        let mut body = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            if self.reader.read_until(b'\n', &mut line).await? == 0 {
+                return Ok(Some(body));
+            }
+
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim();
+            if text.starts_with(&self.delimiter) {
+                self.ended = text == format!("{}--", self.delimiter);
+                self.in_part = !self.ended;
+
+                // The line break before the delimiter belongs to it
+                if body.ends_with(b"\r\n") {
+                    body.truncate(body.len() - 2);
+                }
+                return Ok(Some(body));
+            }
+            body.extend_from_slice(&line);
+
+            if body.len() > MAX_BODY {
+                return Err(invalid_data("Part too large".to_string()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(content_type: &str) -> HttpResponse {
+        HttpResponse {
+            code: 200,
+            reason: "OK".to_string(),
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+        }
+    }
+
+    /**
+     * Parts of a body streamed with the given content type
+     */
+    async fn parts(content_type: &str, body: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let (mut tx, rx) = tokio::io::duplex(body.len() + 1);
+        tx.write_all(body).await.unwrap();
+        drop(tx);
+
+        let mut multipart = Multipart::new(&response(content_type), BufReader::new(rx))?;
+        let mut parts = Vec::new();
+        while let Some(part) = multipart.next().await? {
+            parts.push(part);
+        }
+        Ok(parts)
+    }
+
+    #[tokio::test]
+    async fn with_length() {
+        let body = b"--myboundary\r\nContent-Type: text/plain\r\nContent-Length: 9\r\n\r\nHeartbeat\r\n\
+            --myboundary\r\nContent-Type: text/plain\r\ncontent-length: 13\r\n\r\nCode=A\r\n--x\r\n\r\n";
+
+        let parts = parts("multipart/x-mixed-replace; boundary=myboundary", body)
+            .await
+            .unwrap();
+
+        // The length takes precedence over what looks like a delimiter
+        assert_eq!(
+            parts,
+            vec![b"Heartbeat".to_vec(), b"Code=A\r\n--x\r\n".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn without_length() {
+        let body = b"preamble\r\n--myboundary\r\nContent-Type: text/plain\r\n\r\nCode=A;action=Start\r\nCode=B;action=Stop\r\n\
+            --myboundary\r\n\r\nHeartbeat\r\n--myboundary--\r\n--myboundary\r\n\r\nafter the end\r\n";
+
+        let parts = parts("multipart/x-mixed-replace; boundary=\"--myboundary\"", body)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            parts,
+            vec![
+                b"Code=A;action=Start\r\nCode=B;action=Stop".to_vec(),
+                b"Heartbeat".to_vec(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_stream() {
+        let body = b"--myboundary\r\n\r\nCode=A\r\n--myboundary\r\nContent-Type: text/plain";
+
+        let parts = parts("multipart/x-mixed-replace; boundary=myboundary", body)
+            .await
+            .unwrap();
+
+        assert_eq!(parts, vec![b"Code=A".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn missing_boundary() {
+        let e = parts("text/plain", b"").await.unwrap_err();
+
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn part_too_large() {
+        let body = format!(
+            "--myboundary\r\nContent-Length: {}\r\n\r\nCode=A\r\n",
+            MAX_BODY + 1
+        );
+
+        let e = parts(
+            "multipart/x-mixed-replace; boundary=myboundary",
+            body.as_bytes(),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn chunk_too_large() {
+        let mut res = response("text/plain");
+        res.headers
+            .push(("Transfer-Encoding".to_string(), "chunked".to_string()));
+
+        let (mut tx, rx) = tokio::io::duplex(64);
+        tx.write_all(b"4\r\nCode\r\nfffffffff\r\n").await.unwrap();
+        drop(tx);
+
+        let e = read_body(&res, &mut BufReader::new(rx)).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
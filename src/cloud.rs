@@ -0,0 +1,815 @@
+use async_trait::async_trait;
+use base64::Engine;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha1::Digest;
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    time::{self, Duration, Instant},
+};
+use xml::EmitterConfig;
+
+use crate::net::{canonical, destination};
+
+pub static MAIN_SERVER: &str = "www.easy4ipcloud.com:8800";
+
+/// Time given to each request of the handshake, including retransmissions
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time given to the device to answer the P2P channel request
+const CHANNEL_TIMEOUT: Duration = Duration::from_secs(20);
+/// Delay before the first retransmission, doubled after each one
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(500);
+/// Size limit of a response spanning several datagrams
+const MAX_RESPONSE: usize = 65536;
+
+static USERNAME: &str = "cba1b29e32cb17aa46b8ff9e73c7f40b";
+static USERKEY: &str = "996103384cdf19179e19243e959bbf8b";
+
+/// Body of `/device/{serial}/p2p-channel`
+#[derive(Debug, Serialize)]
+#[serde(rename = "body")]
+pub struct P2PChannelRequest {
+    /// Client ID, as space separated hex bytes
+    #[serde(rename = "Identify")]
+    pub identify: String,
+    #[serde(rename = "IpEncrpt")]
+    pub ip_encrypt: bool,
+    #[serde(rename = "LocalAddr")]
+    pub local_addr: String,
+    pub version: String,
+}
+
+/// Body of `/relay/start/{token}`
+#[derive(Debug, Serialize)]
+#[serde(rename = "body")]
+pub struct RelayStartRequest {
+    #[serde(rename = "Client")]
+    pub client: String,
+}
+
+/// Body of `/device/{serial}/relay-channel`
+#[derive(Debug, Serialize)]
+#[serde(rename = "body")]
+pub struct RelayChannelRequest {
+    #[serde(rename = "agentAddr")]
+    pub agent_addr: String,
+}
+
+/// Answer of `/online/p2psrv/{serial}`
+#[derive(Debug, Deserialize)]
+pub struct P2PServer {
+    #[serde(rename = "US")]
+    pub us: String,
+}
+
+/// Answer of `/online/relay`
+#[derive(Debug, Deserialize)]
+pub struct RelayServer {
+    #[serde(rename = "Address")]
+    pub address: String,
+}
+
+/// Answer of `/relay/agent`
+#[derive(Debug, Deserialize)]
+pub struct RelayAgent {
+    #[serde(rename = "Token")]
+    pub token: String,
+    #[serde(rename = "Agent")]
+    pub agent: String,
+}
+
+/// Answer of `/device/{serial}/p2p-channel`, addresses of the device
+#[derive(Debug, Deserialize)]
+pub struct P2PChannel {
+    #[serde(rename = "LocalAddr")]
+    pub local_addr: String,
+    #[serde(rename = "PubAddr")]
+    pub pub_addr: String,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct DHResponse {
+    version: String,
+    code: u16,
+    status: String,
+    /// Header names are lowercase
+    headers: HashMap<String, String>,
+    body: Option<String>,
+}
+
+impl DHResponse {
+    /**
+     * Parse a response received so far, `None` when more datagrams are expected
+     */
+    pub fn parse_response(data: &[u8]) -> io::Result<Option<DHResponse>> {
+        if !data.starts_with(&b"HTTP/"[..data.len().min(5)]) {
+            return Err(invalid("Not a response"));
+        }
+
+        let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") else {
+            return Ok(None);
+        };
+        let head = String::from_utf8_lossy(&data[..end]);
+        let mut lines = head.split("\r\n");
+
+        // The reason may contain spaces, or be missing
+        let mut status_line = lines.next().unwrap_or_default().splitn(3, ' ');
+        let version = status_line.next().unwrap_or_default().to_string();
+        let code = status_line
+            .next()
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| invalid(format!("Invalid status line: {}", head)))?;
+        let status = status_line.next().unwrap_or_default().trim().to_string();
+
+        let mut headers = HashMap::new();
+        for line in lines {
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid(format!("Invalid header: {}", line)))?;
+            headers.insert(key.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+
+        let mut body = &data[end + 4..];
+        if let Some(length) = headers.get("content-length") {
+            let length: usize = length
+                .parse()
+                .map_err(|_| invalid(format!("Invalid Content-Length: {}", length)))?;
+
+            // The body continues in the next datagrams
+            if body.len() < length {
+                return Ok(None);
+            }
+            body = &body[..length];
+        }
+
+        let body = String::from_utf8_lossy(body);
+        let body = match body.trim().len() {
+            0 => None,
+            _ => Some(body.into_owned()),
+        };
+
+        Ok(Some(DHResponse {
+            version,
+            code,
+            status,
+            headers,
+            body,
+        }))
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    fn cseq(&self) -> Option<u32> {
+        self.header("CSeq")?.parse().ok()
+    }
+
+    /**
+     * Deserialize the XML body, answered by the handshake step `step`
+     */
+    pub fn body<T: DeserializeOwned>(&self, step: &str) -> io::Result<T> {
+        let body = self.body.as_deref().ok_or_else(|| {
+            invalid(format!(
+                "Handshake step \"{}\" answered without a body",
+                step
+            ))
+        })?;
+
+        serde_xml_rs::from_str(body).map_err(|e| {
+            invalid(format!(
+                "Handshake step \"{}\" answered an invalid body: {}",
+                step, e
+            ))
+        })
+    }
+}
+
+/**
+ * A request sent to a cloud server, kept to be retransmitted
+ */
+struct DHRequest {
+    /// Handshake step, named in errors
+    step: &'static str,
+    cseq: u32,
+    data: Vec<u8>,
+    to: SocketAddr,
+}
+
+#[async_trait]
+trait DHP2P {
+    async fn dh_request(
+        &self,
+        to: SocketAddr,
+        step: &'static str,
+        path: &str,
+        body: Option<&str>,
+        cseq: u32,
+    ) -> io::Result<DHRequest>;
+    async fn dh_read_raw(&self) -> io::Result<DHResponse>;
+
+    /**
+     * Wait for the answer to a request, retransmitting it with exponential backoff
+     * until it is acknowledged or `timeout` elapses.
+     * Answers to other requests are skipped when `match_cseq` is set
+     */
+    async fn dh_wait(
+        &self,
+        req: &DHRequest,
+        timeout: Duration,
+        match_cseq: bool,
+    ) -> io::Result<DHResponse>;
+}
+
+#[async_trait]
+impl DHP2P for UdpSocket {
+    async fn dh_request(
+        &self,
+        to: SocketAddr,
+        step: &'static str,
+        path: &str,
+        body: Option<&str>,
+        cseq: u32,
+    ) -> io::Result<DHRequest> {
+        let method = match body {
+            Some(_) => "DHPOST",
+            None => "DHGET",
+        };
+
+        let body = body.unwrap_or_default();
+
+        // random a 32-bit number
+        let nonce = rand::random::<u32>();
+        // iso8601 time string
+        let currdate = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let pwd = format!("{}{}DHP2P:{}:{}", nonce, currdate, USERNAME, USERKEY);
+
+        // sha1 then base64
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(pwd);
+        let hash_digest = hasher.finalize();
+        let digest = base64::engine::general_purpose::STANDARD.encode(hash_digest);
+
+        let req = format!("\
+            {} {} HTTP/1.1\r\n\
+            CSeq: {}\r\n\
+            Authorization: WSSE profile=\"UsernameToken\"\r\n\
+            X-WSSE: UsernameToken Username=\"{}\", PasswordDigest=\"{}\", Nonce=\"{}\", Created=\"{}\"\r\n\r\n{}",
+            method, path, cseq, USERNAME, digest, nonce, currdate, body,
+        );
+
+        eprintln!(">>> {}", to);
+        eprintln!("{}", req);
+        eprintln!("---");
+
+        self.send_to(req.as_bytes(), destination(self, to)).await?;
+
+        Ok(DHRequest {
+            step,
+            cseq,
+            data: req.into_bytes(),
+            to,
+        })
+    }
+
+    async fn dh_read_raw(&self) -> io::Result<DHResponse> {
+        let mut buf = [0u8; 4096];
+        let mut data = Vec::new();
+        let mut sender = None;
+
+        loop {
+            let (n, from) = self.recv_from(&mut buf).await?;
+            let from = canonical(from);
+
+            // A response is only continued by its own sender
+            if sender.is_some_and(|sender| sender != from) {
+                data.clear();
+            }
+            sender = Some(from);
+            data.extend_from_slice(&buf[0..n]);
+
+            eprintln!("<<< {}", from);
+            eprintln!("{}", String::from_utf8_lossy(&buf[0..n]));
+            eprintln!("---");
+
+            if let Some(res) = DHResponse::parse_response(&data)? {
+                eprintln!("{:?}", res);
+                return Ok(res);
+            }
+
+            if data.len() > MAX_RESPONSE {
+                return Err(invalid("Response too large"));
+            }
+        }
+    }
+
+    async fn dh_wait(
+        &self,
+        req: &DHRequest,
+        timeout: Duration,
+        match_cseq: bool,
+    ) -> io::Result<DHResponse> {
+        let deadline = Instant::now() + timeout;
+        let mut interval = RETRANSMIT_INTERVAL;
+        let mut retransmit = Instant::now() + interval;
+        let mut attempts = 1;
+        // A provisional answer means the request arrived
+        let mut acknowledged = false;
+
+        loop {
+            let wake = match acknowledged {
+                true => deadline,
+                false => retransmit.min(deadline),
+            };
+
+            let res = match time::timeout_at(wake, self.dh_read_raw()).await {
+                Ok(Ok(res)) => res,
+                // An unreachable server is retried like a lost datagram
+                Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Ok(Err(e)) if e.kind() == io::ErrorKind::InvalidData => {
+                    eprintln!("Skipping message: {}", e);
+                    continue;
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) if Instant::now() >= deadline => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "Handshake step \"{}\" timed out after {} attempts",
+                            req.step, attempts
+                        ),
+                    ));
+                }
+                Err(_) => {
+                    attempts += 1;
+                    eprintln!("Retransmitting \"{}\" (attempt {})", req.step, attempts);
+                    match self.send_to(&req.data, destination(self, req.to)).await {
+                        Err(e) if e.kind() != io::ErrorKind::ConnectionRefused => return Err(e),
+                        _ => {}
+                    }
+
+                    interval *= 2;
+                    retransmit = Instant::now() + interval;
+                    continue;
+                }
+            };
+
+            if match_cseq && res.cseq().is_some_and(|cseq| cseq != req.cseq) {
+                eprintln!("Skipping answer to another request");
+                continue;
+            }
+
+            if res.code < 200 {
+                acknowledged = true;
+                continue;
+            }
+
+            if res.code >= 300 {
+                let kind = match res.code {
+                    403 => io::ErrorKind::PermissionDenied,
+                    _ => io::ErrorKind::Other,
+                };
+                return Err(io::Error::new(
+                    kind,
+                    format!(
+                        "Handshake step \"{}\" failed: {} {}",
+                        req.step, res.code, res.status
+                    ),
+                ));
+            }
+
+            return Ok(res);
+        }
+    }
+}
+
+/**
+ * A request whose answer is awaited later, see [`CloudClient::p2p_channel`]
+ */
+pub struct Pending<T> {
+    req: DHRequest,
+    timeout: Duration,
+    match_cseq: bool,
+    parse: fn(&DHResponse, &str) -> io::Result<T>,
+}
+
+impl<T> Pending<T> {
+    /**
+     * Wait for the answer on `socket`, retransmitting the request to its server
+     */
+    pub async fn wait(self, socket: &UdpSocket) -> io::Result<T> {
+        let res = socket
+            .dh_wait(&self.req, self.timeout, self.match_cseq)
+            .await?;
+        (self.parse)(&res, self.req.step)
+    }
+}
+
+/**
+ * Percent-encode a path segment
+ */
+fn escape_path(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn to_xml<T: Serialize>(body: &T) -> io::Result<String> {
+    serde_xml_rs::SerdeXml::new()
+        .emitter(EmitterConfig::new().write_document_declaration(false))
+        .to_string(body)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+}
+
+/**
+ * Resolve a server address given by the cloud, e.g. `1.2.3.4:8800`
+ */
+pub async fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No address for {}", addr)))
+}
+
+/**
+ * Client of the Easy4IPCloud servers (p2psrv, relay and agent), sending each request to
+ * the given server over the given socket
+ */
+#[derive(Default)]
+pub struct CloudClient {
+    /// Shared by requests running concurrently on different sockets
+    cseq: AtomicU32,
+}
+
+impl CloudClient {
+    pub fn new() -> CloudClient {
+        CloudClient::default()
+    }
+
+    async fn send(
+        &self,
+        socket: &UdpSocket,
+        server: SocketAddr,
+        step: &'static str,
+        path: &str,
+        body: Option<String>,
+    ) -> io::Result<DHRequest> {
+        socket
+            .dh_request(
+                server,
+                step,
+                path,
+                body.as_deref(),
+                self.cseq.fetch_add(1, Ordering::Relaxed) + 1,
+            )
+            .await
+    }
+
+    async fn call(
+        &self,
+        socket: &UdpSocket,
+        server: SocketAddr,
+        step: &'static str,
+        path: &str,
+        body: Option<String>,
+    ) -> io::Result<DHResponse> {
+        let req = self.send(socket, server, step, path, body).await?;
+        socket.dh_wait(&req, STEP_TIMEOUT, true).await
+    }
+
+    pub async fn probe_p2psrv(&self, socket: &UdpSocket, server: SocketAddr) -> io::Result<()> {
+        self.call(socket, server, "probe p2psrv", "/probe/p2psrv", None)
+            .await?;
+        Ok(())
+    }
+
+    /**
+     * Address of the p2psrv server the device is registered on
+     */
+    pub async fn online_p2psrv(
+        &self,
+        socket: &UdpSocket,
+        server: SocketAddr,
+        serial: &str,
+    ) -> io::Result<P2PServer> {
+        let path = format!("/online/p2psrv/{}", escape_path(serial));
+        self.call(socket, server, "online p2psrv", &path, None)
+            .await?
+            .body("online p2psrv")
+    }
+
+    pub async fn online_relay(
+        &self,
+        socket: &UdpSocket,
+        server: SocketAddr,
+    ) -> io::Result<RelayServer> {
+        self.call(socket, server, "online relay", "/online/relay", None)
+            .await?
+            .body("online relay")
+    }
+
+    /**
+     * Sent to the p2psrv server of the device
+     */
+    pub async fn probe_device(
+        &self,
+        socket: &UdpSocket,
+        server: SocketAddr,
+        serial: &str,
+    ) -> io::Result<()> {
+        let path = format!("/probe/device/{}", escape_path(serial));
+        self.call(socket, server, "probe device", &path, None)
+            .await?;
+        Ok(())
+    }
+
+    /**
+     * Ask the device for a P2P channel. It answers through the server once it has
+     * been reached, so the answer is awaited separately
+     */
+    pub async fn p2p_channel(
+        &self,
+        socket: &UdpSocket,
+        server: SocketAddr,
+        serial: &str,
+        body: &P2PChannelRequest,
+    ) -> io::Result<Pending<P2PChannel>> {
+        let path = format!("/device/{}/p2p-channel", escape_path(serial));
+        let req = self
+            .send(socket, server, "p2p channel", &path, Some(to_xml(body)?))
+            .await?;
+
+        Ok(Pending {
+            req,
+            timeout: CHANNEL_TIMEOUT,
+            match_cseq: true,
+            parse: |res, step| res.body(step),
+        })
+    }
+
+    /**
+     * Sent to the relay server, returns the agent to use and its token
+     */
+    pub async fn relay_agent(
+        &self,
+        socket: &UdpSocket,
+        server: SocketAddr,
+    ) -> io::Result<RelayAgent> {
+        self.call(socket, server, "relay agent", "/relay/agent", None)
+            .await?
+            .body("relay agent")
+    }
+
+    /**
+     * Sent to the agent
+     */
+    pub async fn relay_start(
+        &self,
+        socket: &UdpSocket,
+        server: SocketAddr,
+        token: &str,
+        body: &RelayStartRequest,
+    ) -> io::Result<()> {
+        let path = format!("/relay/start/{}", escape_path(token));
+        self.call(socket, server, "relay start", &path, Some(to_xml(body)?))
+            .await?;
+        Ok(())
+    }
+
+    /**
+     * Ask the device to join the agent. The answer comes from the agent rather than the
+     * server, so the socket is expected to be connected to the agent when waiting
+     */
+    pub async fn relay_channel(
+        &self,
+        socket: &UdpSocket,
+        server: SocketAddr,
+        serial: &str,
+        body: &RelayChannelRequest,
+    ) -> io::Result<Pending<()>> {
+        let path = format!("/device/{}/relay-channel", escape_path(serial));
+        let req = self
+            .send(socket, server, "relay channel", &path, Some(to_xml(body)?))
+            .await?;
+
+        Ok(Pending {
+            req,
+            timeout: STEP_TIMEOUT,
+            match_cseq: false,
+            parse: |_, _| Ok(()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &[u8]) -> Option<DHResponse> {
+        DHResponse::parse_response(data).unwrap()
+    }
+
+    #[test]
+    fn reason_phrase() {
+        let res = parse(b"HTTP/1.1 404 Device Not Found\r\n\r\n").unwrap();
+        assert_eq!(res.version, "HTTP/1.1");
+        assert_eq!(res.code, 404);
+        assert_eq!(res.status, "Device Not Found");
+        assert!(res.body.is_none());
+
+        // The reason is optional
+        let res = parse(b"HTTP/1.1 200\r\nCSeq: 1\r\n\r\n").unwrap();
+        assert_eq!(res.code, 200);
+        assert_eq!(res.status, "");
+    }
+
+    #[test]
+    fn headers_ignore_case() {
+        let res = parse(b"HTTP/1.1 200 OK\r\ncseq: 7\r\nCONTENT-length: 2\r\n\r\nok").unwrap();
+
+        assert_eq!(res.cseq(), Some(7));
+        assert_eq!(res.header("Content-Length"), Some("2"));
+        assert_eq!(res.body.as_deref(), Some("ok"));
+    }
+
+    #[test]
+    fn body_across_datagrams() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 26\r\n\r\n".to_vec();
+        let body = b"<body><US>a:1</US></body>";
+
+        // The head alone, then part of the body, wait for the rest
+        assert!(parse(&head).is_none());
+        assert!(parse(&[head.as_slice(), &body[..10]].concat()).is_none());
+
+        // Anything past the Content-Length is not part of the body
+        let res = parse(&[head.as_slice(), body, b"\nnext"].concat()).unwrap();
+        assert_eq!(res.body.as_deref(), Some("<body><US>a:1</US></body>\n"));
+
+        let server: P2PServer = res.body("online p2psrv").unwrap();
+        assert_eq!(server.us, "a:1");
+    }
+
+    #[test]
+    fn incomplete_head() {
+        assert!(parse(b"HTT").is_none());
+        assert!(parse(b"HTTP/1.1 200 OK\r\nCSeq: 1\r\n").is_none());
+    }
+
+    #[test]
+    fn invalid_responses() {
+        let error = |data: &[u8]| DHResponse::parse_response(data).unwrap_err().kind();
+
+        assert_eq!(
+            error(b"DHGET /probe HTTP/1.1\r\n\r\n"),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(error(b"HTTP/1.1 OK\r\n\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error(b"HTTP/1.1 200 OK\r\nno colon\r\n\r\n"),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            error(b"HTTP/1.1 200 OK\r\nContent-Length: x\r\n\r\n"),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn body_missing() {
+        let res = parse(b"HTTP/1.1 200 OK\r\n\r\n  \r\n").unwrap();
+
+        assert!(res.body.is_none());
+        assert!(res.body::<P2PServer>("online p2psrv").is_err());
+    }
+
+    /**
+     * A client socket with a request to a local server, which answers with `datagrams`
+     */
+    async fn exchange(datagrams: &[&[u8]]) -> io::Result<DHResponse> {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let req = DHRequest {
+            step: "test",
+            cseq: 3,
+            data: b"DHGET /probe/p2psrv HTTP/1.1\r\nCSeq: 3\r\n\r\n".to_vec(),
+            to: server.local_addr().unwrap(),
+        };
+
+        for datagram in datagrams {
+            server
+                .send_to(datagram, client.local_addr().unwrap())
+                .await
+                .unwrap();
+        }
+
+        client.dh_wait(&req, Duration::from_secs(2), true).await
+    }
+
+    #[tokio::test]
+    async fn wait_skips_provisional_answers() {
+        let res = exchange(&[
+            b"HTTP/1.1 100 Trying\r\nCSeq: 3\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nCSeq: 3\r\n\r\n",
+        ])
+        .await
+        .unwrap();
+
+        assert_eq!(res.code, 200);
+    }
+
+    #[tokio::test]
+    async fn wait_skips_other_cseq() {
+        let res = exchange(&[
+            b"HTTP/1.1 200 OK\r\nCSeq: 2\r\nContent-Length: 5\r\n\r\nstale",
+            b"HTTP/1.1 200 OK\r\nCSeq: 3\r\nContent-Length: 5\r\n\r\nfresh",
+        ])
+        .await
+        .unwrap();
+
+        assert_eq!(res.cseq(), Some(3));
+        assert_eq!(res.body.as_deref(), Some("fresh"));
+    }
+
+    #[tokio::test]
+    async fn wait_reassembles_the_body() {
+        let res = exchange(&[
+            b"HTTP/1.1 200 OK\r\nCSeq: 3\r\nContent-Length: 10\r\n\r\nhello",
+            b" body",
+        ])
+        .await
+        .unwrap();
+
+        assert_eq!(res.body.as_deref(), Some("hello body"));
+    }
+
+    #[tokio::test]
+    async fn wait_skips_garbage() {
+        let res = exchange(&[b"\x00\x01garbage", b"HTTP/1.1 200 OK\r\nCSeq: 3\r\n\r\n"])
+            .await
+            .unwrap();
+
+        assert_eq!(res.code, 200);
+    }
+
+    #[tokio::test]
+    async fn wait_fails_on_error_codes() {
+        let e = exchange(&[b"HTTP/1.1 403 Forbidden\r\nCSeq: 3\r\n\r\n"])
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            e.to_string(),
+            "Handshake step \"test\" failed: 403 Forbidden"
+        );
+
+        let e = exchange(&[b"HTTP/1.1 500 Internal Error\r\nCSeq: 3\r\n\r\n"])
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_retransmits_until_the_deadline() {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let req = DHRequest {
+            step: "test",
+            cseq: 1,
+            data: b"DHGET /probe/p2psrv HTTP/1.1\r\nCSeq: 1\r\n\r\n".to_vec(),
+            to: server.local_addr().unwrap(),
+        };
+
+        // Sent again after 0.5, 1.5, 3.5 and 7.5 seconds
+        let e = client.dh_wait(&req, STEP_TIMEOUT, true).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            e.to_string(),
+            "Handshake step \"test\" timed out after 5 attempts"
+        );
+
+        let mut buf = [0u8; 128];
+        let mut retransmitted = 0;
+        while server.try_recv_from(&mut buf).is_ok() {
+            retransmitted += 1;
+        }
+        assert_eq!(retransmitted, 4);
+    }
+}
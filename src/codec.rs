@@ -0,0 +1,462 @@
+use base64::Engine;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoCodec {
+    H264,
+    H265,
+}
+
+impl VideoCodec {
+    /**
+     * Match the encoding name of an SDP `rtpmap` attribute
+     */
+    pub fn from_encoding(name: &str) -> Option<VideoCodec> {
+        match name.to_uppercase().as_str() {
+            "H264" => Some(VideoCodec::H264),
+            "H265" | "HEVC" => Some(VideoCodec::H265),
+            _ => None,
+        }
+    }
+
+    pub fn nal_type(&self, nal: &[u8]) -> u8 {
+        match self {
+            VideoCodec::H264 => nal[0] & 0x1F,
+            VideoCodec::H265 => (nal[0] >> 1) & 0x3F,
+        }
+    }
+
+    /**
+     * IDR for H.264, IRAP for H.265
+     */
+    pub fn is_keyframe(&self, nal: &[u8]) -> bool {
+        match self {
+            VideoCodec::H264 => self.nal_type(nal) == 5,
+            VideoCodec::H265 => (16..=21).contains(&self.nal_type(nal)),
+        }
+    }
+
+    /**
+     * VPS, SPS or PPS
+     */
+    pub fn is_parameter_set(&self, nal: &[u8]) -> bool {
+        match self {
+            VideoCodec::H264 => matches!(self.nal_type(nal), 7 | 8),
+            VideoCodec::H265 => matches!(self.nal_type(nal), 32..=34),
+        }
+    }
+
+    /**
+     * Access unit delimiter, required by some players in MPEG-TS
+     */
+    pub fn access_unit_delimiter(&self) -> &'static [u8] {
+        match self {
+            VideoCodec::H264 => b"\x09\xf0",
+            VideoCodec::H265 => b"\x46\x01\x50",
+        }
+    }
+}
+
+/**
+ * Parameter sets of a video stream, from the SDP or in-band
+ */
+#[derive(Clone, Debug, Default)]
+pub struct ParameterSets {
+    pub vps: Option<Vec<u8>>,
+    pub sps: Option<Vec<u8>>,
+    pub pps: Option<Vec<u8>>,
+}
+
+impl ParameterSets {
+    /**
+     * Read `sprop-parameter-sets` (H.264) or `sprop-vps/sps/pps` (H.265) from fmtp
+     */
+    pub fn from_fmtp(codec: VideoCodec, fmtp: &[(String, String)]) -> ParameterSets {
+        let decode = |s: &str| {
+            base64::engine::general_purpose::STANDARD
+                .decode(s.trim())
+                .ok()
+        };
+        let get = |k: &str| {
+            fmtp.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(k))
+                .map(|(_, v)| v.as_str())
+        };
+
+        let mut sets = ParameterSets::default();
+
+        match codec {
+            VideoCodec::H264 => {
+                for nal in get("sprop-parameter-sets")
+                    .unwrap_or_default()
+                    .split(',')
+                    .filter_map(decode)
+                {
+                    sets.update(codec, &nal);
+                }
+            }
+            VideoCodec::H265 => {
+                sets.vps = get("sprop-vps").and_then(decode);
+                sets.sps = get("sprop-sps").and_then(decode);
+                sets.pps = get("sprop-pps").and_then(decode);
+            }
+        }
+
+        sets
+    }
+
+    /**
+     * Remember an in-band parameter set, return true if it is one
+     */
+    pub fn update(&mut self, codec: VideoCodec, nal: &[u8]) -> bool {
+        if nal.is_empty() {
+            return false;
+        }
+
+        let slot = match (codec, codec.nal_type(nal)) {
+            (VideoCodec::H264, 7) | (VideoCodec::H265, 33) => &mut self.sps,
+            (VideoCodec::H264, 8) | (VideoCodec::H265, 34) => &mut self.pps,
+            (VideoCodec::H265, 32) => &mut self.vps,
+            _ => return false,
+        };
+
+        *slot = Some(nal.to_vec());
+        true
+    }
+
+    pub fn is_complete(&self, codec: VideoCodec) -> bool {
+        self.sps.is_some()
+            && self.pps.is_some()
+            && (codec == VideoCodec::H264 || self.vps.is_some())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vec<u8>> {
+        [&self.vps, &self.sps, &self.pps].into_iter().flatten()
+    }
+}
+
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/**
+ * AudioSpecificConfig of an AAC stream, from the SDP `config` parameter
+ */
+#[derive(Clone, Debug)]
+pub struct AacConfig {
+    pub object_type: u8,
+    pub frequency_index: u8,
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub raw: Vec<u8>,
+}
+
+impl AacConfig {
+    pub fn from_hex(hex: &str) -> Option<AacConfig> {
+        let raw = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+
+        let mut r = BitReader::new(&raw);
+        let object_type = r.bits(5)? as u8;
+        let frequency_index = r.bits(4)? as u8;
+        let sample_rate = match frequency_index {
+            15 => r.bits(24)?,
+            i => *AAC_SAMPLE_RATES.get(i as usize)?,
+        };
+        let channels = r.bits(4)? as u8;
+
+        // Object type 0 is not AAC and a null rate can't time the frames
+        if object_type == 0 || sample_rate == 0 {
+            return None;
+        }
+
+        Some(AacConfig {
+            object_type,
+            frequency_index,
+            sample_rate,
+            channels,
+            raw,
+        })
+    }
+
+    /**
+     * ADTS header for a raw AAC frame, as used by MPEG-TS
+     */
+    pub fn adts_header(&self, len: usize) -> [u8; 7] {
+        let frame_len = len + 7;
+
+        [
+            0xFF,
+            0xF1,
+            ((self.object_type - 1) << 6) | (self.frequency_index << 2) | (self.channels >> 2),
+            ((self.channels & 3) << 6) | (frame_len >> 11) as u8,
+            (frame_len >> 3) as u8,
+            ((frame_len as u8 & 7) << 5) | 0x1F,
+            0xFC,
+        ]
+    }
+}
+
+/**
+ * Remove emulation prevention bytes (00 00 03) from a NAL unit
+ */
+pub fn unescape_rbsp(nal: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+
+    for &b in nal {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        out.push(b);
+    }
+
+    out
+}
+
+/**
+ * Exp-Golomb capable bit reader over an RBSP
+ */
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, pos: 0 }
+    }
+
+    pub fn bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+
+        Some(bit as u32)
+    }
+
+    pub fn bits(&mut self, n: usize) -> Option<u32> {
+        (0..n).try_fold(0, |acc, _| Some((acc << 1) | self.bit()?))
+    }
+
+    pub fn skip(&mut self, n: usize) -> Option<()> {
+        if self.pos + n > self.data.len() * 8 {
+            return None;
+        }
+
+        self.pos += n;
+        Some(())
+    }
+
+    pub fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+
+        Some((1u32 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    pub fn se(&mut self) -> Option<i32> {
+        let v = self.ue()?;
+
+        Some(if v & 1 == 1 {
+            v.div_ceil(2) as i32
+        } else {
+            -((v / 2) as i32)
+        })
+    }
+}
+
+/**
+ * Fields of a sequence parameter set needed by the muxers
+ */
+#[derive(Clone, Debug, Default)]
+pub struct SpsInfo {
+    pub width: u32,
+    pub height: u32,
+    /// H.265 only: general profile_tier_level, 12 bytes
+    pub profile_tier_level: Vec<u8>,
+    pub chroma_format_idc: u32,
+    pub bit_depth_luma: u32,
+    pub bit_depth_chroma: u32,
+}
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Option<()> {
+    let mut last = 8;
+    let mut next = 8;
+
+    for _ in 0..size {
+        if next != 0 {
+            next = (last + r.se()? + 256) % 256;
+        }
+        if next != 0 {
+            last = next;
+        }
+    }
+
+    Some(())
+}
+
+impl SpsInfo {
+    pub fn parse(codec: VideoCodec, nal: &[u8]) -> Option<SpsInfo> {
+        match codec {
+            VideoCodec::H264 => SpsInfo::parse_h264(nal),
+            VideoCodec::H265 => SpsInfo::parse_h265(nal),
+        }
+    }
+
+    fn parse_h264(nal: &[u8]) -> Option<SpsInfo> {
+        let rbsp = unescape_rbsp(nal.get(1..)?);
+        let mut r = BitReader::new(&rbsp);
+
+        let profile_idc = r.bits(8)?;
+        r.skip(16)?; // constraint flags, level_idc
+        r.ue()?; // seq_parameter_set_id
+
+        let mut chroma_format_idc = 1;
+        let mut bit_depth_luma = 8;
+        let mut bit_depth_chroma = 8;
+
+        if matches!(
+            profile_idc,
+            100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+        ) {
+            chroma_format_idc = r.ue()?;
+            if chroma_format_idc == 3 {
+                r.skip(1)?; // separate_colour_plane_flag
+            }
+            bit_depth_luma = r.ue()? + 8;
+            bit_depth_chroma = r.ue()? + 8;
+            r.skip(1)?; // qpprime_y_zero_transform_bypass_flag
+
+            if r.bit()? == 1 {
+                let count = if chroma_format_idc == 3 { 12 } else { 8 };
+                for i in 0..count {
+                    if r.bit()? == 1 {
+                        skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        r.ue()?; // log2_max_frame_num_minus4
+        match r.ue()? {
+            0 => {
+                r.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+            }
+            1 => {
+                r.skip(1)?;
+                r.se()?;
+                r.se()?;
+                for _ in 0..r.ue()? {
+                    r.se()?;
+                }
+            }
+            _ => {}
+        }
+        r.ue()?; // max_num_ref_frames
+        r.skip(1)?; // gaps_in_frame_num_value_allowed_flag
+
+        let width_mbs = r.ue()? + 1;
+        let height_map_units = r.ue()? + 1;
+        let frame_mbs_only = r.bit()?;
+        if frame_mbs_only == 0 {
+            r.skip(1)?; // mb_adaptive_frame_field_flag
+        }
+        r.skip(1)?; // direct_8x8_inference_flag
+
+        let mut width = width_mbs * 16;
+        let mut height = height_map_units * 16 * (2 - frame_mbs_only);
+
+        if r.bit()? == 1 {
+            let (left, right, top, bottom) = (r.ue()?, r.ue()?, r.ue()?, r.ue()?);
+            let (crop_x, crop_y) = match chroma_format_idc {
+                0 => (1, 2 - frame_mbs_only),
+                1 => (2, 2 * (2 - frame_mbs_only)),
+                2 => (2, 2 - frame_mbs_only),
+                _ => (1, 2 - frame_mbs_only),
+            };
+            width = width.saturating_sub((left + right) * crop_x);
+            height = height.saturating_sub((top + bottom) * crop_y);
+        }
+
+        Some(SpsInfo {
+            width,
+            height,
+            profile_tier_level: Vec::new(),
+            chroma_format_idc,
+            bit_depth_luma,
+            bit_depth_chroma,
+        })
+    }
+
+    fn parse_h265(nal: &[u8]) -> Option<SpsInfo> {
+        let rbsp = unescape_rbsp(nal.get(2..)?);
+        let mut r = BitReader::new(&rbsp);
+
+        r.skip(4)?; // sps_video_parameter_set_id
+        let max_sub_layers = r.bits(3)? as usize;
+        r.skip(1)?; // sps_temporal_id_nesting_flag
+
+        // general profile, tier and level are byte aligned at this point
+        let profile_tier_level = rbsp.get(1..13)?.to_vec();
+        r.skip(96)?;
+
+        let mut sub_layer_flags = Vec::new();
+        for _ in 0..max_sub_layers {
+            sub_layer_flags.push((r.bit()?, r.bit()?));
+        }
+        if max_sub_layers > 0 {
+            r.skip(2 * (8 - max_sub_layers))?;
+        }
+        for (profile_present, level_present) in sub_layer_flags {
+            if profile_present == 1 {
+                r.skip(88)?;
+            }
+            if level_present == 1 {
+                r.skip(8)?;
+            }
+        }
+
+        r.ue()?; // sps_seq_parameter_set_id
+        let chroma_format_idc = r.ue()?;
+        let mut separate_colour_plane = 0;
+        if chroma_format_idc == 3 {
+            separate_colour_plane = r.bit()?;
+        }
+
+        let mut width = r.ue()?;
+        let mut height = r.ue()?;
+
+        if r.bit()? == 1 {
+            let (left, right, top, bottom) = (r.ue()?, r.ue()?, r.ue()?, r.ue()?);
+            let (sub_width, sub_height) = match (chroma_format_idc, separate_colour_plane) {
+                (1, 0) => (2, 2),
+                (2, 0) => (2, 1),
+                _ => (1, 1),
+            };
+            width = width.saturating_sub((left + right) * sub_width);
+            height = height.saturating_sub((top + bottom) * sub_height);
+        }
+
+        let bit_depth_luma = r.ue()? + 8;
+        let bit_depth_chroma = r.ue()? + 8;
+
+        Some(SpsInfo {
+            width,
+            height,
+            profile_tier_level,
+            chroma_format_idc,
+            bit_depth_luma,
+            bit_depth_chroma,
+        })
+    }
+}
@@ -1,439 +1,577 @@
-use async_trait::async_trait;
-use base64::Engine;
-use sha1::Digest;
-use std::{collections::HashMap, net::SocketAddrV4};
-use tokio::{net::UdpSocket, time};
-use xml::reader::{EventReader, XmlEvent};
-
-use crate::ptcp::{PTCPBody, PTCPSession, PTCP};
-
-static MAIN_SERVER: &str = "www.easy4ipcloud.com:8800";
-
-static USERNAME: &str = "cba1b29e32cb17aa46b8ff9e73c7f40b";
-static USERKEY: &str = "996103384cdf19179e19243e959bbf8b";
-
-fn ip_to_bytes(ip: &str) -> Vec<u8> {
-    let addr: SocketAddrV4 = ip.parse().unwrap();
-    let ip = addr.ip().octets();
-    let port = addr.port();
-
-    let mut bytes = Vec::new();
-    bytes.extend_from_slice(&port.to_be_bytes());
-    bytes.extend_from_slice(&ip);
-
-    bytes.iter().map(|b| !b).collect()
+use serde::Serialize;
+use std::{future::Future, io, net::SocketAddr, sync::Mutex};
+use tokio::{
+    net::UdpSocket,
+    time::{self, Duration, Instant},
+};
+
+use crate::{
+    cloud::{
+        resolve, CloudClient, P2PChannel, P2PChannelRequest, RelayAgent, RelayChannelRequest,
+        RelayStartRequest, MAIN_SERVER,
+    },
+    net::{bind_udp, canonical, destination, BindArgs},
+    ptcp::{PTCPBody, PTCPPacket, PTCPSession, PTCP},
+    stun::{Attribute, Message, MessageType},
+};
+
+/// Time given to the device to answer the hole punching
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay between Binding requests until the device answers
+const PUNCH_INTERVAL: Duration = Duration::from_millis(500);
+/// Silence ending the exchange once both sides have been answered
+const PUNCH_LINGER: Duration = Duration::from_millis(300);
+/// Longest wait for that silence
+const PUNCH_LINGER_MAX: Duration = Duration::from_secs(1);
+/// Public ports after the device's one tried as candidates
+const PREDICTED_PORTS: u16 = 4;
+/// Longest wait for each PTCP answer of the relay or the device
+const PTCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+ * Timing and outcome of a handshake step
+ */
+#[derive(Debug, Serialize)]
+pub struct Step {
+    pub name: &'static str,
+    /// Start of the step, since the start of the handshake
+    pub start_ms: u128,
+    pub millis: u128,
+    pub error: Option<String>,
 }
 
-pub async fn p2p_handshake(
-    socket: UdpSocket,
-    serial: String,
-    relay_mode: bool,
-) -> (UdpSocket, PTCPSession) {
-    let mut cseq = 0;
-
-    socket.connect(MAIN_SERVER).await.unwrap();
-
-    socket.dh_request("/probe/p2psrv", None, &mut cseq).await;
-    socket.dh_read().await;
-
-    socket
-        .dh_request(
-            format!("/online/p2psrv/{}", serial).as_ref(),
-            None,
-            &mut cseq,
-        )
-        .await;
-    let p2psrv = &socket.dh_read().await.body.unwrap()["body/US"];
-
-    socket.dh_request("/online/relay", None, &mut cseq).await;
-    let relay = &socket.dh_read().await.body.unwrap()["body/Address"];
-
-    let socket2 = UdpSocket::bind("0.0.0.0:0").await.unwrap();
-    socket2.connect(p2psrv).await.unwrap();
-
-    socket2
-        .dh_request(
-            format!("/probe/device/{}", serial).as_ref(),
-            None,
-            &mut cseq,
-        )
-        .await;
-    socket2.dh_read().await;
-
-    let cid: [u8; 8] = rand::random();
-
-    socket
-        .dh_request(
-            format!("/device/{}/p2p-channel", serial).as_ref(),
-            Some(format!(
-                "<body><Identify>{}</Identify><IpEncrpt>true</IpEncrpt><LocalAddr>127.0.0.1:{}</LocalAddr><version>5.0.0</version></body>",
-                cid.iter().map(|b| format!("{:x}", b)).collect::<Vec<_>>().join(" "),
-                socket.local_addr().unwrap().port(),
-            ).as_ref()),
-            &mut cseq,
-        )
-        .await;
-
-    socket2.connect(relay).await.unwrap();
-
-    socket2.dh_request("/relay/agent", None, &mut cseq).await;
-    let data = socket2.dh_read().await.body.unwrap();
-    let token = &data["body/Token"];
-    let agent = &data["body/Agent"];
-
-    socket2.connect(agent).await.unwrap();
-
-    socket2
-        .dh_request(
-            format!("/relay/start/{}", token).as_ref(),
-            Some("<body><Client>:0</Client></body>"),
-            &mut cseq,
-        )
-        .await;
-    socket2.dh_read().await;
+/**
+ * What a handshake went through, reported by the `probe` command
+ */
+#[derive(Debug, Default, Serialize)]
+pub struct Diagnostics {
+    #[serde(skip)]
+    started: Option<Instant>,
+    /// Steps in completion order, some of them run concurrently
+    pub steps: Mutex<Vec<Step>>,
+    /// Steps in progress when the handshake stopped
+    pub pending: Mutex<Vec<&'static str>>,
+    pub local_port: Option<u16>,
+    pub device_public: Option<String>,
+    pub device_local: Option<String>,
+    pub punch: Punch,
+}
 
-    let mut res = socket.dh_read_raw().await;
+/**
+ * Outcome of the hole punching
+ */
+#[derive(Debug, Default, Serialize)]
+pub struct Punch {
+    pub candidates: Vec<SocketAddr>,
+    /// Whether the device sent its own Binding request
+    pub device_request: bool,
+    /// Candidate answering the Binding requests first
+    pub answered_candidate: Option<SocketAddr>,
+    /// Address that answer came from
+    pub answered_from: Option<SocketAddr>,
+    /// Our address as seen by the device, per answer
+    pub mapped_addresses: Vec<SocketAddr>,
+}
 
-    if res.code == 100 {
-        res = socket.dh_read_raw().await;
+impl Diagnostics {
+    async fn timed<T>(
+        &self,
+        name: &'static str,
+        future: impl Future<Output = io::Result<T>>,
+    ) -> io::Result<T> {
+        let started = Instant::now();
+        self.pending.lock().unwrap().push(name);
+
+        let result = future.await;
+
+        self.pending.lock().unwrap().retain(|p| *p != name);
+        self.steps.lock().unwrap().push(Step {
+            name,
+            start_ms: self
+                .started
+                .map_or(0, |origin| (started - origin).as_millis()),
+            millis: started.elapsed().as_millis(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+
+        result
     }
 
-    if res.code >= 400 {
-        if res.code == 403 {
-            println!("Device requires authentication when creating P2P channel.");
-            println!("Authentication is not supported at this time.");
+    /**
+     * Log the timing of each step
+     */
+    pub fn print_steps(&self) {
+        for step in self.steps.lock().unwrap().iter() {
+            eprintln!(
+                "Handshake: {:<15} +{:>5} ms {:>6} ms{}",
+                step.name,
+                step.start_ms,
+                step.millis,
+                step.error
+                    .as_ref()
+                    .map(|e| format!("  {}", e))
+                    .unwrap_or_default()
+            );
         }
-
-        panic!("Error response: {}", res.status);
     }
+}
 
-    let data = res.body.unwrap();
-    let device_laddr = &data["body/LocalAddr"];
-    let device = &data["body/PubAddr"];
-
-    // not necessary when relay_mode is true, but UDP is connectionless
-    socket.connect(device).await.unwrap();
-
-    socket2.connect(MAIN_SERVER).await.unwrap();
-
-    socket2
-        .dh_request(
-            format!("/device/{}/relay-channel", serial).as_ref(),
-            Some(format!("<body><agentAddr>{}</agentAddr></body>", agent).as_ref()),
-            &mut cseq,
+fn parse_addr(addr: &str) -> io::Result<SocketAddr> {
+    addr.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid device address: {}", addr),
         )
-        .await;
-
-    socket2.connect(agent).await.unwrap();
-    // TODO check timeout
-    socket2.dh_read().await;
-
-    let mut session = PTCPSession::new();
-
-    socket2.ptcp_request(session.send(PTCPBody::Sync)).await;
-    session.recv(socket2.ptcp_read().await);
-
-    if relay_mode {
-        return (socket2, session);
-    }
-
-    socket2
-        .ptcp_request(session.send(PTCPBody::Command(
-            b"\x17\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec(),
-        )))
-        .await;
-    let mut res = session.recv(socket2.ptcp_read().await);
-
-    while let PTCPBody::Empty = res.body {
-        res = session.recv(socket2.ptcp_read().await);
-    }
+    })
+}
 
-    let sign = match res.body {
-        PTCPBody::Command(ref c) => &c[12..],
-        _ => panic!("Invalid response"),
-    };
+/**
+ * Read the next PTCP packet of a handshake step, failing the step when nothing comes
+ */
+async fn ptcp_read(
+    socket: &impl PTCP,
+    session: &mut PTCPSession,
+    step: &str,
+) -> io::Result<PTCPPacket> {
+    let packet = time::timeout(PTCP_TIMEOUT, socket.ptcp_read())
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Handshake step \"{}\" timed out", step),
+            )
+        })?;
+
+    Ok(session.recv(packet))
+}
 
-    println!(
-        "Sign: {}",
-        sign.iter()
-            .map(|b| format!("{:02x}", b))
-            .collect::<Vec<_>>()
-            .join("")
-    );
+async fn stun_send(socket: &UdpSocket, message: &Message, to: SocketAddr) -> io::Result<()> {
+    eprintln!(">>> {}", to);
+    eprintln!("{:?}", message);
+    eprintln!("---");
 
-    let cookie: [u8; 4] = rand::random();
-    let trans_id: [u8; 12] = rand::random();
-    let cid: Vec<u8> = cid.iter().map(|b| !b).collect();
-
-    println!(">>> {}", socket.peer_addr().unwrap());
-    let data = [
-        b"\xff\xfe\xff\xe7".to_vec(),
-        cookie.to_vec(),
-        trans_id.to_vec(),
-        b"\x7f\xd5\xff\xf7".to_vec(),
-        cid.clone(),
-        b"\xff\xfb\xff\xf7\xff\xfe".to_vec(),
-        ip_to_bytes(&device),
-    ]
-    .concat();
-    println!(
-        "Raw [{}]",
-        data.iter()
-            .map(|b| format!("{:02x}", b))
-            .collect::<Vec<_>>()
-            .join(" ")
-    );
-    socket.send(&data).await.unwrap();
-    println!("---");
-
-    println!("<<< {}", socket.peer_addr().unwrap());
-    let mut buf = [0u8; 4096];
+    socket
+        .send_to(&message.encode(), destination(socket, to))
+        .await?;
+    Ok(())
+}
 
-    let result = time::timeout(time::Duration::from_secs(5), socket.recv(&mut buf)).await;
+/**
+ * Addresses the device may be reached at: its public address, its LAN address for clients
+ * on the same network, then the next public ports for NATs allocating them in sequence.
+ * IPv6 addresses are not translated, so no port is predicted for them
+ */
+fn candidates(device: SocketAddr, device_laddr: SocketAddr) -> Vec<SocketAddr> {
+    let mut candidates = vec![device];
 
-    if result.is_err() {
-        println!("Timeout occurred while waiting for a response from the device.");
-        println!(
-            "If the issue persists, you may need to use relay mode (--relay) with this device."
-        );
-        panic!("Timeout");
+    if device_laddr != device {
+        candidates.push(device_laddr);
     }
 
-    let n = result.unwrap().unwrap();
-    println!(
-        "Raw [{}]",
-        buf[0..n]
-            .iter()
-            .map(|b| format!("{:02x}", b))
-            .collect::<Vec<_>>()
-            .join(" ")
-    );
-    println!("---");
-
-    let rtrans_id = &buf[8..20];
-
-    println!(">>> {}", socket.peer_addr().unwrap());
-    let data = [
-        b"\xfe\xfe\xff\xe7".to_vec(),
-        cookie.to_vec(),
-        rtrans_id.to_vec(),
-        b"\x7f\xd6\xff\xf7".to_vec(),
-        cid.clone(),
-        b"\xff\xfb\xff\xf7\xff\xfe".to_vec(),
-        ip_to_bytes(&device_laddr),
-    ]
-    .concat();
-    println!(
-        "Raw [{}]",
-        data.iter()
-            .map(|b| format!("{:02x}", b))
-            .collect::<Vec<_>>()
-            .join(" ")
-    );
-    socket.send(&data).await.unwrap();
-    println!("---");
-
-    // read 5 times
-    for _ in 0..5 {
-        println!("<<< {}", socket.peer_addr().unwrap());
-        let n = socket.recv(&mut buf).await.unwrap();
-        println!(
-            "Raw [{}]",
-            buf[0..n]
-                .iter()
-                .map(|b| format!("{:02x}", b))
-                .collect::<Vec<_>>()
-                .join(" ")
+    if device.is_ipv4() {
+        candidates.extend(
+            (1..=PREDICTED_PORTS)
+                .filter_map(|i| device.port().checked_add(i))
+                .map(|port| SocketAddr::new(device.ip(), port)),
         );
-        println!("---");
     }
 
-    let mut session = PTCPSession::new();
+    candidates
+}
 
-    socket.ptcp_request(session.send(PTCPBody::Sync)).await;
-    let mut res = session.recv(socket.ptcp_read().await);
-    assert!(matches!(res.body, PTCPBody::Sync), "Invalid response");
+/**
+ * Exchange Binding requests with the device on every candidate address, until one of them
+ * answered and the device was answered. The socket is then connected to that candidate
+ */
+async fn hole_punch(
+    socket: &UdpSocket,
+    cid: [u8; 8],
+    device: SocketAddr,
+    device_laddr: SocketAddr,
+    punch: &mut Punch,
+) -> io::Result<SocketAddr> {
+    let cookie: [u8; 4] = rand::random();
+    let requests: Vec<(SocketAddr, Message)> = candidates(device, device_laddr)
+        .into_iter()
+        .map(|candidate| {
+            let request = Message {
+                kind: MessageType::Request,
+                cookie,
+                transaction: rand::random(),
+                attributes: vec![
+                    Attribute::ControllingId(cid),
+                    Attribute::SourceAddress(candidate),
+                ],
+            };
+            (candidate, request)
+        })
+        .collect();
+    punch.candidates = requests.iter().map(|(candidate, _)| *candidate).collect();
+
+    let deadline = Instant::now() + PUNCH_TIMEOUT;
+    let mut retransmit = Instant::now();
+    // The first candidate to answer, and whether we answered one of the device's requests
+    let mut selected = None;
+    let mut responded = false;
+    let mut linger_until = None;
+    let mut buf = [0u8; 4096];
 
-    socket
-        .ptcp_request(
-            session.send(PTCPBody::Command(
-                [
-                    b"\x19\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec(),
-                    sign.to_vec(),
-                ]
-                .concat(),
-            )),
-        )
-        .await;
+    loop {
+        if selected.is_none() && Instant::now() >= retransmit {
+            for (candidate, request) in &requests {
+                stun_send(socket, request, *candidate).await?;
+            }
+            retransmit = Instant::now() + PUNCH_INTERVAL;
+        }
 
-    res = session.recv(socket.ptcp_read().await);
-    while let PTCPBody::Empty = res.body {
-        res = session.recv(socket.ptcp_read().await);
-    }
-    match res.body {
-        PTCPBody::Command(ref c) => {
-            assert_eq!(c[0], 0x1A, "Invalid response");
+        if selected.is_some() && responded {
+            linger_until.get_or_insert(Instant::now() + PUNCH_LINGER_MAX);
         }
-        _ => panic!("Invalid response"),
-    }
 
-    socket
-        .ptcp_request(session.send(PTCPBody::Command(
-            b"\x1b\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec(),
-        )))
-        .await;
-    res = session.recv(socket.ptcp_read().await);
+        // Once done, the device's retransmissions are handled until it goes quiet
+        let wake = match linger_until {
+            Some(until) if Instant::now() >= until => break,
+            Some(until) => until.min(Instant::now() + PUNCH_LINGER),
+            None if selected.is_some() => deadline,
+            None => retransmit.min(deadline),
+        };
 
-    assert!(matches!(res.body, PTCPBody::Empty), "Invalid response");
+        let (n, from) = match time::timeout_at(wake, socket.recv_from(&mut buf)).await {
+            Ok(Ok((n, from))) => (n, canonical(from)),
+            // The hole is not open yet
+            Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+            Ok(Err(e)) => return Err(e),
+            Err(_) if linger_until.is_some() => break,
+            Err(_) if Instant::now() >= deadline => {
+                eprintln!("Timeout occurred while waiting for a response from the device.");
+                eprintln!(
+                    "If the issue persists, you may need to use relay mode (--relay) with this device."
+                );
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Hole punching timed out",
+                ));
+            }
+            Err(_) => continue,
+        };
 
-    (socket, session)
-}
+        // Late answers of the cloud servers arrive on the same socket
+        let message = match Message::decode(&buf[0..n]) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Skipping datagram from {}: {}", from, e);
+                continue;
+            }
+        };
 
-#[derive(Debug)]
-#[allow(dead_code)]
-struct DHResponse {
-    version: String,
-    code: u16,
-    status: String,
-    headers: HashMap<String, String>,
-    body: Option<HashMap<String, String>>,
-}
+        eprintln!("<<< {}", from);
+        eprintln!("{:?}", message);
+        eprintln!("---");
 
-impl DHResponse {
-    fn parse_body(body: &str) -> HashMap<String, String> {
-        // XmlBody::Value("")
-        let mut parser = EventReader::from_str(body);
-        let mut stack = Vec::new();
-        let mut tree = HashMap::new();
-
-        loop {
-            match parser.next() {
-                Ok(XmlEvent::StartElement { name, .. }) => {
-                    stack.push(name.local_name);
-                }
-                Ok(XmlEvent::EndElement { .. }) => {
-                    stack.pop().unwrap();
-                }
-                Ok(XmlEvent::Characters(s)) => {
-                    let key = stack.as_slice().join("/");
-                    tree.insert(key, s);
+        let answered = requests
+            .iter()
+            .find(|(_, request)| message.answers(request));
+
+        match (message.kind, answered) {
+            (MessageType::Request, _) => {
+                let response = message.response(
+                    cookie,
+                    vec![
+                        Attribute::ControlledId(cid),
+                        Attribute::SourceAddress(device_laddr),
+                    ],
+                );
+                stun_send(socket, &response, from).await?;
+                responded = true;
+                punch.device_request = true;
+            }
+            (MessageType::ErrorResponse, Some((candidate, _))) => {
+                eprintln!("Candidate {} rejected the Binding request", candidate);
+            }
+            (MessageType::Response, Some((candidate, _))) => {
+                if let Some(addr) = message.mapped_address() {
+                    eprintln!("Mapped address: {}", addr);
+                    if !punch.mapped_addresses.contains(&addr) {
+                        punch.mapped_addresses.push(addr);
+                    }
                 }
-                Ok(XmlEvent::EndDocument) => {
-                    break;
+                if selected.is_none() {
+                    eprintln!("Candidate {} answered from {}", candidate, from);
+                    selected = Some(from);
+                    punch.answered_candidate = Some(*candidate);
+                    punch.answered_from = Some(from);
                 }
-                Err(e) => panic!("Error: {}", e),
-                _ => {}
             }
+            _ => eprintln!("Skipping unrelated STUN message"),
         }
-
-        tree
     }
 
-    fn parse_response(res: &str) -> DHResponse {
-        // split head and body by "\r\n\r\n"
-        let mut parts = res.split("\r\n\r\n");
-        let head = parts.next().unwrap();
-        let body = parts.next().unwrap();
-
-        let mut head_parts = head.split("\r\n");
-        let mut status_line = head_parts.next().unwrap().split(" ");
-        let version = status_line.next().unwrap().to_string();
-        let code = status_line.next().unwrap().parse::<u16>().unwrap();
-        let status = status_line.next().unwrap().to_string();
-
-        let mut headers = HashMap::new();
-        for line in head_parts {
-            let mut parts = line.split(": ");
-            let key = parts.next().unwrap().to_string();
-            let value = parts.next().unwrap().to_string();
-            headers.insert(key, value);
-        }
-
-        let body = match body.trim().len() {
-            0 => None,
-            _ => Some(DHResponse::parse_body(body)),
-        };
+    let selected = selected.unwrap();
+    socket.connect(destination(socket, selected)).await?;
 
-        DHResponse {
-            version,
-            code,
-            status,
-            headers,
-            body,
-        }
-    }
+    Ok(selected)
 }
 
-#[async_trait]
-trait DHP2P {
-    async fn dh_request(&self, path: &str, body: Option<&str>, seq: &mut u32);
-    async fn dh_read_raw(&self) -> DHResponse;
-
-    async fn dh_read(&self) -> DHResponse {
-        let res = self.dh_read_raw().await;
-
-        assert!(res.code < 300, "Error response: {}", res.status);
+pub async fn p2p_handshake(
+    serial: String,
+    relay_mode: bool,
+    bind: &BindArgs,
+) -> io::Result<(UdpSocket, PTCPSession)> {
+    let mut diagnostics = Diagnostics::default();
+    let result = p2p_handshake_diagnosed(serial, relay_mode, bind, &mut diagnostics).await;
 
-        res
-    }
+    diagnostics.print_steps();
+    result
 }
 
-#[async_trait]
-impl DHP2P for UdpSocket {
-    async fn dh_request(&self, path: &str, body: Option<&str>, seq: &mut u32) {
-        let method = match body {
-            Some(_) => "DHPOST",
-            None => "DHGET",
+/**
+ * The P2P handshake, recording each step in `diagnostics`.
+ * Finding the device and setting up the relay run concurrently on their own sockets
+ */
+pub async fn p2p_handshake_diagnosed(
+    serial: String,
+    relay_mode: bool,
+    bind: &BindArgs,
+    diagnostics: &mut Diagnostics,
+) -> io::Result<(UdpSocket, PTCPSession)> {
+    let cloud = CloudClient::new();
+    diagnostics.started = Some(Instant::now());
+    let socket = bind_udp(bind, bind.udp_port)?;
+    diagnostics.local_port = Some(socket.local_addr()?.port());
+
+    let main_server = diagnostics.timed("resolve", resolve(MAIN_SERVER)).await?;
+
+    let probe_socket = bind_udp(bind, None)?;
+    let relay_socket = bind_udp(bind, bind.relay_udp_port)?;
+    let cid: [u8; 8] = rand::random();
+    let shared = &*diagnostics;
+
+    // Ask the device for a P2P channel through its p2psrv server
+    let direct = async {
+        shared
+            .timed("probe p2psrv", cloud.probe_p2psrv(&socket, main_server))
+            .await?;
+        let p2psrv = shared
+            .timed(
+                "online p2psrv",
+                cloud.online_p2psrv(&socket, main_server, &serial),
+            )
+            .await?;
+        let p2psrv = resolve(&p2psrv.us).await?;
+
+        shared
+            .timed(
+                "probe device",
+                cloud.probe_device(&probe_socket, p2psrv, &serial),
+            )
+            .await?;
+
+        let request = P2PChannelRequest {
+            identify: cid
+                .iter()
+                .map(|b| format!("{:x}", b))
+                .collect::<Vec<_>>()
+                .join(" "),
+            ip_encrypt: true,
+            local_addr: format!("127.0.0.1:{}", socket.local_addr()?.port()),
+            version: "5.0.0".to_string(),
         };
+        let channel = shared
+            .timed("p2p channel", async {
+                cloud
+                    .p2p_channel(&socket, main_server, &serial, &request)
+                    .await?
+                    .wait(&socket)
+                    .await
+            })
+            .await;
+
+        if channel
+            .as_ref()
+            .is_err_and(|e| e.kind() == io::ErrorKind::PermissionDenied)
+        {
+            eprintln!("Device requires authentication when creating P2P channel.");
+            eprintln!("Authentication is not supported at this time.");
+        }
+        channel
+    };
 
-        let body = match body {
-            Some(s) => s,
-            None => "",
-        };
+    // Get an agent from the relay server and register with it
+    let relay = async {
+        let relay = shared
+            .timed(
+                "online relay",
+                cloud.online_relay(&relay_socket, main_server),
+            )
+            .await?;
+        let relay = resolve(&relay.address).await?;
+
+        let agent = shared
+            .timed("relay agent", cloud.relay_agent(&relay_socket, relay))
+            .await?;
+        let agent_addr = resolve(&agent.agent).await?;
+
+        shared
+            .timed(
+                "relay start",
+                cloud.relay_start(
+                    &relay_socket,
+                    agent_addr,
+                    &agent.token,
+                    &RelayStartRequest {
+                        client: ":0".to_string(),
+                    },
+                ),
+            )
+            .await?;
+
+        io::Result::Ok((agent, agent_addr))
+    };
 
-        // random a 32-bit number
-        let nonce = rand::random::<u32>();
-        // iso8601 time string
-        let currdate = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
-        let pwd = format!("{}{}DHP2P:{}:{}", nonce, currdate, USERNAME, USERKEY);
-
-        // sha1 then base64
-        let mut hasher = sha1::Sha1::new();
-        hasher.update(pwd);
-        let hash_digest = hasher.finalize();
-        let digest = base64::engine::general_purpose::STANDARD.encode(&hash_digest);
-
-        *seq += 1;
-
-        let req = format!("\
-            {} {} HTTP/1.1\r\n\
-            CSeq: {}\r\n\
-            Authorization: WSSE profile=\"UsernameToken\"\r\n\
-            X-WSSE: UsernameToken Username=\"{}\", PasswordDigest=\"{}\", Nonce=\"{}\", Created=\"{}\"\r\n\r\n{}",
-            method, path, seq, USERNAME, digest, nonce, currdate, body,
-        );
+    let (
+        P2PChannel {
+            local_addr: device_laddr,
+            pub_addr: device,
+        },
+        (RelayAgent { agent, .. }, agent_addr),
+    ) = tokio::try_join!(direct, relay)?;
+    diagnostics.device_public = Some(device.clone());
+    diagnostics.device_local = Some(device_laddr.clone());
+
+    // The request and its retransmissions go to the main server but the agent answers,
+    // so the socket is only connected to the agent once the channel is set up
+    diagnostics
+        .timed("relay channel", async {
+            cloud
+                .relay_channel(
+                    &relay_socket,
+                    main_server,
+                    &serial,
+                    &RelayChannelRequest {
+                        agent_addr: agent.clone(),
+                    },
+                )
+                .await?
+                .wait(&relay_socket)
+                .await
+        })
+        .await?;
+
+    relay_socket
+        .connect(destination(&relay_socket, agent_addr))
+        .await?;
 
-        println!(">>> {}", self.peer_addr().unwrap());
-        println!("{}", req);
-        println!("---");
+    let mut session = PTCPSession::new();
+
+    diagnostics
+        .timed("relay sync", async {
+            relay_socket
+                .ptcp_request(session.send(PTCPBody::Sync))
+                .await;
+            ptcp_read(&relay_socket, &mut session, "relay sync").await?;
+            Ok(())
+        })
+        .await?;
 
-        self.send(req.as_bytes()).await.unwrap();
+    if relay_mode {
+        return Ok((relay_socket, session));
     }
 
-    async fn dh_read_raw(&self) -> DHResponse {
-        println!("### {}", self.peer_addr().unwrap());
+    let sign = diagnostics
+        .timed("relay sign", async {
+            relay_socket
+                .ptcp_request(session.send(PTCPBody::SignRequest))
+                .await;
+            let mut res = ptcp_read(&relay_socket, &mut session, "relay sign").await?;
+
+            while let PTCPBody::Empty = res.body {
+                res = ptcp_read(&relay_socket, &mut session, "relay sign").await?;
+            }
+
+            // Relays answering with another control type are still read as before the typed
+            // messages, the sign being the fields of the reply
+            match res.body.control_fields() {
+                Some((0x18, sign)) => Ok(sign),
+                Some((kind, sign)) => {
+                    eprintln!(
+                        "Relay sign: expected a sign, got a control message of type 0x{:02x}",
+                        kind
+                    );
+                    Ok(sign)
+                }
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Expected the sign from the relay, got {:?}", res.body),
+                )),
+            }
+        })
+        .await?;
+
+    let (device, device_laddr) = (parse_addr(&device)?, parse_addr(&device_laddr)?);
+    let mut punch = Punch::default();
+    let peer = diagnostics
+        .timed(
+            "hole punch",
+            hole_punch(&socket, cid, device, device_laddr, &mut punch),
+        )
+        .await;
+    diagnostics.punch = punch;
+    eprintln!("Connected to the device at {}", peer?);
+
+    let mut session = PTCPSession::new();
 
-        let mut buf = [0u8; 4096];
-        let n = self.recv(&mut buf).await.unwrap();
-        let res = String::from_utf8_lossy(&buf[0..n]);
+    diagnostics
+        .timed("device sync", async {
+            socket.ptcp_request(session.send(PTCPBody::Sync)).await;
+            let mut res = ptcp_read(&socket, &mut session, "device sync").await?;
+            match res.body {
+                PTCPBody::Sync => {}
+                body => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Expected the sync from the device, got {:?}", body),
+                    ))
+                }
+            }
 
-        println!("<<< {}", self.peer_addr().unwrap());
-        println!("{}", res);
-        println!("---");
+            socket
+                .ptcp_request(session.send(PTCPBody::Auth(sign.clone())))
+                .await;
+
+            res = ptcp_read(&socket, &mut session, "device sync").await?;
+            while let PTCPBody::Empty = res.body {
+                res = ptcp_read(&socket, &mut session, "device sync").await?;
+            }
+            match res.body {
+                PTCPBody::AuthResponse(_) => {}
+                body => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Expected the authentication response from the device, got {:?}",
+                            body
+                        ),
+                    ))
+                }
+            }
 
-        let res = DHResponse::parse_response(&res);
-        println!("{:?}", res);
+            socket
+                .ptcp_request(session.send(PTCPBody::AuthConfirm))
+                .await;
+            res = ptcp_read(&socket, &mut session, "device sync").await?;
+
+            match res.body {
+                PTCPBody::Empty => Ok(()),
+                body => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Expected the acknowledgement of the confirmation from the device, got {:?}",
+                        body
+                    ),
+                )),
+            }
+        })
+        .await?;
 
-        res
-    }
+    Ok((socket, session))
 }
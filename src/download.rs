@@ -0,0 +1,306 @@
+use chrono::NaiveDateTime;
+use clap::Args;
+use std::{io, path::PathBuf, time::Duration};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncSeekExt, AsyncWriteExt},
+    time::Instant,
+};
+
+use crate::{
+    auth::Credentials,
+    media::MediaSession,
+    net::BindArgs,
+    segment::{Chunk, Format, Segmenter},
+    shutdown::{self, Shutdown},
+    tunnel::Tunnel,
+};
+
+/// Attempts without progress before giving up
+const MAX_ATTEMPTS: u32 = 5;
+/// Tolerance when matching the keyframe a download resumes from (90 kHz)
+const RESUME_TOLERANCE: i64 = 4500;
+
+fn parse_time(s: &str) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .map_err(|_| "expected YYYY-MM-DD HH:MM:SS".to_string())
+}
+
+#[derive(Args)]
+pub struct DownloadArgs {
+    /// Username of the camera
+    #[arg(short, long)]
+    username: Option<String>,
+    /// Password of the camera
+    #[arg(short, long)]
+    password: Option<String>,
+    /// Channel to download
+    #[arg(short, long, default_value_t = 1)]
+    channel: u32,
+    /// Stream subtype, 0 for main stream and 1 for sub stream
+    #[arg(short, long, default_value_t = 0)]
+    subtype: u32,
+    /// RTSP port of the device
+    #[arg(long, default_value_t = 554)]
+    remote_port: u16,
+    /// Start of the footage, in the device's local time
+    #[arg(long, value_name = "YYYY-MM-DD HH:MM:SS", value_parser = parse_time)]
+    start: NaiveDateTime,
+    /// End of the footage, in the device's local time
+    #[arg(long, value_name = "YYYY-MM-DD HH:MM:SS", value_parser = parse_time)]
+    end: NaiveDateTime,
+    /// Playback speed requested from the device, 1 for real time
+    #[arg(long, default_value_t = 8)]
+    speed: u32,
+    /// Output file (MPEG-TS). Default: {serial}_ch{channel}_{start}.ts
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+impl DownloadArgs {
+    fn credentials(&self) -> Option<Credentials> {
+        Some(Credentials {
+            username: self.username.clone()?,
+            password: self.password.clone().unwrap_or_default(),
+        })
+    }
+}
+
+/**
+ * Written part of the download, resumed from the last keyframe
+ */
+struct Progress {
+    /// Time of the last keyframe written (90 kHz) and the file length before it
+    keyframe: Option<(i64, u64)>,
+    /// Time of the last keyframe written (90 kHz), since the start
+    position: i64,
+}
+
+fn format_duration(seconds: i64) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/**
+ * Download from the last keyframe written until the end, an error or a shutdown request
+ */
+async fn download_session(
+    tunnel: &Tunnel,
+    args: &DownloadArgs,
+    path: &PathBuf,
+    progress: &mut Progress,
+    shutdown: &Shutdown,
+) -> io::Result<()> {
+    // Playback restarts on a whole second at or before the keyframe
+    let (resume, length) = progress.keyframe.unwrap_or((0, 0));
+    let skipped = resume / 90000;
+    let start = args.start + chrono::Duration::seconds(skipped);
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)
+        .await?;
+    file.set_len(length).await?;
+    file.seek(io::SeekFrom::End(0)).await?;
+
+    let url = format!(
+        "rtsp://127.0.0.1:{}/cam/playback?channel={}&subtype={}&starttime={}&endtime={}",
+        args.remote_port,
+        args.channel,
+        args.subtype,
+        start.format("%Y_%m_%d_%H_%M_%S"),
+        args.end.format("%Y_%m_%d_%H_%M_%S")
+    );
+
+    let headers = [
+        ("Range", "npt=0.000-".to_string()),
+        ("Speed", args.speed.to_string()),
+    ];
+    let play = async {
+        let stream = tunnel.open(args.remote_port).await?;
+        MediaSession::play(stream, url, args.credentials(), true, &headers).await
+    };
+    let mut session = tokio::select! {
+        session = play => session?,
+        _ = shutdown.requested() => return Err(io::ErrorKind::Interrupted.into()),
+    };
+
+    // Every keyframe starts a segment, marking where a later attempt can resume
+    let mut segmenter = Segmenter::new(
+        Format::Ts,
+        session.codec,
+        session.params.clone(),
+        session.audio.clone(),
+        Duration::ZERO,
+        session.rtptime,
+    )
+    .offset(skipped * 90000);
+
+    let total = (args.end - args.start).num_seconds();
+    let mut length = length;
+    let mut writing = progress.keyframe.is_none();
+    let mut last_report = Instant::now();
+
+    loop {
+        let frames = tokio::select! {
+            frames = session.next() => frames,
+            // What was written so far is flushed below
+            _ = shutdown.requested() => break,
+        };
+        let frames = match frames {
+            Ok(frames) => frames,
+            // The device closes the stream at the end of the footage
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+
+        for frame in frames {
+            for chunk in segmenter.push(frame) {
+                let data = match chunk {
+                    Chunk::Segment { time, header } => {
+                        // Skip the overlap with what was already written
+                        writing = writing || time >= resume - RESUME_TOLERANCE;
+                        if !writing {
+                            continue;
+                        }
+
+                        progress.keyframe = Some((time, length));
+                        progress.position = time;
+                        header
+                    }
+                    Chunk::Data(data) => data,
+                };
+
+                if writing {
+                    file.write_all(&data).await?;
+                    length += data.len() as u64;
+                }
+            }
+        }
+
+        if last_report.elapsed() >= Duration::from_secs(2) {
+            last_report = Instant::now();
+
+            let position = progress.position / 90000;
+            eprintln!(
+                "Download: {}% {} / {}, {:.1} MB",
+                (position * 100 / total.max(1)).min(100),
+                format_duration(position),
+                format_duration(total),
+                length as f64 / 1_000_000.0
+            );
+        }
+
+        if progress.position / 90000 >= total {
+            break;
+        }
+    }
+
+    file.flush().await?;
+
+    // The stream may end early when the realm is closed
+    if progress.position / 90000 + 2 < total {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Stream ended before the end of the footage",
+        ));
+    }
+
+    Ok(())
+}
+
+/**
+ * Download recorded footage of a channel, resuming over a new session after failures.
+ * Returns false when interrupted by a shutdown request
+ */
+pub async fn download(
+    serial: String,
+    relay_mode: bool,
+    bind: BindArgs,
+    args: DownloadArgs,
+    shutdown: &Shutdown,
+) -> bool {
+    if args.end <= args.start {
+        clap::Error::raw(
+            clap::error::ErrorKind::ValueValidation,
+            "--end must be after --start\n",
+        )
+        .exit();
+    }
+
+    let path = args.output.clone().unwrap_or_else(|| {
+        PathBuf::from(format!(
+            "{}_ch{}_{}.ts",
+            serial,
+            args.channel,
+            args.start.format("%Y%m%d-%H%M%S")
+        ))
+    });
+
+    let mut progress = Progress {
+        keyframe: None,
+        position: 0,
+    };
+    let mut attempts = 0;
+
+    loop {
+        let before = progress.position;
+
+        let connect = Tunnel::connect(serial.clone(), relay_mode, &bind);
+        let result = tokio::select! {
+            result = connect => match result {
+                Ok(tunnel) => {
+                    let result =
+                        download_session(&tunnel, &args, &path, &mut progress, shutdown).await;
+                    match shutdown.is_requested() {
+                        true => shutdown::close(&tunnel).await,
+                        false => tunnel.close(),
+                    }
+                    result
+                }
+                Err(e) => Err(e),
+            },
+            _ = shutdown.requested() => Err(io::ErrorKind::Interrupted.into()),
+        };
+
+        match result {
+            Ok(()) => {
+                eprintln!("Download: saved {}", path.display());
+                return true;
+            }
+            Err(_) if shutdown.is_requested() => break,
+            Err(e) => eprintln!("Download: {}", e),
+        }
+
+        attempts = match progress.position > before {
+            true => 1,
+            false => attempts + 1,
+        };
+        if attempts >= MAX_ATTEMPTS {
+            eprintln!("Download: failed {} times without progress", attempts);
+            std::process::exit(1);
+        }
+
+        eprintln!(
+            "Download: resuming from {} in 5 seconds",
+            format_duration(progress.position / 90000)
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+            _ = shutdown.requested() => break,
+        }
+    }
+
+    eprintln!(
+        "Download: interrupted at {}, {} is incomplete",
+        format_duration(progress.position / 90000),
+        path.display()
+    );
+    false
+}
@@ -0,0 +1,354 @@
+use clap::Args;
+use serde_json::{json, Value};
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{
+    auth::Credentials,
+    cgi::{CgiClient, Multipart},
+    net::BindArgs,
+    shutdown::{self, Shutdown},
+    tunnel::Tunnel,
+};
+
+/// Heartbeat interval requested from the device
+const HEARTBEAT: u64 = 5;
+/// Realms failing to open in a row before the session is re-established
+const MAX_FAILURES: u32 = 3;
+
+fn parse_webhook(s: &str) -> Result<String, String> {
+    match s.strip_prefix("http://") {
+        Some(rest) if !rest.starts_with('/') && !rest.is_empty() => Ok(s.to_string()),
+        _ => Err("only http://host[:port]/path webhooks are supported".to_string()),
+    }
+}
+
+#[derive(Args)]
+pub struct EventsArgs {
+    /// Username of the camera
+    #[arg(short, long)]
+    username: Option<String>,
+    /// Password of the camera
+    #[arg(short, long)]
+    password: Option<String>,
+    /// HTTP port of the device
+    #[arg(long, default_value_t = 80)]
+    remote_port: u16,
+    /// Event codes to subscribe to, e.g. VideoMotion,VideoBlind,CrossLineDetection
+    #[arg(long, value_delimiter = ',', default_value = "All")]
+    codes: Vec<String>,
+    /// URL receiving each event as a JSON POST request, can be repeated.
+    /// Events are printed to stdout when no webhook is given
+    #[arg(short, long, value_name = "http://host[:port]/path", value_parser = parse_webhook)]
+    webhook: Vec<String>,
+}
+
+impl EventsArgs {
+    fn credentials(&self) -> Option<Credentials> {
+        Some(Credentials {
+            username: self.username.clone()?,
+            password: self.password.clone().unwrap_or_default(),
+        })
+    }
+}
+
+/**
+ * An event of the device, e.g. `Code=VideoMotion;action=Start;index=0`
+ */
+#[derive(Debug)]
+pub struct Event {
+    pub code: String,
+    pub action: String,
+    pub index: Option<u32>,
+    pub data: Option<Value>,
+}
+
+impl Event {
+    fn parse(entry: &str) -> Option<Event> {
+        let mut event = Event {
+            code: String::new(),
+            action: String::new(),
+            index: None,
+            data: None,
+        };
+        let mut rest = entry.trim();
+
+        while !rest.is_empty() {
+            // The data is JSON and runs to the end of the entry
+            if let Some(data) = rest.strip_prefix("data=") {
+                event.data = serde_json::from_str(data).ok();
+                break;
+            }
+
+            let (param, next) = rest.split_once(';').unwrap_or((rest, ""));
+            match param.split_once('=') {
+                Some(("Code", v)) => event.code = v.to_string(),
+                Some(("action", v)) => event.action = v.to_string(),
+                Some(("index", v)) => event.index = v.parse().ok(),
+                _ => {}
+            }
+            rest = next.trim_start();
+        }
+
+        (!event.code.is_empty()).then_some(event)
+    }
+
+    /**
+     * Events in the body of a part, one per `Code=` line
+     */
+    pub fn parse_all(body: &str) -> Vec<Event> {
+        let mut entries: Vec<String> = Vec::new();
+
+        for line in body.lines() {
+            match entries.last_mut() {
+                Some(entry) if !line.starts_with("Code=") => {
+                    entry.push('\n');
+                    entry.push_str(line);
+                }
+                _ => entries.push(line.to_string()),
+            }
+        }
+
+        entries.iter().filter_map(|e| Event::parse(e)).collect()
+    }
+
+    pub fn to_json(&self, serial: &str) -> Value {
+        json!({
+            "serial": serial,
+            "time": chrono::Local::now().to_rfc3339(),
+            "code": self.code,
+            "action": self.action,
+            "index": self.index,
+            "data": self.data,
+        })
+    }
+}
+
+/**
+ * POST a JSON body to a plain HTTP URL
+ */
+async fn post(url: &str, body: &str) -> io::Result<()> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Not an http:// URL"))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let address = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:80", host),
+    };
+
+    let mut stream = TcpStream::connect(address).await?;
+
+    let req = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: dh-p2p\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    );
+    stream.write_all(req.as_bytes()).await?;
+
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status).await?;
+
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "Webhook answered {}",
+            status.trim()
+        ))),
+    }
+}
+
+fn deliver(serial: &str, event: &Event, webhooks: &[String]) {
+    let body = event.to_json(serial).to_string();
+
+    if webhooks.is_empty() {
+        println!("{}", body);
+        return;
+    }
+
+    for url in webhooks {
+        let url = url.clone();
+        let body = body.clone();
+        tokio::spawn(async move {
+            if let Err(e) = post(&url, &body).await {
+                eprintln!("Events: {} {}", url, e);
+            }
+        });
+    }
+}
+
+/**
+ * Attach to the event stream and deliver events until it ends
+ */
+async fn subscribe(client: &CgiClient, serial: &str, args: &EventsArgs) -> io::Result<()> {
+    let uri = format!(
+        "/cgi-bin/eventManager.cgi?action=attach&codes=[{}]&heartbeat={}",
+        args.codes.join(","),
+        HEARTBEAT
+    );
+
+    let (res, body) = timeout(
+        Duration::from_secs(HEARTBEAT * 3),
+        client.open("GET", &uri, &[]),
+    )
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Subscription timed out"))??;
+    let mut parts = Multipart::new(&res, body)?;
+
+    eprintln!("Events: subscribed to {}", args.codes.join(","));
+
+    loop {
+        // Heartbeats keep the stream busy, silence means the realm is gone
+        let part = timeout(Duration::from_secs(HEARTBEAT * 3), parts.next())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Heartbeat timed out"))??;
+        let Some(part) = part else {
+            return Ok(());
+        };
+
+        for event in Event::parse_all(&String::from_utf8_lossy(&part)) {
+            deliver(serial, &event, &args.webhook);
+        }
+    }
+}
+
+/**
+ * Forward the device's events, resubscribing when the realm or the session is lost,
+ * until a shutdown is requested
+ */
+pub async fn events(
+    serial: String,
+    relay_mode: bool,
+    bind: BindArgs,
+    args: EventsArgs,
+    shutdown: &Shutdown,
+) {
+    loop {
+        let connect = Tunnel::connect(serial.clone(), relay_mode, &bind);
+        let tunnel = tokio::select! {
+            result = connect => match result {
+                Ok(tunnel) => tunnel,
+                Err(e) => {
+                    eprintln!("Events: handshake failed, retrying in 5 seconds: {}", e);
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_secs(5)) => continue,
+                        _ = shutdown.requested() => return,
+                    }
+                }
+            },
+            _ = shutdown.requested() => return,
+        };
+        let client = CgiClient::new(tunnel.clone(), args.remote_port, args.credentials());
+        let mut failures = 0;
+
+        while failures < MAX_FAILURES {
+            let started = tokio::time::Instant::now();
+
+            let result = tokio::select! {
+                result = subscribe(&client, &serial, &args) => result,
+                _ = shutdown.requested() => break,
+            };
+            match result {
+                Ok(()) => eprintln!("Events: stream ended"),
+                Err(e) => eprintln!("Events: {}", e),
+            }
+
+            // A subscription that lasted is not a failure of the session
+            match started.elapsed() > Duration::from_secs(HEARTBEAT * 6) {
+                true => failures = 0,
+                false => failures += 1,
+            }
+
+            eprintln!("Events: resubscribing in 5 seconds");
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                _ = shutdown.requested() => break,
+            }
+        }
+
+        if shutdown.is_requested() {
+            shutdown::close(&tunnel).await;
+            return;
+        }
+
+        eprintln!("Events: re-establishing the PTCP session");
+        tunnel.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_events() {
+        let events = Event::parse_all(
+            "Code=VideoMotion;action=Start;index=0\r\nCode=VideoBlind;action=Stop;index=2\r\n",
+        );
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].code, "VideoMotion");
+        assert_eq!(events[0].action, "Start");
+        assert_eq!(events[0].index, Some(0));
+        assert!(events[0].data.is_none());
+        assert_eq!(events[1].code, "VideoBlind");
+        assert_eq!(events[1].action, "Stop");
+        assert_eq!(events[1].index, Some(2));
+    }
+
+    #[test]
+    fn multi_line_data() {
+        let events = Event::parse_all(
+            "Code=CrossLineDetection;action=Start;index=0;data={\n   \"Name\" : \"Line; 1\",\n   \"Object\" : { \"ObjectID\" : 7 }\n}\nCode=VideoMotion;action=Stop;index=1",
+        );
+
+        assert_eq!(events.len(), 2);
+        let data = events[0].data.as_ref().unwrap();
+        assert_eq!(data["Name"], "Line; 1");
+        assert_eq!(data["Object"]["ObjectID"], 7);
+        assert_eq!(events[1].code, "VideoMotion");
+    }
+
+    #[test]
+    fn heartbeats_and_invalid_fields() {
+        // Heartbeats carry no code, invalid indexes and data are left out
+        let events = Event::parse_all("Heartbeat\r\nCode=AlarmLocal;action=Pulse;index=x;data={");
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].code, "AlarmLocal");
+        assert_eq!(events[0].action, "Pulse");
+        assert_eq!(events[0].index, None);
+        assert!(events[0].data.is_none());
+        assert!(Event::parse_all("").is_empty());
+    }
+
+    #[test]
+    fn json() {
+        let event = Event::parse_all("Code=VideoMotion;action=Start;index=0").remove(0);
+        let json = event.to_json("SERIAL");
+
+        assert_eq!(json["serial"], "SERIAL");
+        assert_eq!(json["code"], "VideoMotion");
+        assert_eq!(json["action"], "Start");
+        assert_eq!(json["index"], 0);
+        assert!(json["data"].is_null());
+    }
+
+    #[test]
+    fn webhooks() {
+        assert!(parse_webhook("http://127.0.0.1:8123/api/webhook/dh").is_ok());
+        assert!(parse_webhook("http://host").is_ok());
+        assert!(parse_webhook("https://host/path").is_err());
+        assert!(parse_webhook("host/path").is_err());
+        assert!(parse_webhook("http:///path").is_err());
+    }
+}
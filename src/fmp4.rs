@@ -0,0 +1,516 @@
+use crate::codec::{AacConfig, ParameterSets, SpsInfo, VideoCodec};
+
+pub const VIDEO_TIMESCALE: u32 = 90000;
+
+const MATRIX: [u32; 9] = [0x00010000, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000];
+
+fn mp4_box(kind: &[u8; 4], children: &[&[u8]]) -> Vec<u8> {
+    let size = 8 + children.iter().map(|c| c.len()).sum::<usize>();
+
+    let mut out = Vec::with_capacity(size);
+    out.extend_from_slice(&(size as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    for child in children {
+        out.extend_from_slice(child);
+    }
+
+    out
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, children: &[&[u8]]) -> Vec<u8> {
+    let header = ((version as u32) << 24 | flags).to_be_bytes();
+    mp4_box(kind, &[&[&header[..]], children].concat())
+}
+
+fn matrix() -> Vec<u8> {
+    MATRIX.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+/**
+ * A single encoded frame of a fragment
+ */
+pub struct Sample {
+    pub duration: u32,
+    pub keyframe: bool,
+    /// Length-prefixed NAL units
+    pub data: Vec<u8>,
+}
+
+/**
+ * Video track description, built once the parameter sets are known
+ */
+pub struct VideoTrack {
+    codec: VideoCodec,
+    params: ParameterSets,
+    info: SpsInfo,
+}
+
+impl VideoTrack {
+    pub fn new(codec: VideoCodec, params: &ParameterSets) -> Option<VideoTrack> {
+        if !params.is_complete(codec) {
+            return None;
+        }
+
+        let info = SpsInfo::parse(codec, params.sps.as_ref()?)?;
+
+        Some(VideoTrack {
+            codec,
+            params: params.clone(),
+            info,
+        })
+    }
+
+    fn avcc(&self) -> Vec<u8> {
+        let sps = self.params.sps.as_ref().unwrap();
+        let pps = self.params.pps.as_ref().unwrap();
+
+        let mut config = vec![0x01, sps[1], sps[2], sps[3], 0xFF, 0xE1];
+        config.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+        config.extend_from_slice(sps);
+        config.push(0x01);
+        config.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+        config.extend_from_slice(pps);
+
+        mp4_box(b"avcC", &[&config])
+    }
+
+    fn hvcc(&self) -> Vec<u8> {
+        let mut config = vec![0x01];
+        config.extend_from_slice(&self.info.profile_tier_level);
+        config.extend_from_slice(&[
+            0xF0,
+            0x00, // min_spatial_segmentation_idc
+            0xFC, // parallelismType
+            0xFC | self.info.chroma_format_idc as u8,
+            0xF8 | (self.info.bit_depth_luma - 8) as u8,
+            0xF8 | (self.info.bit_depth_chroma - 8) as u8,
+            0x00,
+            0x00, // avgFrameRate
+            0x0F, // one temporal layer, nested, 4-byte lengths
+            0x03, // numOfArrays
+        ]);
+
+        for nal in self.params.iter() {
+            config.push(0x80 | self.codec.nal_type(nal));
+            config.extend_from_slice(&1u16.to_be_bytes());
+            config.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+            config.extend_from_slice(nal);
+        }
+
+        mp4_box(b"hvcC", &[&config])
+    }
+
+    fn sample_entry(&self) -> Vec<u8> {
+        let mut entry = vec![0u8; 6];
+        entry.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+        entry.extend_from_slice(&[0u8; 16]);
+        entry.extend_from_slice(&(self.info.width as u16).to_be_bytes());
+        entry.extend_from_slice(&(self.info.height as u16).to_be_bytes());
+        entry.extend_from_slice(&0x00480000u32.to_be_bytes());
+        entry.extend_from_slice(&0x00480000u32.to_be_bytes());
+        entry.extend_from_slice(&[0u8; 4]);
+        entry.extend_from_slice(&1u16.to_be_bytes()); // frame count
+        entry.extend_from_slice(&[0u8; 32]); // compressor name
+        entry.extend_from_slice(&0x0018u16.to_be_bytes());
+        entry.extend_from_slice(&0xFFFFu16.to_be_bytes());
+
+        match self.codec {
+            VideoCodec::H264 => mp4_box(b"avc1", &[&entry, &self.avcc()]),
+            VideoCodec::H265 => mp4_box(b"hvc1", &[&entry, &self.hvcc()]),
+        }
+    }
+}
+
+/**
+ * AAC track description
+ */
+pub struct AudioTrack {
+    config: AacConfig,
+}
+
+impl AudioTrack {
+    pub fn new(config: AacConfig) -> AudioTrack {
+        AudioTrack { config }
+    }
+
+    fn esds(&self) -> Vec<u8> {
+        let asc = &self.config.raw;
+
+        let mut decoder_config = vec![0x40, 0x15, 0x00, 0x00, 0x00];
+        decoder_config.extend_from_slice(&[0u8; 8]); // max and average bitrate
+        decoder_config.extend_from_slice(&[0x05, asc.len() as u8]);
+        decoder_config.extend_from_slice(asc);
+
+        let mut es = vec![0x00, 0x00, 0x00]; // ES id, flags
+        es.extend_from_slice(&[0x04, decoder_config.len() as u8]);
+        es.extend_from_slice(&decoder_config);
+        es.extend_from_slice(&[0x06, 0x01, 0x02]); // SL config
+
+        let mut descriptor = vec![0x03, es.len() as u8];
+        descriptor.extend_from_slice(&es);
+
+        full_box(b"esds", 0, 0, &[&descriptor])
+    }
+
+    fn sample_entry(&self) -> Vec<u8> {
+        let mut entry = vec![0u8; 6];
+        entry.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+        entry.extend_from_slice(&[0u8; 8]);
+        entry.extend_from_slice(&(self.config.channels as u16).to_be_bytes());
+        entry.extend_from_slice(&16u16.to_be_bytes()); // sample size
+        entry.extend_from_slice(&[0u8; 4]);
+        entry.extend_from_slice(&(self.config.sample_rate << 16).to_be_bytes());
+
+        mp4_box(b"mp4a", &[&entry, &self.esds()])
+    }
+}
+
+struct TrackInfo {
+    id: u32,
+    timescale: u32,
+    handler: &'static [u8; 4],
+    name: &'static [u8],
+    media_header: Vec<u8>,
+    sample_entry: Vec<u8>,
+    width: u32,
+    height: u32,
+    volume: u16,
+}
+
+fn trak(track: &TrackInfo) -> Vec<u8> {
+    let mut tkhd = Vec::new();
+    tkhd.extend_from_slice(&[0u8; 8]);
+    tkhd.extend_from_slice(&track.id.to_be_bytes());
+    tkhd.extend_from_slice(&[0u8; 20]);
+    tkhd.extend_from_slice(&track.volume.to_be_bytes());
+    tkhd.extend_from_slice(&[0u8; 2]);
+    tkhd.extend_from_slice(&matrix());
+    tkhd.extend_from_slice(&(track.width << 16).to_be_bytes());
+    tkhd.extend_from_slice(&(track.height << 16).to_be_bytes());
+    let tkhd = full_box(b"tkhd", 0, 0x000003, &[&tkhd]);
+
+    let mut mdhd = Vec::new();
+    mdhd.extend_from_slice(&[0u8; 8]);
+    mdhd.extend_from_slice(&track.timescale.to_be_bytes());
+    mdhd.extend_from_slice(&[0u8; 4]);
+    mdhd.extend_from_slice(&[0x55, 0xC4, 0x00, 0x00]); // und
+    let mdhd = full_box(b"mdhd", 0, 0, &[&mdhd]);
+
+    let hdlr = full_box(
+        b"hdlr",
+        0,
+        0,
+        &[&[0u8; 4], track.handler, &[0u8; 12], track.name],
+    );
+
+    let dref = full_box(
+        b"dref",
+        0,
+        0,
+        &[&1u32.to_be_bytes(), &full_box(b"url ", 0, 1, &[])],
+    );
+    let dinf = mp4_box(b"dinf", &[&dref]);
+
+    let stsd = full_box(b"stsd", 0, 0, &[&1u32.to_be_bytes(), &track.sample_entry]);
+    let stts = full_box(b"stts", 0, 0, &[&[0u8; 4]]);
+    let stsc = full_box(b"stsc", 0, 0, &[&[0u8; 4]]);
+    let stsz = full_box(b"stsz", 0, 0, &[&[0u8; 8]]);
+    let stco = full_box(b"stco", 0, 0, &[&[0u8; 4]]);
+    let stbl = mp4_box(b"stbl", &[&stsd, &stts, &stsc, &stsz, &stco]);
+
+    let minf = mp4_box(b"minf", &[&track.media_header, &dinf, &stbl]);
+    let mdia = mp4_box(b"mdia", &[&mdhd, &hdlr, &minf]);
+
+    mp4_box(b"trak", &[&tkhd, &mdia])
+}
+
+/**
+ * ftyp and moov boxes, video is track 1 and audio track 2
+ */
+pub fn init_segment(video: &VideoTrack, audio: Option<&AudioTrack>) -> Vec<u8> {
+    let ftyp = mp4_box(b"ftyp", &[b"iso5", &[0, 0, 2, 0], b"iso5iso6mp41"]);
+
+    let mut tracks = vec![TrackInfo {
+        id: 1,
+        timescale: VIDEO_TIMESCALE,
+        handler: b"vide",
+        name: b"VideoHandler\0",
+        media_header: full_box(b"vmhd", 0, 1, &[&[0u8; 8]]),
+        sample_entry: video.sample_entry(),
+        width: video.info.width,
+        height: video.info.height,
+        volume: 0,
+    }];
+
+    if let Some(audio) = audio {
+        tracks.push(TrackInfo {
+            id: 2,
+            timescale: audio.config.sample_rate,
+            handler: b"soun",
+            name: b"SoundHandler\0",
+            media_header: full_box(b"smhd", 0, 0, &[&[0u8; 4]]),
+            sample_entry: audio.sample_entry(),
+            width: 0,
+            height: 0,
+            volume: 0x0100,
+        });
+    }
+
+    let mut mvhd = Vec::new();
+    mvhd.extend_from_slice(&[0u8; 8]);
+    mvhd.extend_from_slice(&1000u32.to_be_bytes());
+    mvhd.extend_from_slice(&[0u8; 4]);
+    mvhd.extend_from_slice(&0x00010000u32.to_be_bytes());
+    mvhd.extend_from_slice(&0x0100u16.to_be_bytes());
+    mvhd.extend_from_slice(&[0u8; 10]);
+    mvhd.extend_from_slice(&matrix());
+    mvhd.extend_from_slice(&[0u8; 24]);
+    mvhd.extend_from_slice(&(tracks.len() as u32 + 1).to_be_bytes()); // next track id
+    let mvhd = full_box(b"mvhd", 0, 0, &[&mvhd]);
+
+    let traks: Vec<Vec<u8>> = tracks.iter().map(trak).collect();
+    let trexs: Vec<Vec<u8>> = tracks
+        .iter()
+        .map(|t| {
+            full_box(
+                b"trex",
+                0,
+                0,
+                &[&t.id.to_be_bytes(), &1u32.to_be_bytes(), &[0u8; 12]],
+            )
+        })
+        .collect();
+    let mvex = mp4_box(
+        b"mvex",
+        &trexs.iter().map(|t| t.as_slice()).collect::<Vec<_>>(),
+    );
+
+    let mut moov: Vec<&[u8]> = vec![&mvhd];
+    moov.extend(traks.iter().map(|t| t.as_slice()));
+    moov.push(&mvex);
+    let moov = mp4_box(b"moov", &moov);
+
+    [ftyp, moov].concat()
+}
+
+/**
+ * Samples of one track in a fragment
+ */
+pub struct TrackRun<'a> {
+    pub track_id: u32,
+    pub base_time: u64,
+    pub samples: &'a [Sample],
+}
+
+/**
+ * moof and mdat boxes for runs of samples
+ */
+pub fn media_segment(sequence: u32, runs: &[TrackRun]) -> Vec<u8> {
+    let mfhd = full_box(b"mfhd", 0, 0, &[&sequence.to_be_bytes()]);
+
+    let build = |data_offset: u32| {
+        let mut offset = data_offset;
+        let mut trafs = Vec::new();
+
+        for run in runs {
+            // default-base-is-moof
+            let tfhd = full_box(b"tfhd", 0, 0x020000, &[&run.track_id.to_be_bytes()]);
+            let tfdt = full_box(b"tfdt", 1, 0, &[&run.base_time.to_be_bytes()]);
+
+            let mut trun = Vec::new();
+            trun.extend_from_slice(&(run.samples.len() as u32).to_be_bytes());
+            trun.extend_from_slice(&offset.to_be_bytes());
+            for sample in run.samples {
+                let flags: u32 = if sample.keyframe {
+                    0x02000000
+                } else {
+                    0x01010000
+                };
+                trun.extend_from_slice(&sample.duration.to_be_bytes());
+                trun.extend_from_slice(&(sample.data.len() as u32).to_be_bytes());
+                trun.extend_from_slice(&flags.to_be_bytes());
+                offset += sample.data.len() as u32;
+            }
+
+            // data offset, duration, size and flags present
+            let trun = full_box(b"trun", 0, 0x000701, &[&trun]);
+            trafs.push(mp4_box(b"traf", &[&tfhd, &tfdt, &trun]));
+        }
+
+        let mut moof: Vec<&[u8]> = vec![&mfhd];
+        moof.extend(trafs.iter().map(|t| t.as_slice()));
+        mp4_box(b"moof", &moof)
+    };
+
+    let size = build(0).len();
+    let moof = build(size as u32 + 8);
+
+    let data: Vec<&[u8]> = runs
+        .iter()
+        .flat_map(|r| r.samples.iter().map(|s| s.data.as_slice()))
+        .collect();
+    let mdat = mp4_box(b"mdat", &data);
+
+    [moof, mdat].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 1920x1080 H.264 main profile parameter sets
+    const SPROP: &str = "Z00AKp2oHgCJ+WbgICAoAAADAAgAAAMBlCA=,aO48gA==";
+
+    /**
+     * Split a buffer into boxes, checking that their sizes add up
+     */
+    fn boxes(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut boxes = Vec::new();
+
+        while !data.is_empty() {
+            let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            assert!(size >= 8 && size <= data.len(), "bad box size {}", size);
+
+            boxes.push((&data[4..8], &data[8..size]));
+            data = &data[size..];
+        }
+
+        boxes
+    }
+
+    /**
+     * Content of the box at a path of box types
+     */
+    fn find<'a>(data: &'a [u8], path: &[&[u8]]) -> &'a [u8] {
+        let (kind, rest) = path.split_first().unwrap();
+        let (_, content) = boxes(data)
+            .into_iter()
+            .find(|(k, _)| k == kind)
+            .unwrap_or_else(|| panic!("no {} box", String::from_utf8_lossy(kind)));
+
+        if rest.is_empty() {
+            content
+        } else {
+            find(content, rest)
+        }
+    }
+
+    fn video_track() -> VideoTrack {
+        let fmtp = vec![("sprop-parameter-sets".to_string(), SPROP.to_string())];
+        let params = ParameterSets::from_fmtp(VideoCodec::H264, &fmtp);
+
+        VideoTrack::new(VideoCodec::H264, &params).unwrap()
+    }
+
+    #[test]
+    fn init() {
+        let audio = AudioTrack::new(AacConfig::from_hex("1408").unwrap());
+        let init = init_segment(&video_track(), Some(&audio));
+
+        let kinds: Vec<&[u8]> = boxes(&init).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(kinds, [b"ftyp", b"moov"]);
+
+        let moov = find(&init, &[b"moov"]);
+        let kinds: Vec<&[u8]> = boxes(moov).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(kinds, [b"mvhd", b"trak", b"trak", b"mvex"]);
+
+        // Track size as 16.16 fixed point at the end of tkhd
+        let tkhd = find(moov, &[b"trak", b"tkhd"]);
+        assert_eq!(tkhd[tkhd.len() - 8..], [0x07, 0x80, 0, 0, 0x04, 0x38, 0, 0]);
+
+        let stsd = find(moov, &[b"trak", b"mdia", b"minf", b"stbl", b"stsd"]);
+        let avc1 = find(&stsd[8..], &[b"avc1"]);
+        let avcc = find(&avc1[78..], &[b"avcC"]);
+        assert_eq!(avcc[..4], [0x01, 0x4D, 0x00, 0x2A]);
+
+        // Each box parses down to the sample entries of both tracks
+        let traks: Vec<&[u8]> = boxes(moov)
+            .into_iter()
+            .filter(|(k, _)| *k == b"trak")
+            .map(|(_, c)| c)
+            .collect();
+        let stsd = find(traks[1], &[b"mdia", b"minf", b"stbl", b"stsd"]);
+        let mp4a = find(&stsd[8..], &[b"mp4a"]);
+        assert_eq!(u16::from_be_bytes([mp4a[16], mp4a[17]]), 1);
+        assert_eq!(
+            u32::from_be_bytes(mp4a[24..28].try_into().unwrap()),
+            16000 << 16
+        );
+        boxes(&mp4a[28..]);
+    }
+
+    #[test]
+    fn fragment() {
+        let video = [
+            Sample {
+                duration: 3000,
+                keyframe: true,
+                data: vec![0, 0, 0, 2, 0x65, 1],
+            },
+            Sample {
+                duration: 3000,
+                keyframe: false,
+                data: vec![0, 0, 0, 3, 0x41, 2, 3],
+            },
+        ];
+        let audio = [Sample {
+            duration: 1024,
+            keyframe: true,
+            data: vec![0x21, 0x10, 0x05],
+        }];
+
+        let segment = media_segment(
+            7,
+            &[
+                TrackRun {
+                    track_id: 1,
+                    base_time: 90000,
+                    samples: &video,
+                },
+                TrackRun {
+                    track_id: 2,
+                    base_time: 16000,
+                    samples: &audio,
+                },
+            ],
+        );
+
+        let kinds: Vec<&[u8]> = boxes(&segment).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(kinds, [b"moof", b"mdat"]);
+
+        let moof = find(&segment, &[b"moof"]);
+        assert_eq!(find(moof, &[b"mfhd"])[4..], 7u32.to_be_bytes());
+
+        let trafs: Vec<&[u8]> = boxes(moof)
+            .into_iter()
+            .filter(|(k, _)| *k == b"traf")
+            .map(|(_, c)| c)
+            .collect();
+        assert_eq!(trafs.len(), 2);
+
+        // The data offsets from the start of moof point at the samples in mdat
+        for (traf, samples) in trafs.into_iter().zip([&video[..], &audio[..]]) {
+            let tfdt = find(traf, &[b"tfdt"]);
+            assert_eq!(tfdt[0], 1);
+
+            let trun = find(traf, &[b"trun"]);
+            assert_eq!(
+                u32::from_be_bytes(trun[4..8].try_into().unwrap()),
+                samples.len() as u32
+            );
+            let mut offset = u32::from_be_bytes(trun[8..12].try_into().unwrap()) as usize;
+
+            for (i, sample) in samples.iter().enumerate() {
+                let entry = &trun[12 + i * 12..24 + i * 12];
+                let size = u32::from_be_bytes(entry[4..8].try_into().unwrap()) as usize;
+
+                assert_eq!(
+                    u32::from_be_bytes(entry[..4].try_into().unwrap()),
+                    sample.duration
+                );
+                assert_eq!(&segment[offset..offset + size], sample.data.as_slice());
+                offset += size;
+            }
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use async_trait::async_trait;
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::Notify,
+    time::{self, Instant},
+};
+
+use crate::{
+    auth::Credentials,
+    http::{Handler, Request, Response},
+    media::MediaSession,
+    segment::{Chunk, Format, Segmenter},
+    sessions::Sessions,
+};
+
+const SEGMENT_DURATION: Duration = Duration::from_secs(2);
+/// Segments listed in the playlist, a few more are kept for slow clients
+const PLAYLIST_SIZE: usize = 6;
+const KEPT_SEGMENTS: usize = PLAYLIST_SIZE + 4;
+/// Stop pulling a stream nobody requested for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Streams by channel and subtype
+type Streams = Arc<Mutex<HashMap<(u32, u32), Arc<Stream>>>>;
+
+struct Segment {
+    sequence: u64,
+    duration: f64,
+    data: Arc<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct State {
+    init: Option<Arc<Vec<u8>>>,
+    segments: VecDeque<Segment>,
+    /// Segment being written: sequence, start time and data
+    current: Option<(u64, i64, Vec<u8>)>,
+    next_sequence: u64,
+    closed: bool,
+}
+
+/**
+ * Live segments of one channel
+ */
+struct Stream {
+    state: Mutex<State>,
+    updated: Notify,
+    last_access: Mutex<Instant>,
+}
+
+impl Stream {
+    fn push(&self, chunk: Chunk, format: Format) {
+        let mut state = self.state.lock().unwrap();
+
+        match chunk {
+            Chunk::Segment { time, header } => {
+                if let Some((sequence, start, data)) = state.current.take() {
+                    state.segments.push_back(Segment {
+                        sequence,
+                        duration: (time - start) as f64 / 90000.0,
+                        data: Arc::new(data),
+                    });
+
+                    while state.segments.len() > KEPT_SEGMENTS {
+                        state.segments.pop_front();
+                    }
+                }
+
+                let data = match format {
+                    Format::Ts => header,
+                    Format::Mp4 => {
+                        state.init = Some(Arc::new(header));
+                        Vec::new()
+                    }
+                };
+
+                let sequence = state.next_sequence;
+                state.next_sequence += 1;
+                state.current = Some((sequence, time, data));
+
+                self.updated.notify_waiters();
+            }
+            Chunk::Data(data) => {
+                if let Some((_, _, current)) = state.current.as_mut() {
+                    current.extend_from_slice(&data);
+                }
+            }
+        }
+    }
+
+    /**
+     * Wait until the state satisfies `f`, or the stream ends
+     */
+    async fn wait_for<T>(&self, f: impl Fn(&State) -> Option<T>) -> Option<T> {
+        let deadline = Instant::now() + WAIT_TIMEOUT;
+
+        loop {
+            // Register before checking so no update is missed
+            let notified = self.updated.notified();
+
+            {
+                let state = self.state.lock().unwrap();
+                if let Some(v) = f(&state) {
+                    return Some(v);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+
+            time::timeout_at(deadline, notified).await.ok()?;
+        }
+    }
+}
+
+/**
+ * Serves channels of the device as HLS at `/hls/{serial}/{channel}/index.m3u8`
+ */
+pub struct Hls {
+    sessions: Arc<Sessions>,
+    serial: String,
+    credentials: Option<Credentials>,
+    format: Format,
+    streams: Streams,
+}
+
+async fn pull(
+    sessions: &Sessions,
+    url: String,
+    credentials: Option<Credentials>,
+    format: Format,
+    stream: &Stream,
+) -> io::Result<()> {
+    let realm = sessions.tunnel().await?.open(554).await?;
+    let range = [("Range", "npt=0.000-".to_string())];
+    let mut session = MediaSession::play(realm, url, credentials, true, &range).await?;
+
+    let mut segmenter = Segmenter::new(
+        format,
+        session.codec,
+        session.params.clone(),
+        session.audio.clone(),
+        SEGMENT_DURATION,
+        session.rtptime,
+    );
+
+    loop {
+        if stream.last_access.lock().unwrap().elapsed() > IDLE_TIMEOUT {
+            return Ok(());
+        }
+
+        for frame in session.next().await? {
+            for chunk in segmenter.push(frame) {
+                stream.push(chunk, format);
+            }
+        }
+    }
+}
+
+impl Hls {
+    pub fn new(
+        sessions: Arc<Sessions>,
+        serial: String,
+        credentials: Option<Credentials>,
+        format: Format,
+    ) -> Hls {
+        Hls {
+            sessions,
+            serial,
+            credentials,
+            format,
+            streams: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /**
+     * Get the stream of a channel, starting to pull it if needed
+     */
+    fn stream(&self, channel: u32, subtype: u32) -> Arc<Stream> {
+        let mut streams = self.streams.lock().unwrap();
+
+        if let Some(stream) = streams.get(&(channel, subtype)) {
+            *stream.last_access.lock().unwrap() = Instant::now();
+            return stream.clone();
+        }
+
+        let stream = Arc::new(Stream {
+            state: Mutex::new(State::default()),
+            updated: Notify::new(),
+            last_access: Mutex::new(Instant::now()),
+        });
+        streams.insert((channel, subtype), stream.clone());
+
+        let sessions = self.sessions.clone();
+        let url = format!(
+            "rtsp://127.0.0.1:554/cam/realmonitor?channel={}&subtype={}",
+            channel, subtype
+        );
+        let credentials = self.credentials.clone();
+        let format = self.format;
+        let streams = self.streams.clone();
+        let task_stream = stream.clone();
+
+        tokio::spawn(async move {
+            eprintln!("HLS: starting channel {} subtype {}", channel, subtype);
+
+            match pull(&sessions, url, credentials, format, &task_stream).await {
+                Ok(()) => eprintln!("HLS: channel {} subtype {} idle", channel, subtype),
+                Err(e) => eprintln!("HLS: channel {} subtype {}: {}", channel, subtype, e),
+            }
+
+            task_stream.state.lock().unwrap().closed = true;
+            task_stream.updated.notify_waiters();
+
+            let mut streams = streams.lock().unwrap();
+            if streams
+                .get(&(channel, subtype))
+                .is_some_and(|s| Arc::ptr_eq(s, &task_stream))
+            {
+                streams.remove(&(channel, subtype));
+            }
+        });
+
+        stream
+    }
+
+    fn playlist(&self, state: &State, query: &str) -> String {
+        let listed: Vec<&Segment> = state
+            .segments
+            .iter()
+            .skip(state.segments.len().saturating_sub(PLAYLIST_SIZE))
+            .collect();
+
+        let target = listed
+            .iter()
+            .map(|s| s.duration.ceil() as u64)
+            .max()
+            .unwrap_or(1);
+
+        let mut playlist = String::from("#EXTM3U\n");
+        playlist += match self.format {
+            Format::Ts => "#EXT-X-VERSION:3\n",
+            Format::Mp4 => "#EXT-X-VERSION:7\n",
+        };
+        playlist += &format!("#EXT-X-TARGETDURATION:{}\n", target);
+        playlist += &format!(
+            "#EXT-X-MEDIA-SEQUENCE:{}\n",
+            listed.first().map_or(0, |s| s.sequence)
+        );
+        playlist += "#EXT-X-INDEPENDENT-SEGMENTS\n";
+
+        if let Format::Mp4 = self.format {
+            playlist += &format!("#EXT-X-MAP:URI=\"init.mp4{}\"\n", query);
+        }
+
+        for segment in listed {
+            playlist += &format!(
+                "#EXTINF:{:.3},\nsegment-{}.{}{}\n",
+                segment.duration,
+                segment.sequence,
+                self.extension(),
+                query
+            );
+        }
+
+        playlist
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.format {
+            Format::Ts => "ts",
+            Format::Mp4 => "m4s",
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for Hls {
+    async fn handle(&self, req: Request) -> Response {
+        if req.method != "GET" && req.method != "HEAD" {
+            return Response::error(405);
+        }
+
+        // /hls/{serial}/{channel}/{file}
+        let parts: Vec<&str> = req.path.trim_start_matches('/').split('/').collect();
+        let (serial, channel, file) = match parts[..] {
+            ["hls", serial, channel, file] => (serial, channel, file),
+            _ => return Response::error(404),
+        };
+
+        let Ok(channel) = channel.parse::<u32>() else {
+            return Response::error(404);
+        };
+        if serial != self.serial {
+            return Response::error(404);
+        }
+
+        let subtype = req
+            .query("subtype")
+            .and_then(|s| s.parse::<u32>().ok())
+            .unwrap_or(0);
+        let query = match subtype {
+            0 => String::new(),
+            s => format!("?subtype={}", s),
+        };
+
+        let stream = self.stream(channel, subtype);
+
+        if file == "index.m3u8" {
+            // Players need a couple of segments to start smoothly
+            let playlist = stream
+                .wait_for(|s| (s.segments.len() >= 2).then(|| self.playlist(s, &query)))
+                .await;
+
+            return match playlist {
+                Some(p) => Response::new(200, "application/vnd.apple.mpegurl", p.into_bytes())
+                    .header("Cache-Control", "no-cache"),
+                None => Response::error(502),
+            };
+        }
+
+        if file == "init.mp4" {
+            return match stream.wait_for(|s| s.init.clone()).await {
+                Some(init) => Response::new(200, "video/mp4", init.to_vec()),
+                None => Response::error(502),
+            };
+        }
+
+        let sequence = file
+            .strip_prefix("segment-")
+            .and_then(|f| f.strip_suffix(&format!(".{}", self.extension())))
+            .and_then(|s| s.parse::<u64>().ok());
+        let Some(sequence) = sequence else {
+            return Response::error(404);
+        };
+
+        let state = stream.state.lock().unwrap();
+        match state.segments.iter().find(|s| s.sequence == sequence) {
+            Some(segment) => Response::new(
+                200,
+                match self.format {
+                    Format::Ts => "video/mp2t",
+                    Format::Mp4 => "video/mp4",
+                },
+                segment.data.to_vec(),
+            ),
+            None => Response::error(404),
+        }
+    }
+}
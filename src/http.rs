@@ -0,0 +1,419 @@
+use async_trait::async_trait;
+use std::{io, sync::Arc};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+/// Largest request body accepted, bodies are read and thrown away
+const MAX_BODY: u64 = 64 * 1024;
+/// Longest request line or header line
+const MAX_LINE: u64 = 8 * 1024;
+/// Most header lines in a request
+const MAX_HEADERS: usize = 64;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+}
+
+pub struct Response {
+    pub code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/**
+ * Decode `%XX` escapes and `+` of a query component
+ */
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' => s
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok()),
+            _ => None,
+        };
+
+        match (escaped, bytes[i]) {
+            (Some(b), _) => {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+            (None, b'+') => out.push(b' '),
+            (None, b) => out.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).to_string()
+}
+
+impl Request {
+    pub fn query(&self, key: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn reason(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+impl Response {
+    pub fn new(code: u16, content_type: &str, body: Vec<u8>) -> Response {
+        Response {
+            code,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+        }
+    }
+
+    pub fn error(code: u16) -> Response {
+        Response::new(
+            code,
+            "text/plain",
+            format!("{}\n", reason(code)).into_bytes(),
+        )
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+#[async_trait]
+pub trait Handler: Send + Sync + 'static {
+    async fn handle(&self, req: Request) -> Response;
+}
+
+/**
+ * Dispatches requests to handlers by path prefix
+ */
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<(String, Box<dyn Handler>)>,
+}
+
+impl Router {
+    pub fn route(mut self, prefix: &str, handler: impl Handler) -> Router {
+        self.routes.push((prefix.to_string(), Box::new(handler)));
+        self
+    }
+}
+
+#[async_trait]
+impl Handler for Router {
+    async fn handle(&self, req: Request) -> Response {
+        match self.routes.iter().find(|(p, _)| req.path.starts_with(p)) {
+            Some((_, handler)) => handler.handle(req).await,
+            None => Response::error(404),
+        }
+    }
+}
+
+enum Line {
+    Text(String),
+    /// The client closed the connection, possibly in the middle of the line
+    End,
+    TooLong,
+}
+
+/**
+ * Read a line of the request head, without buffering more than `MAX_LINE`
+ */
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Line> {
+    let mut line = String::new();
+    let n = reader.take(MAX_LINE).read_line(&mut line).await?;
+
+    Ok(match line.ends_with('\n') {
+        true => Line::Text(line),
+        false if n as u64 == MAX_LINE => Line::TooLong,
+        false => Line::End,
+    })
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    res: &Response,
+    keep_alive: bool,
+    head_only: bool,
+) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", res.code, reason(res.code));
+    for (k, v) in &res.headers {
+        head += &format!("{}: {}\r\n", k, v);
+    }
+    head += &format!("Content-Length: {}\r\n", res.body.len());
+    head += if keep_alive {
+        "Connection: keep-alive\r\n\r\n"
+    } else {
+        "Connection: close\r\n\r\n"
+    };
+
+    writer.write_all(head.as_bytes()).await?;
+    if !head_only {
+        writer.write_all(&res.body).await?;
+    }
+
+    Ok(())
+}
+
+async fn handle_connection(stream: TcpStream, handler: Arc<dyn Handler>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let line = match read_line(&mut reader).await? {
+            Line::Text(line) => line,
+            Line::End => return Ok(()),
+            Line::TooLong => {
+                return write_response(&mut writer, &Response::error(414), false, false).await
+            }
+        };
+
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target), version) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Ok(());
+        };
+        let method = method.to_string();
+        let version = version.unwrap_or("HTTP/1.0").to_string();
+
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let path = percent_decode(path);
+        let query = query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                let (k, v) = p.split_once('=').unwrap_or((p, ""));
+                (percent_decode(k), percent_decode(v))
+            })
+            .collect();
+
+        let mut headers = Vec::new();
+        loop {
+            let line = match read_line(&mut reader).await? {
+                Line::Text(line) => line,
+                Line::End => return Ok(()),
+                Line::TooLong => {
+                    return write_response(&mut writer, &Response::error(431), false, false).await
+                }
+            };
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+
+            if headers.len() == MAX_HEADERS {
+                return write_response(&mut writer, &Response::error(431), false, false).await;
+            }
+
+            if let Some((k, v)) = line.split_once(':') {
+                headers.push((k.trim().to_string(), v.trim().to_string()));
+            }
+        }
+
+        let req = Request {
+            method,
+            path,
+            query,
+            headers,
+        };
+
+        let mut keep_alive = match req.header("Connection") {
+            Some(c) => c.eq_ignore_ascii_case("keep-alive"),
+            None => version == "HTTP/1.1",
+        };
+
+        eprintln!("HTTP: {} {}", req.method, req.path);
+
+        let head_only = req.method == "HEAD";
+        let length: u64 = req
+            .header("Content-Length")
+            .and_then(|l| l.parse().ok())
+            .unwrap_or(0);
+
+        // Request bodies are not used, a large one is refused rather than drained
+        let res = if length > MAX_BODY {
+            keep_alive = false;
+            Response::error(413)
+        } else {
+            let skipped =
+                tokio::io::copy(&mut (&mut reader).take(length), &mut tokio::io::sink()).await?;
+            if skipped < length {
+                return Ok(());
+            }
+            handler.handle(req).await
+        };
+
+        write_response(&mut writer, &res, keep_alive, head_only).await?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/**
+ * Serve HTTP/1.1 requests with the handler
+ */
+pub async fn serve(listener: TcpListener, handler: Arc<dyn Handler>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("HTTP: {}", e);
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, handler).await {
+                eprintln!("HTTP: {} {}", addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    #[async_trait]
+    impl Handler for Echo {
+        async fn handle(&self, req: Request) -> Response {
+            let body = format!("{} {:?}", req.path, req.query("q"));
+            Response::new(200, "text/plain", body.into_bytes())
+        }
+    }
+
+    /**
+     * Send raw data to a new server, returns everything it answered before closing
+     */
+    async fn exchange(data: &[u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::new(Echo)));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        // The server may close before reading everything
+        let _ = stream.write_all(data).await;
+        let _ = stream.shutdown().await;
+
+        let mut answer = Vec::new();
+        let _ = stream.read_to_end(&mut answer).await;
+        String::from_utf8_lossy(&answer).to_string()
+    }
+
+    /**
+     * Status of each response, bodies are not followed by a line break
+     */
+    fn statuses(answer: &str) -> Vec<&str> {
+        answer
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .filter_map(|rest| rest.split("\r\n").next())
+            .collect()
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(percent_decode("a%20b+c%2F%zz%4"), "a b c/%zz%4");
+    }
+
+    #[tokio::test]
+    async fn keep_alive() {
+        let answer = exchange(
+            b"GET /a?q=x%20y HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+
+        assert_eq!(statuses(&answer), vec!["200 OK", "200 OK"]);
+        assert!(answer.contains("/a Some(\"x y\")"));
+        assert!(answer.ends_with("/b None"));
+        assert!(!answer.contains("Access-Control-Allow-Origin"));
+    }
+
+    #[tokio::test]
+    async fn body_too_large() {
+        let answer = exchange(b"POST / HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n").await;
+
+        assert_eq!(statuses(&answer), vec!["413 Content Too Large"]);
+    }
+
+    #[tokio::test]
+    async fn request_line_too_long() {
+        let line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE as usize));
+        let answer = exchange(line.as_bytes()).await;
+
+        assert_eq!(statuses(&answer), vec!["414 URI Too Long"]);
+    }
+
+    #[tokio::test]
+    async fn header_too_long() {
+        let head = format!(
+            "GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n",
+            "a".repeat(MAX_LINE as usize)
+        );
+        let answer = exchange(head.as_bytes()).await;
+
+        assert_eq!(
+            statuses(&answer),
+            vec!["431 Request Header Fields Too Large"]
+        );
+    }
+
+    #[tokio::test]
+    async fn too_many_headers() {
+        let head = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X-A: b\r\n".repeat(MAX_HEADERS + 1)
+        );
+        let answer = exchange(head.as_bytes()).await;
+
+        assert_eq!(
+            statuses(&answer),
+            vec!["431 Request Header Fields Too Large"]
+        );
+    }
+
+    #[tokio::test]
+    async fn unterminated_line() {
+        // A client closing in the middle of a line gets no answer
+        assert_eq!(exchange(b"GET / HTTP/1.1").await, "");
+    }
+}
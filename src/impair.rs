@@ -0,0 +1,549 @@
+//! Test-only impaired link between the tunnel and a simulated device
+
+use async_trait::async_trait;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::mpsc,
+    time::{self, Duration, Instant},
+};
+
+use crate::ptcp::{Datagram, PTCPBody, PTCPSession, Retransmissions, PTCP};
+
+/// Longest a reordered datagram waits for the next one to pass it
+const HOLD_MAX: Duration = Duration::from_secs(1);
+/// Port the simulated device accepts realms on, echoing their data
+pub const ECHO_PORT: u16 = 554;
+
+/**
+ * Impairments applied to every datagram going in one direction
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Impairment {
+    /// Probability of dropping a datagram
+    pub loss: f64,
+    /// Delay of every datagram
+    pub delay: Duration,
+    /// Random extra delay, up to this
+    pub jitter: Duration,
+    /// Probability of delivering a datagram twice
+    pub duplicate: f64,
+    /// Probability of holding a datagram back until the next one was delivered
+    pub reorder: f64,
+    /// Largest datagram delivered, larger ones are dropped
+    pub mtu: Option<usize>,
+}
+
+/**
+ * What happened to the datagrams going in one direction
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub sent: usize,
+    pub delivered: usize,
+    pub lost: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+    pub oversized: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Direction {
+    ToDevice,
+    ToClient,
+}
+
+/**
+ * One side of the link
+ */
+pub struct Endpoint {
+    name: &'static str,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+}
+
+#[async_trait]
+impl Datagram for Endpoint {
+    async fn send_datagram(&self, data: &[u8]) -> io::Result<usize> {
+        self.tx
+            .send(data.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(data.len())
+    }
+
+    async fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self
+            .rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        // Like UDP, the end of a datagram larger than the buffer is lost
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn peer(&self) -> String {
+        self.name.to_string()
+    }
+}
+
+/**
+ * Settings and counters of one direction
+ */
+struct HalfLink {
+    impairment: Arc<Mutex<Impairment>>,
+    stats: Arc<Mutex<Stats>>,
+}
+
+/**
+ * A link whose impairments can be changed while it runs.
+ * Randomness comes from the seed only, and tests run on a paused clock
+ */
+pub struct Link {
+    to_device: HalfLink,
+    to_client: HalfLink,
+}
+
+impl Link {
+    /**
+     * Create a link, returning it with its client and device endpoints
+     */
+    pub fn new(seed: u64) -> (Link, Endpoint, Endpoint) {
+        let (client_tx, to_device_rx) = mpsc::unbounded_channel();
+        let (device_tx, to_client_rx) = mpsc::unbounded_channel();
+        let (to_device_tx, device_rx) = mpsc::unbounded_channel();
+        let (to_client_tx, client_rx) = mpsc::unbounded_channel();
+
+        let to_device = HalfLink {
+            impairment: Default::default(),
+            stats: Default::default(),
+        };
+        let to_client = HalfLink {
+            impairment: Default::default(),
+            stats: Default::default(),
+        };
+
+        tokio::spawn(carry(
+            to_device_rx,
+            to_device_tx,
+            to_device.impairment.clone(),
+            to_device.stats.clone(),
+            StdRng::seed_from_u64(seed),
+        ));
+        tokio::spawn(carry(
+            to_client_rx,
+            to_client_tx,
+            to_client.impairment.clone(),
+            to_client.stats.clone(),
+            StdRng::seed_from_u64(seed.wrapping_add(1)),
+        ));
+
+        let client = Endpoint {
+            name: "client",
+            tx: client_tx,
+            rx: tokio::sync::Mutex::new(client_rx),
+        };
+        let device = Endpoint {
+            name: "device",
+            tx: device_tx,
+            rx: tokio::sync::Mutex::new(device_rx),
+        };
+
+        (
+            Link {
+                to_device,
+                to_client,
+            },
+            client,
+            device,
+        )
+    }
+
+    fn half(&self, direction: Direction) -> &HalfLink {
+        match direction {
+            Direction::ToDevice => &self.to_device,
+            Direction::ToClient => &self.to_client,
+        }
+    }
+
+    pub fn impair(&self, direction: Direction, impairment: Impairment) {
+        *self.half(direction).impairment.lock().unwrap() = impairment;
+    }
+
+    pub fn stats(&self, direction: Direction) -> Stats {
+        self.half(direction).stats.lock().unwrap().clone()
+    }
+}
+
+/**
+ * Carry datagrams in one direction, applying the impairments
+ */
+async fn carry(
+    mut input: mpsc::UnboundedReceiver<Vec<u8>>,
+    output: mpsc::UnboundedSender<Vec<u8>>,
+    impairment: Arc<Mutex<Impairment>>,
+    stats: Arc<Mutex<Stats>>,
+    mut rng: StdRng,
+) {
+    // Datagrams in flight by delivery time, then by order of scheduling
+    let mut in_flight: BTreeMap<(Instant, u64), Vec<u8>> = BTreeMap::new();
+    let mut held: Option<(Instant, Vec<u8>)> = None;
+    let mut order = 0u64;
+
+    loop {
+        let next = in_flight
+            .keys()
+            .next()
+            .map(|(at, _)| *at)
+            .into_iter()
+            .chain(held.as_ref().map(|(at, _)| *at))
+            .min();
+
+        tokio::select! {
+            biased;
+
+            _ = time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                let now = Instant::now();
+                if held.as_ref().is_some_and(|(at, _)| *at <= now) {
+                    let (at, data) = held.take().unwrap();
+                    in_flight.insert((at, order), data);
+                    order += 1;
+                }
+
+                while let Some(entry) = in_flight.first_entry() {
+                    if entry.key().0 > now {
+                        break;
+                    }
+                    stats.lock().unwrap().delivered += 1;
+                    let _ = output.send(entry.remove());
+                }
+            }
+            data = input.recv() => {
+                let Some(data) = data else {
+                    return;
+                };
+
+                let impairment = impairment.lock().unwrap().clone();
+                let mut stats = stats.lock().unwrap();
+                stats.sent += 1;
+
+                if impairment.mtu.is_some_and(|mtu| data.len() > mtu) {
+                    stats.oversized += 1;
+                    continue;
+                }
+                if rng.gen_bool(impairment.loss) {
+                    stats.lost += 1;
+                    continue;
+                }
+
+                let at = Instant::now() + impairment.delay + impairment.jitter.mul_f64(rng.gen());
+
+                if held.is_none() && rng.gen_bool(impairment.reorder) {
+                    stats.reordered += 1;
+                    held = Some((at + HOLD_MAX, data));
+                    continue;
+                }
+
+                let copies = match rng.gen_bool(impairment.duplicate) {
+                    true => {
+                        stats.duplicated += 1;
+                        2
+                    }
+                    false => 1,
+                };
+                for _ in 0..copies {
+                    in_flight.insert((at, order), data.clone());
+                    order += 1;
+                }
+
+                // The held datagram follows the one passing it
+                if let Some((_, data)) = held.take() {
+                    in_flight.insert((at, order), data);
+                    order += 1;
+                }
+            }
+        }
+    }
+}
+
+/**
+ * A device accepting realms on the echo port and sending their data back, acknowledging,
+ * sequencing and retransmitting packets like the tunnel
+ */
+pub async fn simulated_device(endpoint: Endpoint) {
+    let mut session = PTCPSession::new();
+    let mut unacked = Retransmissions::default();
+
+    loop {
+        let due = unacked.next_due();
+        let packet = tokio::select! {
+            packet = endpoint.ptcp_read() => packet,
+            _ = time::sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                unacked.acknowledge(session.acked());
+                for packet in unacked.due() {
+                    endpoint.ptcp_request(packet).await;
+                }
+                continue;
+            }
+        };
+
+        let empty = matches!(packet.body, PTCPBody::Empty);
+        let packets = session.receive(packet);
+        if empty {
+            continue;
+        }
+
+        endpoint.ptcp_request(session.send(PTCPBody::Empty)).await;
+
+        for packet in packets {
+            let answer = match packet.body {
+                PTCPBody::Bind(realm, port) => PTCPBody::Status(
+                    realm,
+                    match port == ECHO_PORT as u32 {
+                        true => "CONN".to_string(),
+                        false => "DISC".to_string(),
+                    },
+                ),
+                PTCPBody::Payload(payload) => PTCPBody::Payload(payload),
+                _ => continue,
+            };
+
+            let answer = session.send(answer);
+            unacked.push(&answer);
+            endpoint.ptcp_request(answer).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::Tunnel;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// Length of each test message
+    const MESSAGE: usize = 8;
+    /// Silence after which no more data is expected, past several retransmissions
+    const QUIET: Duration = Duration::from_secs(10);
+
+    fn start(seed: u64) -> (Link, Tunnel) {
+        let (link, client, device) = Link::new(seed);
+        tokio::spawn(simulated_device(device));
+
+        (link, Tunnel::start(client, PTCPSession::new()))
+    }
+
+    fn message(i: usize) -> Vec<u8> {
+        format!("msg-{:04}", i).into_bytes()
+    }
+
+    /**
+     * Send each message in its own payload, then collect what was echoed until the link is quiet
+     */
+    async fn echo(
+        stream: &mut DuplexStream,
+        messages: impl Iterator<Item = usize>,
+    ) -> Vec<Vec<u8>> {
+        for i in messages {
+            stream.write_all(&message(i)).await.unwrap();
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        while let Ok(Ok(n)) = time::timeout(QUIET, stream.read(&mut buf)).await {
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+
+        received.chunks(MESSAGE).map(|m| m.to_vec()).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clean_link() {
+        let (link, tunnel) = start(1);
+        let mut stream = tunnel.open(ECHO_PORT).await.unwrap();
+
+        let received = echo(&mut stream, 0..20).await;
+
+        assert_eq!(received, (0..20).map(message).collect::<Vec<_>>());
+        assert_eq!(link.stats(Direction::ToDevice).lost, 0);
+        assert!(!tunnel.is_closed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refused_port() {
+        let (_link, tunnel) = start(1);
+
+        let e = tunnel.open(ECHO_PORT + 1).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_bind() {
+        let (link, tunnel) = start(1);
+        link.impair(
+            Direction::ToDevice,
+            Impairment {
+                loss: 1.0,
+                ..Default::default()
+            },
+        );
+
+        let started = Instant::now();
+        let e = tunnel.open(ECHO_PORT).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+
+        // The realm is forgotten, a later one opens once the link is back
+        link.impair(Direction::ToDevice, Impairment::default());
+        let mut stream = tunnel.open(ECHO_PORT).await.unwrap();
+        assert_eq!(tunnel.active_realms(), 1);
+        assert_eq!(echo(&mut stream, 0..1).await, vec![message(0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delay() {
+        let (link, tunnel) = start(1);
+        let delayed = Impairment {
+            delay: Duration::from_millis(150),
+            ..Default::default()
+        };
+        link.impair(Direction::ToDevice, delayed.clone());
+        link.impair(Direction::ToClient, delayed);
+
+        // The bind and its answer cross the link once each
+        let started = Instant::now();
+        let mut stream = tunnel.open(ECHO_PORT).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_millis(300));
+
+        let received = echo(&mut stream, 0..5).await;
+        assert_eq!(received, (0..5).map(message).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn loss_keeps_the_session() {
+        let (link, tunnel) = start(7);
+        let mut stream = tunnel.open(ECHO_PORT).await.unwrap();
+
+        let lossy = Impairment {
+            loss: 0.3,
+            ..Default::default()
+        };
+        link.impair(Direction::ToDevice, lossy.clone());
+        link.impair(Direction::ToClient, lossy);
+        let received = echo(&mut stream, 0..50).await;
+
+        assert_eq!(received, (0..50).map(message).collect::<Vec<_>>());
+        assert!(link.stats(Direction::ToDevice).lost > 0);
+        assert!(link.stats(Direction::ToClient).lost > 0);
+        assert!(!tunnel.is_closed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn duplicated_bind_answer() {
+        let (link, tunnel) = start(1);
+        link.impair(
+            Direction::ToClient,
+            Impairment {
+                duplicate: 1.0,
+                ..Default::default()
+            },
+        );
+
+        let mut stream = tunnel.open(ECHO_PORT).await.unwrap();
+        link.impair(Direction::ToClient, Impairment::default());
+
+        // The second CONN is ignored, the realm is open once
+        assert_eq!(link.stats(Direction::ToClient).duplicated, 2);
+        assert_eq!(tunnel.active_realms(), 1);
+        assert_eq!(
+            echo(&mut stream, 0..3).await,
+            (0..3).map(message).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reordering() {
+        let (link, tunnel) = start(1);
+        let mut stream = tunnel.open(ECHO_PORT).await.unwrap();
+
+        // Every datagram to the device not passing a held one is held until the next passes it
+        link.impair(
+            Direction::ToDevice,
+            Impairment {
+                reorder: 1.0,
+                ..Default::default()
+            },
+        );
+        let received = echo(&mut stream, 0..6).await;
+
+        assert_eq!(received, (0..6).map(message).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mtu() {
+        let (link, tunnel) = start(1);
+        let mut stream = tunnel.open(ECHO_PORT).await.unwrap();
+        link.impair(
+            Direction::ToDevice,
+            Impairment {
+                mtu: Some(1400),
+                ..Default::default()
+            },
+        );
+
+        let data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        stream.write_all(&data).await.unwrap();
+
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        while let Ok(Ok(n)) = time::timeout(QUIET, stream.read(&mut buf)).await {
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+
+        assert_eq!(link.stats(Direction::ToDevice).oversized, 0);
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deterministic() {
+        async fn run() -> (Vec<Vec<u8>>, Stats, Stats) {
+            let (link, tunnel) = start(42);
+            let mut stream = tunnel.open(ECHO_PORT).await.unwrap();
+
+            let impairment = Impairment {
+                loss: 0.1,
+                delay: Duration::from_millis(40),
+                jitter: Duration::from_millis(30),
+                duplicate: 0.1,
+                reorder: 0.1,
+                mtu: None,
+            };
+            link.impair(Direction::ToDevice, impairment.clone());
+            link.impair(Direction::ToClient, impairment);
+
+            let received = echo(&mut stream, 0..30).await;
+            (
+                received,
+                link.stats(Direction::ToDevice),
+                link.stats(Direction::ToClient),
+            )
+        }
+
+        assert_eq!(run().await, run().await);
+    }
+}
@@ -0,0 +1,191 @@
+use clap::Args;
+use std::io;
+
+use crate::{auth::Credentials, cgi::CgiClient, tunnel::Tunnel};
+
+#[derive(Args)]
+pub struct InfoArgs {
+    /// Username of the camera
+    #[arg(short, long)]
+    username: Option<String>,
+    /// Password of the camera
+    #[arg(short, long)]
+    password: Option<String>,
+    /// HTTP port of the device
+    #[arg(long, default_value_t = 80)]
+    remote_port: u16,
+    /// Local port of the tunnel to the RTSP port, used in the printed URLs
+    #[arg(long, default_value_t = 1554)]
+    rtsp_port: u16,
+}
+
+impl InfoArgs {
+    fn credentials(&self) -> Option<Credentials> {
+        Some(Credentials {
+            username: self.username.clone()?,
+            password: self.password.clone().unwrap_or_default(),
+        })
+    }
+}
+
+pub struct DeviceInfo {
+    pub device_type: String,
+    pub serial: String,
+    pub hardware: String,
+    pub firmware: String,
+}
+
+pub struct StreamInfo {
+    pub compression: String,
+    pub resolution: String,
+    pub fps: String,
+}
+
+pub struct ChannelInfo {
+    /// Channel number as used in RTSP URLs, starting from 1
+    pub number: u32,
+    pub title: String,
+    pub main: Option<StreamInfo>,
+    pub sub: Option<StreamInfo>,
+}
+
+fn lookup<'a>(table: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    table
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+pub async fn device_info(client: &CgiClient) -> io::Result<DeviceInfo> {
+    let system = client
+        .get_table("/cgi-bin/magicBox.cgi?action=getSystemInfo")
+        .await?;
+    let software = client
+        .get_table("/cgi-bin/magicBox.cgi?action=getSoftwareVersion")
+        .await?;
+
+    let get = |table: &[(String, String)], key| lookup(table, key).unwrap_or("?").to_string();
+
+    Ok(DeviceInfo {
+        device_type: get(&system, "deviceType"),
+        serial: get(&system, "serialNumber"),
+        hardware: get(&system, "hardwareVersion"),
+        firmware: get(&software, "version"),
+    })
+}
+
+/**
+ * Settings of a stream, e.g. `table.Encode[0].MainFormat[0]`
+ */
+fn stream_info(table: &[(String, String)], prefix: &str) -> Option<StreamInfo> {
+    let get = |key: &str| lookup(table, &format!("{}.Video.{}", prefix, key));
+
+    // Older firmwares give "resolution", newer ones "Width" and "Height"
+    let resolution = match (get("Width"), get("Height")) {
+        (Some(w), Some(h)) => format!("{}x{}", w, h),
+        _ => get("resolution").unwrap_or("?").to_string(),
+    };
+
+    Some(StreamInfo {
+        compression: get("Compression")?.to_string(),
+        resolution,
+        fps: get("FPS").unwrap_or("?").to_string(),
+    })
+}
+
+pub async fn channels(client: &CgiClient) -> io::Result<Vec<ChannelInfo>> {
+    let titles = client
+        .get_table("/cgi-bin/configManager.cgi?action=getConfig&name=ChannelTitle")
+        .await?;
+    let encode = client
+        .get_table("/cgi-bin/configManager.cgi?action=getConfig&name=Encode")
+        .await
+        .unwrap_or_default();
+
+    // table.ChannelTitle[0].Name=...
+    let channels = titles
+        .iter()
+        .filter_map(|(k, v)| {
+            let index = k
+                .strip_prefix("table.ChannelTitle[")?
+                .strip_suffix("].Name")?;
+            Some((index.parse::<u32>().ok()?, v.clone()))
+        })
+        .map(|(index, title)| ChannelInfo {
+            number: index + 1,
+            title,
+            main: stream_info(&encode, &format!("table.Encode[{}].MainFormat[0]", index)),
+            sub: stream_info(&encode, &format!("table.Encode[{}].ExtraFormat[0]", index)),
+        })
+        .collect();
+
+    Ok(channels)
+}
+
+pub fn rtsp_url(rtsp_port: u16, channel: u32, subtype: u32) -> String {
+    format!(
+        "rtsp://127.0.0.1{}/cam/realmonitor?channel={}&subtype={}",
+        if rtsp_port != 554 {
+            format!(":{}", rtsp_port)
+        } else {
+            String::new()
+        },
+        channel,
+        subtype
+    )
+}
+
+fn describe(stream: &Option<StreamInfo>) -> String {
+    match stream {
+        Some(s) => format!("{} {} {}fps", s.compression, s.resolution, s.fps),
+        None => "-".to_string(),
+    }
+}
+
+/**
+ * Print the channels with their local RTSP URLs
+ */
+pub fn print_channels(channels: &[ChannelInfo], rtsp_port: u16) {
+    println!(
+        "{:<4} {:<20} {:<26} {:<26}",
+        "CH", "TITLE", "MAIN STREAM", "SUB STREAM"
+    );
+    for channel in channels {
+        println!(
+            "{:<4} {:<20} {:<26} {:<26}",
+            channel.number,
+            channel.title,
+            describe(&channel.main),
+            describe(&channel.sub)
+        );
+    }
+
+    println!();
+    for channel in channels {
+        println!(
+            "{:<4} {}",
+            channel.number,
+            rtsp_url(rtsp_port, channel.number, 0)
+        );
+        println!("{:<4} {}", "", rtsp_url(rtsp_port, channel.number, 1));
+    }
+}
+
+/**
+ * Print the device information and its channels
+ */
+pub async fn info(tunnel: Tunnel, args: InfoArgs) -> io::Result<()> {
+    let client = CgiClient::new(tunnel, args.remote_port, args.credentials());
+
+    let device = device_info(&client).await?;
+    println!("Device:   {}", device.device_type);
+    println!("Serial:   {}", device.serial);
+    println!("Hardware: {}", device.hardware);
+    println!("Firmware: {}", device.firmware);
+    println!();
+
+    let channels = channels(&client).await?;
+    print_channels(&channels, args.rtsp_port);
+
+    Ok(())
+}
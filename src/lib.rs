@@ -0,0 +1,6 @@
+//! Codecs of the DH-P2P protocols, shared by the tunnel and the fuzz targets
+
+pub mod cloud;
+pub mod net;
+pub mod ptcp;
+pub mod stun;
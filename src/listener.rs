@@ -0,0 +1,82 @@
+use std::io;
+#[cfg(unix)]
+use std::{fs, os::unix::fs::FileTypeExt, path::PathBuf};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+/**
+ * A client connection, TCP or Unix
+ */
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Stream for T {}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /**
+     * Bind to `bind_address:port` or `unix:/path/to.sock`
+     */
+    pub async fn bind(address: &str) -> io::Result<Listener> {
+        let Some(path) = address.strip_prefix("unix:") else {
+            return Ok(Listener::Tcp(TcpListener::bind(address).await?));
+        };
+
+        Listener::bind_unix(path)
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: &str) -> io::Result<Listener> {
+        // A socket left over by a previous run would make bind fail
+        if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+
+        Ok(Listener::Unix(
+            UnixListener::bind(path)?,
+            PathBuf::from(path),
+        ))
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(_: &str) -> io::Result<Listener> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix sockets are not supported on this platform",
+        ))
+    }
+
+    /**
+     * Accept a client, returns its stream and a description of its address
+     */
+    pub async fn accept(&self) -> io::Result<(Box<dyn Stream>, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), addr.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), format!("unix:{}", path.display())))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
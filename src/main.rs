@@ -1,141 +1,524 @@
-use clap::Parser;
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
-use tokio::{
-    net::{TcpListener, UdpSocket},
-    sync::{mpsc, oneshot},
-};
+use clap::{Args, Parser, Subcommand};
+use std::{future::Future, io, sync::Arc, time::Duration};
+use tokio::net::TcpListener;
+
+use dh_p2p::{cloud, net, ptcp, stun};
 
 use crate::{
-    dh::p2p_handshake,
-    process::{dh_reader, dh_writer, process_reader, process_writer},
-    ptcp::PTCPEvent,
+    auth::Credentials,
+    cgi::CgiClient,
+    download::{download, DownloadArgs},
+    events::{events, EventsArgs},
+    hls::Hls,
+    http::Router,
+    info::{info, InfoArgs},
+    listener::Listener,
+    net::BindArgs,
+    pipe::pipe,
+    probe::{probe, ProbeArgs},
+    ptz::{ptz, PtzArgs},
+    record::{record, RecordArgs},
+    segment::Format,
+    sessions::Sessions,
+    shutdown::Shutdown,
+    snapshot::{snapshot, Snapshot, SnapshotArgs},
+    tunnel::Tunnel,
 };
 
+mod auth;
+mod cgi;
+mod codec;
 mod dh;
+mod download;
+mod events;
+mod fmp4;
+mod hls;
+mod http;
+#[cfg(test)]
+mod impair;
+mod info;
+mod listener;
+mod media;
+mod mpegts;
+mod pipe;
+mod probe;
 mod process;
-mod ptcp;
+mod ptz;
+mod record;
+mod rtp;
+mod rtsp;
+mod segment;
+mod sessions;
+mod shutdown;
+mod snapshot;
+mod tunnel;
+
+/// Exit status of a command stopped by a signal, as shells report it
+const EXIT_INTERRUPTED: i32 = 130;
 
 #[derive(Parser)]
 #[command(about = "A PoC implementation of TCP tunneling over Dahua P2P protocol.", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    /// Bind address, port and remote port. Default: 127.0.0.1:1554:554
-    #[arg(short, long, value_name = "[bind_address:]port:remote_port")]
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Bind address, port and remote port, or a Unix socket path and remote port.
+    /// IPv6 bind addresses are bracketed, e.g. [::1]:1554:554.
+    /// Default: 127.0.0.1:1554:554
+    #[arg(
+        short,
+        long,
+        value_name = "[bind_address:]port:remote_port|unix:path:remote_port"
+    )]
     port: Option<String>,
+    /// Serve HLS streams and snapshots over HTTP, e.g. 127.0.0.1:8080
+    #[arg(long, value_name = "[bind_address:]port")]
+    http: Option<String>,
+    /// Container of the HLS segments
+    #[arg(long, value_enum, default_value_t = Format::Ts)]
+    hls_format: Format,
+    /// HTTP port of the device, used to list channels and by the HTTP server
+    #[arg(long, value_name = "PORT", default_value_t = 80)]
+    http_remote_port: u16,
+    /// Username of the camera, used to list channels and by the HTTP server
+    #[arg(short, long)]
+    username: Option<String>,
+    /// Password of the camera, used to list channels and by the HTTP server
+    #[arg(long)]
+    password: Option<String>,
+    /// Establish the PTCP session on the first connection and close it when idle
+    #[arg(long, conflicts_with = "http")]
+    on_demand: bool,
+    /// Seconds without any open connection before an on-demand session is closed
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        requires = "on_demand"
+    )]
+    idle_timeout: u64,
+    /// Relay mode (experimental)
+    #[arg(short, long)]
+    relay: bool,
+    /// Serial number of the camera
+    #[arg(required = true)]
+    serial: Option<String>,
+    #[command(flatten)]
+    bind: BindArgs,
+}
+
+impl Cli {
+    fn credentials(&self) -> Option<Credentials> {
+        Some(Credentials {
+            username: self.username.clone()?,
+            password: self.password.clone().unwrap_or_default(),
+        })
+    }
+
+    /**
+     * Device of the forward mode, whose serial clap requires without a subcommand
+     */
+    fn device(&self) -> DeviceArgs {
+        DeviceArgs {
+            relay: self.relay,
+            serial: self.serial.clone().unwrap(),
+            bind: self.bind.clone(),
+        }
+    }
+}
+
+#[derive(Args)]
+struct DeviceArgs {
     /// Relay mode (experimental)
     #[arg(short, long)]
     relay: bool,
     /// Serial number of the camera
     serial: String,
+    #[command(flatten)]
+    bind: BindArgs,
 }
 
-#[tokio::main]
-async fn main() {
-    let args = Cli::parse();
+#[derive(Subcommand)]
+enum Command {
+    /// Record the RTSP stream of a channel into rolling segments
+    Record {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[command(flatten)]
+        args: RecordArgs,
+    },
+    /// Save a JPEG snapshot of a channel
+    Snapshot {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[command(flatten)]
+        args: SnapshotArgs,
+    },
+    /// Control the pan, tilt and zoom of a channel
+    Ptz {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[command(flatten)]
+        args: PtzArgs,
+    },
+    /// Forward device events (motion, tamper, IVS...) as JSON to webhooks or stdout
+    Events {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[command(flatten)]
+        args: EventsArgs,
+    },
+    /// Download recorded footage of a channel by time range
+    Download {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[command(flatten)]
+        args: DownloadArgs,
+    },
+    /// Print the device information and its channels with RTSP URLs
+    Info {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[command(flatten)]
+        args: InfoArgs,
+    },
+    /// Diagnose the handshake: NAT mapping, device addresses, hole punching and step timings
+    Probe {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[command(flatten)]
+        args: ProbeArgs,
+    },
+    /// Connect stdin and stdout to a remote port, e.g. as an SSH ProxyCommand
+    ///
+    /// Exits with 0 when the connection is closed, 1 when it is refused and 2 when the session is lost.
+    Pipe {
+        #[command(flatten)]
+        device: DeviceArgs,
+        /// Port of the device to connect to
+        remote_port: u16,
+    },
+}
 
-    let serial = args.serial;
-    let port = args.port.unwrap_or("127.0.0.1:1554:554".to_string());
+async fn forward(args: Cli, shutdown: &Shutdown) {
+    let device = args.device();
+    let port = args
+        .port
+        .clone()
+        .unwrap_or("127.0.0.1:1554:554".to_string());
 
-    let parts: Vec<&str> = port.split(':').collect();
-    let (bind_address, bind_port, remote_port): (&str, u16, u16) = match parts.len() {
-        2 => (
-            "127.0.0.1",
-            parts[0].parse().unwrap(),
-            parts[1].parse().unwrap(),
-        ),
-        3 => (
-            parts[0],
-            parts[1].parse().unwrap(),
-            parts[2].parse().unwrap(),
-        ),
-        _ => panic!("Invalid port specification"),
+    // unix:/path/to.sock:remote_port
+    let (bind_address, bind_port, remote_port) = match port.strip_prefix("unix:") {
+        Some(rest) => {
+            let (path, remote_port) = rest.rsplit_once(':').expect("Invalid port specification");
+            (format!("unix:{}", path), None, remote_port.parse().unwrap())
+        }
+        None => {
+            // A bracketed IPv6 address has colons of its own
+            let (bind_address, ports) = match port.strip_prefix('[') {
+                Some(rest) => rest.split_once("]:").expect("Invalid port specification"),
+                None if port.matches(':').count() == 2 => port.split_once(':').unwrap(),
+                None => ("127.0.0.1", port.as_str()),
+            };
+            let (bind_port, remote_port): (u16, u16) = match ports.split_once(':') {
+                Some((bind_port, remote_port)) => {
+                    (bind_port.parse().unwrap(), remote_port.parse().unwrap())
+                }
+                None => panic!("Invalid port specification"),
+            };
+
+            (
+                match bind_address.contains(':') {
+                    true => format!("[{}]:{}", bind_address, bind_port),
+                    false => format!("{}:{}", bind_address, bind_port),
+                },
+                Some(bind_port),
+                remote_port,
+            )
+        }
     };
 
     // Bind the listener to the address
-    let listener = TcpListener::bind(format!("{}:{}", bind_address, bind_port))
-        .await
-        .unwrap();
+    let listener = Listener::bind(&bind_address).await.unwrap();
 
-    let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
-
-    let (socket, session) = p2p_handshake(socket, serial, args.relay).await;
+    let http_listener = match &args.http {
+        Some(http) => {
+            let address = match http.contains(':') {
+                true => http.clone(),
+                false => format!("127.0.0.1:{}", http),
+            };
+            Some(TcpListener::bind(address).await.unwrap())
+        }
+        None => None,
+    };
 
-    let (dh_tx, dh_rx) = mpsc::channel::<PTCPEvent>(128);
-    let session = Arc::new(Mutex::new(session));
+    let idle_timeout = args
+        .on_demand
+        .then(|| Duration::from_secs(args.idle_timeout));
+    let sessions = Sessions::new(
+        device.serial.clone(),
+        device.relay,
+        device.bind.clone(),
+        idle_timeout,
+    );
 
-    let channels = Arc::new(Mutex::new(HashMap::<u32, mpsc::Sender<Vec<u8>>>::new()));
-    let conn_channels = Arc::new(Mutex::new(HashMap::<u32, oneshot::Sender<bool>>::new()));
+    // On demand, the session is only established for the first client
+    let tunnel = match args.on_demand {
+        true => None,
+        false => Some(
+            sessions
+                .tunnel()
+                .await
+                .unwrap_or_else(|e| handshake_failed(e)),
+        ),
+    };
 
-    println!("PTCP session established");
+    // The HTTP server gets its session through `sessions`, which replaces it when it dies
+    if let Some(http_listener) = http_listener {
+        let address = http_listener.local_addr().unwrap();
+        eprintln!(
+            "HLS URL: http://{}/hls/{}/1/index.m3u8",
+            address, device.serial
+        );
+        eprintln!(
+            "Snapshot URL: http://{}/snapshot/{}/1.jpg",
+            address, device.serial
+        );
 
-    /*
-     * Clone the handles
-     */
+        let hls = Hls::new(
+            sessions.clone(),
+            device.serial.clone(),
+            args.credentials(),
+            args.hls_format,
+        );
+        let snapshot = Snapshot::new(
+            sessions.clone(),
+            device.serial.clone(),
+            args.http_remote_port,
+            args.credentials(),
+        );
+        let router = Router::default()
+            .route("/hls/", hls)
+            .route("/snapshot/", snapshot);
+        tokio::spawn(http::serve(http_listener, Arc::new(router)));
+    }
 
-    let reader = Arc::new(socket);
-    let writer = reader.clone();
+    eprintln!("Ready to connect!");
+    match (bind_port, &tunnel) {
+        (Some(bind_port), Some(tunnel)) if remote_port == 554 => {
+            // List every channel when the device lets us query them
+            let channels = match args.credentials() {
+                Some(credentials) => {
+                    let client =
+                        CgiClient::new(tunnel.clone(), args.http_remote_port, Some(credentials));
+                    tokio::time::timeout(Duration::from_secs(10), info::channels(&client))
+                        .await
+                        .ok()
+                        .and_then(|r| r.ok())
+                }
+                None => None,
+            };
 
-    let session2 = session.clone();
-    let channels2 = channels.clone();
-    let conn_channels2 = conn_channels.clone();
+            match channels {
+                Some(channels) if !channels.is_empty() => {
+                    info::print_channels(&channels, bind_port)
+                }
+                _ => eprintln!("RTSP URL: {}", info::rtsp_url(bind_port, 1, 0)),
+            }
+        }
+        (Some(bind_port), None) if remote_port == 554 => {
+            eprintln!("RTSP URL: {}", info::rtsp_url(bind_port, 1, 0))
+        }
+        (Some(_), _) => {}
+        (None, _) => eprintln!("Listening on {}", bind_address),
+    }
 
-    let hb_tx = dh_tx.clone();
-    tokio::spawn(async move {
+    let accept = async {
         loop {
-            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-            hb_tx.send(PTCPEvent::Heartbeat).await.unwrap();
+            // The second item contains the IP and port of the new connection.
+            let (client, addr) = listener.accept().await.unwrap();
+            eprintln!("Accepted connection from {}", addr);
+
+            // A client waiting for its session or realm does not hold the others up
+            let sessions = sessions.clone();
+            tokio::spawn(async move {
+                // Without a session, only this client is dropped
+                let tunnel = match sessions.tunnel().await {
+                    Ok(tunnel) => tunnel,
+                    Err(e) => {
+                        eprintln!("Handshake failed, closing connection from {}: {}", addr, e);
+                        return;
+                    }
+                };
+
+                let (reader, writer) = tokio::io::split(client);
+
+                if let Err(e) = tunnel.forward(reader, writer, remote_port).await {
+                    eprintln!("{}", e);
+                }
+            });
         }
-    });
-
-    tokio::spawn(async move {
-        dh_writer(session, writer, dh_rx, remote_port.into()).await;
-    });
-
-    tokio::spawn(async move {
-        dh_reader(session2, reader, channels, conn_channels).await;
-    });
-
-    println!("Ready to connect!");
-    if remote_port == 554 {
-        println!(
-            "RTSP URL: rtsp://127.0.0.1{}/cam/realmonitor?channel=1&subtype=0",
-            if bind_port != 554 {
-                format!(":{}", bind_port)
-            } else {
-                String::new()
-            }
-        );
+    };
+
+    tokio::select! {
+        _ = accept => {}
+        _ = shutdown.requested() => {}
     }
 
-    loop {
-        // The second item contains the IP and port of the new connection.
-        let (client, addr) = listener.accept().await.unwrap();
-        println!("Accepted connection from {}", addr);
+    shutdown::close_sessions(&sessions).await;
+}
+
+fn handshake_failed(e: io::Error) -> ! {
+    eprintln!("Handshake failed: {}", e);
+    std::process::exit(1);
+}
+
+fn command_failed(command: &str, e: io::Error) -> ! {
+    eprintln!("{}: {}", command, e);
+    std::process::exit(1);
+}
+
+/**
+ * Establish the session of a command, exiting when the handshake fails
+ */
+async fn connect(device: &DeviceArgs) -> Tunnel {
+    Tunnel::connect(device.serial.clone(), device.relay, &device.bind)
+        .await
+        .unwrap_or_else(|e| handshake_failed(e))
+}
+
+/**
+ * Run a command on a new session until it ends or a shutdown is requested, then close the
+ * realms it left open. `None` when interrupted
+ */
+async fn run<T, F>(
+    device: &DeviceArgs,
+    shutdown: &Shutdown,
+    command: impl FnOnce(Tunnel) -> F,
+) -> Option<T>
+where
+    F: Future<Output = T>,
+{
+    let tunnel = tokio::select! {
+        tunnel = connect(device) => tunnel,
+        _ = shutdown.requested() => return None,
+    };
+
+    let result = tokio::select! {
+        result = command(tunnel.clone()) => Some(result),
+        _ = shutdown.requested() => None,
+    };
+
+    shutdown::close(&tunnel).await;
+    result
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = Cli::parse();
 
-        // Create a channel for the client
-        let (tx, rx) = mpsc::channel::<Vec<u8>>(128);
-        let (conn_tx, conn_rx) = oneshot::channel::<bool>();
-        let dh_tx = dh_tx.clone();
+    let shutdown = Shutdown::listen();
 
-        let realm_id = rand::random::<u32>();
+    match args.command.take() {
+        Some(Command::Record { device, args }) => {
+            record(device.serial, device.relay, device.bind, args, &shutdown).await
+        }
+        Some(Command::Snapshot { device, args }) => {
+            run(&device, &shutdown, |tunnel| {
+                snapshot(tunnel, &device.serial, args)
+            })
+            .await
+            .unwrap_or_else(|| std::process::exit(EXIT_INTERRUPTED))
+            .unwrap_or_else(|e| command_failed("Snapshot", e));
+        }
+        Some(Command::Ptz { device, args }) => {
+            run(&device, &shutdown, |tunnel| ptz(tunnel, args))
+                .await
+                .unwrap_or_else(|| std::process::exit(EXIT_INTERRUPTED))
+                .unwrap_or_else(|e| command_failed("PTZ", e));
+        }
+        Some(Command::Events { device, args }) => {
+            events(device.serial, device.relay, device.bind, args, &shutdown).await
+        }
+        Some(Command::Download { device, args }) => {
+            let completed =
+                download(device.serial, device.relay, device.bind, args, &shutdown).await;
+            if !completed {
+                std::process::exit(EXIT_INTERRUPTED);
+            }
+        }
+        Some(Command::Info { device, args }) => {
+            run(&device, &shutdown, |tunnel| info(tunnel, args))
+                .await
+                .unwrap_or_else(|| std::process::exit(EXIT_INTERRUPTED))
+                .unwrap_or_else(|e| command_failed("Info", e));
+        }
+        Some(Command::Probe { device, args }) => {
+            tokio::select! {
+                _ = probe(device.serial, device.relay, device.bind, args) => {}
+                _ = shutdown.requested() => std::process::exit(EXIT_INTERRUPTED),
+            }
+        }
+        Some(Command::Pipe {
+            device,
+            remote_port,
+        }) => {
+            let status = run(&device, &shutdown, |tunnel| pipe(tunnel, remote_port)).await;
+            std::process::exit(status.unwrap_or(EXIT_INTERRUPTED));
+        }
+        None => forward(args, &shutdown).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Store the channel in the map
-        channels2.lock().unwrap().insert(realm_id, tx);
-        conn_channels2.lock().unwrap().insert(realm_id, conn_tx);
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from([&["dh-p2p"], args].concat())
+    }
 
-        dh_tx.send(PTCPEvent::Connect(realm_id)).await.unwrap();
-        conn_rx.await.unwrap();
+    #[test]
+    fn forward_mode() {
+        let cli = parse(&["SERIAL"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.device().serial, "SERIAL");
+        assert!(!cli.device().relay);
 
-        let (reader, writer) = client.into_split();
+        let cli = parse(&[
+            "-p",
+            "1555:554",
+            "--on-demand",
+            "-r",
+            "--udp-port",
+            "5000",
+            "SERIAL",
+        ])
+        .unwrap();
+        assert!(cli.command.is_none());
+        assert!(cli.on_demand);
+        assert_eq!(cli.port.as_deref(), Some("1555:554"));
+        assert_eq!(cli.device().serial, "SERIAL");
+        assert!(cli.device().relay);
+        assert_eq!(cli.device().bind.udp_port, Some(5000));
+    }
 
-        tokio::spawn(async move {
-            process_reader(reader, realm_id, dh_tx).await;
-        });
+    #[test]
+    fn missing_serial() {
+        let e = parse(&[]).err().unwrap();
+        assert_eq!(e.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+
+        let e = parse(&["-p", "1555:554"]).err().unwrap();
+        assert_eq!(e.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+    }
 
-        tokio::spawn(async move {
-            process_writer(writer, rx).await;
-        });
+    #[test]
+    fn subcommand() {
+        let cli = parse(&["probe", "--udp-port", "5000", "SERIAL"]).unwrap();
+        let Some(Command::Probe { device, .. }) = cli.command else {
+            panic!("probe not parsed");
+        };
+        assert_eq!(device.serial, "SERIAL");
+        assert_eq!(device.bind.udp_port, Some(5000));
     }
 }
@@ -0,0 +1,124 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    auth::Credentials,
+    codec::{AacConfig, ParameterSets, VideoCodec},
+    rtp::{AacDepacketizer, AudioFrame, Frame, RtpPacket, VideoDepacketizer},
+    rtsp::{RtspClient, RtspMessage},
+};
+
+pub enum MediaFrame {
+    Video(Frame),
+    Audio(AudioFrame),
+}
+
+/**
+ * A playing RTSP stream with a video and an optional AAC track
+ */
+pub struct MediaSession<S> {
+    client: RtspClient<S>,
+    pub codec: VideoCodec,
+    pub params: ParameterSets,
+    pub audio: Option<AacConfig>,
+    /// RTP timestamps of video and audio at the start of playback
+    pub rtptime: (Option<u32>, Option<u32>),
+    video_pt: u8,
+    audio_pt: Option<u8>,
+    video: VideoDepacketizer,
+    aac: Option<AacDepacketizer>,
+}
+
+impl<S: AsyncRead + AsyncWrite> MediaSession<S> {
+    /**
+     * DESCRIBE, SETUP and PLAY the stream at `url`
+     */
+    pub async fn play(
+        stream: S,
+        url: String,
+        credentials: Option<Credentials>,
+        with_audio: bool,
+        headers: &[(&str, String)],
+    ) -> io::Result<MediaSession<S>> {
+        let mut client = RtspClient::new(stream, url, credentials);
+        let media = client.describe().await?;
+
+        let (video, codec) = media
+            .iter()
+            .filter(|m| m.kind == "video")
+            .find_map(|m| Some((m, VideoCodec::from_encoding(&m.encoding)?)))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Unsupported, "No H.264/H.265 video stream")
+            })?;
+
+        // Only AAC can be carried as is, G.711 and others are left out,
+        // as is AAC whose config or AU headers can't be read
+        let audio = media
+            .iter()
+            .filter(|m| with_audio && m.kind == "audio" && m.encoding == "MPEG4-GENERIC")
+            .find_map(|m| {
+                let config = m
+                    .fmtp
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case("config"))?;
+                Some((
+                    m,
+                    AacConfig::from_hex(&config.1)?,
+                    AacDepacketizer::new(&m.fmtp)?,
+                ))
+            });
+
+        client.setup(video, 0).await?;
+        if let Some((media, _, _)) = &audio {
+            client.setup(media, 2).await?;
+        }
+
+        let res = client.play(headers).await?;
+        let rtptime = (
+            client.rtptime(&res, video),
+            audio.as_ref().and_then(|(m, _, _)| client.rtptime(&res, m)),
+        );
+
+        Ok(MediaSession {
+            codec,
+            params: ParameterSets::from_fmtp(codec, &video.fmtp),
+            rtptime,
+            video_pt: video.payload_type,
+            audio_pt: audio.as_ref().map(|(m, _, _)| m.payload_type),
+            video: VideoDepacketizer::new(codec),
+            audio: audio.as_ref().map(|(_, c, _)| c.clone()),
+            aac: audio.map(|(_, _, d)| d),
+            client,
+        })
+    }
+
+    /**
+     * Read the next RTSP message, returns the frames it completes
+     */
+    pub async fn next(&mut self) -> io::Result<Vec<MediaFrame>> {
+        self.client.keepalive().await?;
+
+        let RtspMessage::Data(channel, data) = self.client.read_message().await? else {
+            return Ok(Vec::new());
+        };
+        let Some(packet) = RtpPacket::parse(&data) else {
+            return Ok(Vec::new());
+        };
+
+        match (channel, self.aac.as_mut()) {
+            (0, _) if packet.payload_type == self.video_pt => Ok(self
+                .video
+                .push(&packet)
+                .into_iter()
+                .map(MediaFrame::Video)
+                .collect()),
+            (2, Some(aac)) if Some(packet.payload_type) == self.audio_pt => Ok(aac
+                .push(&packet)
+                .into_iter()
+                .map(MediaFrame::Audio)
+                .collect()),
+            // RTCP
+            _ => Ok(Vec::new()),
+        }
+    }
+}
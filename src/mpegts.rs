@@ -0,0 +1,342 @@
+use crate::codec::VideoCodec;
+
+const PACKET_SIZE: usize = 188;
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const AUDIO_PID: u16 = 0x0101;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+
+    for &b in data {
+        crc ^= (b as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 != 0 {
+                (crc << 1) ^ 0x04C11DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+fn encode_timestamp(marker: u8, ts: u64) -> [u8; 5] {
+    [
+        (marker << 4) | (((ts >> 30) as u8 & 0x07) << 1) | 1,
+        (ts >> 22) as u8,
+        (((ts >> 15) as u8) << 1) | 1,
+        (ts >> 7) as u8,
+        ((ts as u8) << 1) | 1,
+    ]
+}
+
+/**
+ * MPEG-TS muxer for a video and an optional AAC stream, timestamps in 90 kHz units
+ */
+pub struct TsMuxer {
+    codec: VideoCodec,
+    audio: bool,
+    counters: [u8; 4],
+}
+
+impl TsMuxer {
+    pub fn new(codec: VideoCodec, audio: bool) -> TsMuxer {
+        TsMuxer {
+            codec,
+            audio,
+            counters: [0; 4],
+        }
+    }
+
+    fn counter(&mut self, pid: u16) -> u8 {
+        let slot = match pid {
+            PAT_PID => 0,
+            PMT_PID => 1,
+            VIDEO_PID => 2,
+            _ => 3,
+        };
+
+        let cc = self.counters[slot];
+        self.counters[slot] = (cc + 1) & 0x0F;
+        cc
+    }
+
+    fn write_section(&mut self, out: &mut Vec<u8>, pid: u16, section: &[u8]) {
+        let mut packet = vec![
+            0x47,
+            0x40 | (pid >> 8) as u8,
+            pid as u8,
+            0x10 | self.counter(pid),
+            0x00, // pointer field
+        ];
+        packet.extend_from_slice(section);
+        packet.extend_from_slice(&crc32(section).to_be_bytes());
+        packet.resize(PACKET_SIZE, 0xFF);
+
+        out.extend_from_slice(&packet);
+    }
+
+    /**
+     * PAT and PMT, written at the start of every segment
+     */
+    pub fn write_tables(&mut self, out: &mut Vec<u8>) {
+        let pat = [
+            &[0x00, 0xB0, 0x0D][..],         // table id, section length
+            &[0x00, 0x01, 0xC1, 0x00, 0x00], // transport stream id, version
+            &[0x00, 0x01],                   // program number
+            &(0xE000 | PMT_PID).to_be_bytes(),
+        ]
+        .concat();
+        self.write_section(out, PAT_PID, &pat);
+
+        let stream_type = match self.codec {
+            VideoCodec::H264 => 0x1B,
+            VideoCodec::H265 => 0x24,
+        };
+        let mut streams = vec![(stream_type, VIDEO_PID)];
+        if self.audio {
+            streams.push((0x0F, AUDIO_PID));
+        }
+
+        let section_length = 13 + 5 * streams.len() as u16;
+        let mut pmt = [
+            &(0x02B000 | section_length as u32).to_be_bytes()[1..], // table id, section length
+            &[0x00, 0x01, 0xC1, 0x00, 0x00],                        // program number, version
+            &(0xE000 | VIDEO_PID).to_be_bytes(),                    // PCR PID
+            &[0xF0, 0x00],                                          // program info length
+        ]
+        .concat();
+        for (stream_type, pid) in streams {
+            pmt.push(stream_type);
+            pmt.extend_from_slice(&(0xE000 | pid).to_be_bytes());
+            pmt.extend_from_slice(&[0xF0, 0x00]); // ES info length
+        }
+        self.write_section(out, PMT_PID, &pmt);
+    }
+
+    /**
+     * Write a video access unit in Annex B format
+     */
+    pub fn write_video(&mut self, out: &mut Vec<u8>, pts: u64, keyframe: bool, data: &[u8]) {
+        let mut pes = vec![0x00, 0x00, 0x01, 0xE0, 0x00, 0x00, 0x80, 0x80, 0x05];
+        pes.extend_from_slice(&encode_timestamp(0x02, pts));
+        pes.extend_from_slice(b"\x00\x00\x00\x01");
+        pes.extend_from_slice(self.codec.access_unit_delimiter());
+        pes.extend_from_slice(data);
+
+        // Leave room for the decoder before the presentation time
+        let pcr = pts.saturating_sub(9000);
+        self.write_pes(out, VIDEO_PID, &pes, Some(pcr), keyframe);
+    }
+
+    /**
+     * Write an AAC frame with its ADTS header
+     */
+    pub fn write_audio(&mut self, out: &mut Vec<u8>, pts: u64, data: &[u8]) {
+        let length = (3 + 5 + data.len()) as u16;
+
+        let mut pes = vec![0x00, 0x00, 0x01, 0xC0];
+        pes.extend_from_slice(&length.to_be_bytes());
+        pes.extend_from_slice(&[0x80, 0x80, 0x05]);
+        pes.extend_from_slice(&encode_timestamp(0x02, pts));
+        pes.extend_from_slice(data);
+
+        self.write_pes(out, AUDIO_PID, &pes, None, false);
+    }
+
+    fn write_pes(
+        &mut self,
+        out: &mut Vec<u8>,
+        pid: u16,
+        pes: &[u8],
+        pcr: Option<u64>,
+        keyframe: bool,
+    ) {
+        let mut rest = pes;
+        let mut first = true;
+
+        while !rest.is_empty() {
+            let mut adaptation = Vec::new();
+
+            if first && (pcr.is_some() || keyframe) {
+                let mut flags = 0x00;
+                if keyframe {
+                    flags |= 0x40; // random access indicator
+                }
+                adaptation.push(flags);
+
+                if let Some(pcr) = pcr {
+                    adaptation[0] |= 0x10;
+                    adaptation.extend_from_slice(&[
+                        (pcr >> 25) as u8,
+                        (pcr >> 17) as u8,
+                        (pcr >> 9) as u8,
+                        (pcr >> 1) as u8,
+                        ((pcr as u8 & 1) << 7) | 0x7E,
+                        0x00,
+                    ]);
+                }
+            }
+
+            let header_len = 4 + if adaptation.is_empty() {
+                0
+            } else {
+                1 + adaptation.len()
+            };
+            let mut payload_len = rest.len().min(PACKET_SIZE - header_len);
+
+            // Stuff the last packet through the adaptation field
+            if header_len + payload_len < PACKET_SIZE {
+                let stuffing = PACKET_SIZE - header_len - payload_len;
+                if adaptation.is_empty() {
+                    if stuffing == 1 {
+                        // A lone adaptation field length byte
+                        adaptation = Vec::new();
+                    } else {
+                        adaptation.push(0x00);
+                        adaptation.resize(stuffing - 1, 0xFF);
+                    }
+                } else {
+                    adaptation.resize(adaptation.len() + stuffing, 0xFF);
+                }
+                payload_len = rest.len();
+            }
+
+            let has_adaptation = !adaptation.is_empty() || 4 + payload_len < PACKET_SIZE;
+            out.push(0x47);
+            out.push(if first { 0x40 } else { 0x00 } | (pid >> 8) as u8);
+            out.push(pid as u8);
+            out.push(if has_adaptation { 0x30 } else { 0x10 } | self.counter(pid));
+
+            if has_adaptation {
+                out.push(adaptation.len() as u8);
+                out.extend_from_slice(&adaptation);
+            }
+            out.extend_from_slice(&rest[..payload_len]);
+
+            rest = &rest[payload_len..];
+            first = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_timestamp(data: &[u8]) -> u64 {
+        ((data[0] as u64 >> 1) & 0x07) << 30
+            | (data[1] as u64) << 22
+            | (data[2] as u64 >> 1) << 15
+            | (data[3] as u64) << 7
+            | data[4] as u64 >> 1
+    }
+
+    /**
+     * Split a stream back into the PSI sections and PES packets of each PID, checking the
+     * packet framing and continuity counters on the way
+     */
+    fn demux(stream: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        assert_eq!(stream.len() % PACKET_SIZE, 0);
+
+        let mut units: Vec<(u16, Vec<u8>)> = Vec::new();
+        let mut counters = std::collections::HashMap::new();
+
+        for packet in stream.chunks(PACKET_SIZE) {
+            assert_eq!(packet[0], 0x47);
+            let start = packet[1] & 0x40 != 0;
+            let pid = u16::from_be_bytes([packet[1] & 0x1F, packet[2]]);
+            let cc = packet[3] & 0x0F;
+
+            if let Some(last) = counters.insert(pid, cc) {
+                assert_eq!(cc, (last + 1) & 0x0F, "discontinuity on PID {:#x}", pid);
+            }
+
+            let mut payload = &packet[4..];
+            if packet[3] & 0x20 != 0 {
+                payload = &payload[1 + payload[0] as usize..];
+            }
+
+            if start {
+                units.push((pid, payload.to_vec()));
+            } else {
+                let unit = units.iter_mut().rev().find(|(p, _)| *p == pid).unwrap();
+                unit.1.extend_from_slice(payload);
+            }
+        }
+
+        units
+    }
+
+    #[test]
+    fn tables() {
+        let mut muxer = TsMuxer::new(VideoCodec::H265, true);
+        let mut out = Vec::new();
+        muxer.write_tables(&mut out);
+
+        let units = demux(&out);
+        assert_eq!(units.len(), 2);
+
+        for (pid, unit) in &units {
+            let section = &unit[1..];
+            let length = (u16::from_be_bytes([section[1], section[2]]) & 0x0FFF) as usize;
+            let section = &section[..3 + length];
+
+            // The CRC over a section and its CRC is zero
+            assert_eq!(crc32(section), 0, "CRC of PID {:#x}", pid);
+        }
+
+        let pmt = &units[1].1[1..];
+        assert_eq!(units[1].0, PMT_PID);
+        assert_eq!(pmt[12], 0x24);
+        assert_eq!(u16::from_be_bytes([pmt[13], pmt[14]]) & 0x1FFF, VIDEO_PID);
+        assert_eq!(pmt[17], 0x0F);
+        assert_eq!(u16::from_be_bytes([pmt[18], pmt[19]]) & 0x1FFF, AUDIO_PID);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut muxer = TsMuxer::new(VideoCodec::H264, true);
+        let mut out = Vec::new();
+        let mut frames = Vec::new();
+
+        // Every stuffing length of the last packet for both streams, frames over several
+        // packets and timestamps wrapping at 33 bits
+        for i in 0..500 {
+            let data: Vec<u8> = (0..150 + i / 2).map(|b| b as u8).collect();
+            let pts = ((1 << 33) - 3000 + i as u64 * 3000) & ((1 << 33) - 1);
+
+            if i % 2 == 0 {
+                muxer.write_video(&mut out, pts, i % 10 == 0, &data);
+            } else {
+                muxer.write_audio(&mut out, pts, &data);
+            }
+            frames.push((pts, data));
+        }
+
+        let units = demux(&out);
+        assert_eq!(units.len(), frames.len());
+
+        for (i, ((pid, pes), (pts, data))) in units.iter().zip(&frames).enumerate() {
+            assert_eq!(pes[..3], [0, 0, 1]);
+            assert_eq!(decode_timestamp(&pes[9..14]), *pts);
+
+            let payload = &pes[14..];
+            if i % 2 == 0 {
+                assert_eq!(*pid, VIDEO_PID);
+                let aud = VideoCodec::H264.access_unit_delimiter();
+                assert_eq!(payload[..4], [0, 0, 0, 1]);
+                assert_eq!(&payload[4..4 + aud.len()], aud);
+                assert_eq!(&payload[4 + aud.len()..], data.as_slice());
+            } else {
+                assert_eq!(*pid, AUDIO_PID);
+                assert_eq!(u16::from_be_bytes([pes[4], pes[5]]) as usize, pes.len() - 6);
+                assert_eq!(payload, data.as_slice());
+            }
+        }
+    }
+}
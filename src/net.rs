@@ -0,0 +1,101 @@
+use clap::Args;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::net::UdpSocket;
+
+/**
+ * Local side of the handshake sockets, for firewall rules and multi-homed hosts
+ */
+#[derive(Args, Clone, Debug, Default)]
+pub struct BindArgs {
+    /// Source address of the UDP sockets. Default: any, IPv4 and IPv6
+    #[arg(long, value_name = "ADDRESS")]
+    pub udp_address: Option<IpAddr>,
+    /// Local UDP port of the socket talking to the cloud and the device. Default: any
+    #[arg(long, value_name = "PORT")]
+    pub udp_port: Option<u16>,
+    /// Local UDP port of the socket talking to the relay. Default: any
+    #[arg(long, value_name = "PORT")]
+    pub relay_udp_port: Option<u16>,
+    /// Network interface of the UDP sockets (Linux only)
+    #[arg(long, value_name = "NAME")]
+    pub interface: Option<String>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "fuchsia"))]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes())).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Cannot bind to interface {}: {}", interface, e),
+        )
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "fuchsia")))]
+fn bind_device(_: &Socket, _: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Binding to an interface is not supported on this platform",
+    ))
+}
+
+fn new_socket(domain: Domain, bind: &BindArgs, addr: SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    if domain == Domain::IPV6 && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    if let Some(interface) = &bind.interface {
+        bind_device(&socket, interface)?;
+    }
+
+    socket
+        .bind(&addr.into())
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot bind UDP to {}: {}", addr, e)))?;
+    Ok(socket)
+}
+
+/**
+ * Bind a UDP socket on `port`, at the configured address and interface.
+ * Without an address, the socket reaches both IPv4 and IPv6 addresses,
+ * or IPv4 only on hosts without IPv6
+ */
+pub fn bind_udp(bind: &BindArgs, port: Option<u16>) -> io::Result<UdpSocket> {
+    let port = port.unwrap_or(0);
+
+    let socket = match bind.udp_address {
+        Some(ip @ IpAddr::V4(_)) => new_socket(Domain::IPV4, bind, SocketAddr::new(ip, port))?,
+        Some(ip @ IpAddr::V6(_)) => new_socket(Domain::IPV6, bind, SocketAddr::new(ip, port))?,
+        None => {
+            let any_v6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+            let any_v4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+            new_socket(Domain::IPV6, bind, any_v6)
+                .or_else(|_| new_socket(Domain::IPV4, bind, any_v4))?
+        }
+    };
+
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/**
+ * Address to send to from `socket`: IPv4 addresses are mapped into IPv6 on a dual-stack socket
+ */
+pub fn destination(socket: &UdpSocket, addr: SocketAddr) -> SocketAddr {
+    match (socket.local_addr(), addr) {
+        (Ok(SocketAddr::V6(_)), SocketAddr::V4(v4)) => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        }
+        _ => addr,
+    }
+}
+
+/**
+ * Address received on a dual-stack socket, with IPv4-mapped addresses back to IPv4
+ */
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
@@ -0,0 +1,218 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep_until, timeout, Duration, Instant, Sleep},
+};
+
+use crate::tunnel::Tunnel;
+
+/// The device closed the realm, or stdin ended
+pub const EXIT_CLOSED: i32 = 0;
+/// The device refused the realm
+pub const EXIT_REFUSED: i32 = 1;
+/// The PTCP session died
+pub const EXIT_SESSION_LOST: i32 = 2;
+
+/// Silence of the device after stdin ended before the realm is closed
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/**
+ * Stdin, whose end only closes the realm once the device has been quiet for the drain timeout,
+ * so that its answer to what was sent still comes through
+ */
+struct HalfClosed<R> {
+    inner: R,
+    /// Last time the device sent data
+    last_data: Arc<Mutex<Instant>>,
+    drain: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HalfClosed<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.drain.is_none() {
+            let filled = buf.filled().len();
+            match Pin::new(&mut self.inner).poll_read(cx, buf) {
+                Poll::Ready(Ok(())) if buf.filled().len() == filled => {
+                    eprintln!("Pipe: stdin closed, waiting for the device");
+                    *self.last_data.lock().unwrap() = Instant::now();
+                    self.drain = Some(Box::pin(sleep_until(Instant::now() + DRAIN_TIMEOUT)));
+                }
+                poll => return poll,
+            }
+        }
+
+        let last_data = self.last_data.clone();
+        let drain = self.drain.as_mut().unwrap();
+
+        // Data keeps coming, wait for it to stop
+        while drain.as_mut().poll(cx).is_ready() {
+            let deadline = *last_data.lock().unwrap() + DRAIN_TIMEOUT;
+            if Instant::now() >= deadline {
+                return Poll::Ready(Ok(()));
+            }
+            drain.as_mut().reset(deadline);
+        }
+
+        Poll::Pending
+    }
+}
+
+/**
+ * Stdout, recording when the device last sent data
+ */
+struct Activity<W> {
+    inner: W,
+    last_data: Arc<Mutex<Instant>>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Activity<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        *self.last_data.lock().unwrap() = Instant::now();
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/**
+ * Connect stdin and stdout to a realm, returns the exit status
+ */
+pub async fn pipe(tunnel: Tunnel, remote_port: u16) -> i32 {
+    run(tunnel, tokio::io::stdin(), tokio::io::stdout(), remote_port).await
+}
+
+async fn run<R, W>(tunnel: Tunnel, input: R, output: W, remote_port: u16) -> i32
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let last_data = Arc::new(Mutex::new(Instant::now()));
+    let input = HalfClosed {
+        inner: input,
+        last_data: last_data.clone(),
+        drain: None,
+    };
+    let output = Activity {
+        inner: output,
+        last_data,
+    };
+
+    let (reader, mut writer) = match tunnel.forward(input, output, remote_port).await {
+        Ok(tasks) => tasks,
+        Err(e) => {
+            eprintln!("Pipe: {}", e);
+
+            // A bind without an answer means the session is gone too
+            return match e.kind() {
+                io::ErrorKind::NotConnected | io::ErrorKind::TimedOut => EXIT_SESSION_LOST,
+                _ => EXIT_REFUSED,
+            };
+        }
+    };
+
+    tokio::select! {
+        // DISC from the device, stdout is flushed
+        _ = &mut writer => EXIT_CLOSED,
+        _ = reader => {
+            // Flush what the device sent before the realm was closed
+            let _ = timeout(Duration::from_secs(5), writer).await;
+            EXIT_CLOSED
+        }
+        _ = tunnel.closed() => {
+            eprintln!("Pipe: PTCP session lost");
+            EXIT_SESSION_LOST
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        impair::{simulated_device, Direction, Impairment, Link, ECHO_PORT},
+        ptcp::PTCPSession,
+    };
+    use tokio::io::AsyncReadExt;
+
+    fn start() -> (Link, Tunnel) {
+        let (link, client, device) = Link::new(1);
+        tokio::spawn(simulated_device(device));
+
+        (link, Tunnel::start(client, PTCPSession::new()))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn answer_after_stdin_ends() {
+        let (_link, tunnel) = start();
+        let (output, mut received) = tokio::io::duplex(4096);
+
+        // The input ends before the device answers
+        let started = Instant::now();
+        let status = run(
+            tunnel.clone(),
+            &b"GET / HTTP/1.0\r\n\r\n"[..],
+            output,
+            ECHO_PORT,
+        )
+        .await;
+        assert_eq!(status, EXIT_CLOSED);
+        assert!(started.elapsed() >= DRAIN_TIMEOUT);
+
+        let mut answer = Vec::new();
+        received.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, b"GET / HTTP/1.0\r\n\r\n");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refused() {
+        let (_link, tunnel) = start();
+
+        let status = run(tunnel, tokio::io::empty(), tokio::io::sink(), ECHO_PORT + 1).await;
+        assert_eq!(status, EXIT_REFUSED);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_lost() {
+        let (link, tunnel) = start();
+        link.impair(
+            Direction::ToDevice,
+            Impairment {
+                loss: 1.0,
+                ..Default::default()
+            },
+        );
+
+        let status = run(
+            tunnel.clone(),
+            tokio::io::empty(),
+            tokio::io::sink(),
+            ECHO_PORT,
+        )
+        .await;
+        assert_eq!(status, EXIT_SESSION_LOST);
+
+        tunnel.close();
+        let status = run(tunnel, tokio::io::empty(), tokio::io::sink(), ECHO_PORT).await;
+        assert_eq!(status, EXIT_SESSION_LOST);
+    }
+}
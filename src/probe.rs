@@ -0,0 +1,172 @@
+use clap::Args;
+use serde_json::{json, Value};
+use std::{net::IpAddr, time::Duration};
+
+use crate::{
+    cloud::{resolve, MAIN_SERVER},
+    dh::{p2p_handshake_diagnosed, Diagnostics},
+    net::{bind_udp, canonical, destination, BindArgs},
+};
+
+/// Time given to the whole handshake
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Args)]
+pub struct ProbeArgs {
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/**
+ * Address of the interface used to reach the cloud
+ */
+async fn local_ip(bind: &BindArgs) -> Option<IpAddr> {
+    let socket = bind_udp(bind, None).ok()?;
+    let server = resolve(MAIN_SERVER).await.ok()?;
+    socket.connect(destination(&socket, server)).await.ok()?;
+    Some(canonical(socket.local_addr().ok()?).ip())
+}
+
+/**
+ * How our NAT maps the handshake socket, from the addresses the device saw
+ */
+fn nat_mapping(diagnostics: &Diagnostics, local_ip: Option<IpAddr>) -> &'static str {
+    match (
+        diagnostics.punch.mapped_addresses.as_slice(),
+        diagnostics.local_port,
+    ) {
+        ([], _) => "unknown, the device did not report our address",
+        ([_, _, ..], _) => "endpoint-dependent, each candidate saw a different address",
+        ([mapped], Some(port)) if mapped.port() == port => match local_ip == Some(mapped.ip()) {
+            true => "none, the address is public",
+            false => "endpoint-independent, the port is preserved",
+        },
+        _ => "endpoint-independent, the port is translated",
+    }
+}
+
+fn succeeded(diagnostics: &Diagnostics, step: &str) -> bool {
+    diagnostics
+        .steps
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|s| s.name == step && s.error.is_none())
+}
+
+fn recommendation(
+    diagnostics: &Diagnostics,
+    error: Option<&str>,
+    relay_mode: bool,
+) -> &'static str {
+    match error {
+        None if relay_mode => "relay, direct mode was not tested",
+        None => "direct",
+        Some(_) if succeeded(diagnostics, "relay sync") => "relay (--relay)",
+        Some(_) => "none, the device could not be reached through the cloud",
+    }
+}
+
+fn print_text(report: &Value, diagnostics: &Diagnostics) {
+    let text = |key: &str| report[key].as_str().unwrap_or("-").to_string();
+
+    println!("Serial:          {}", text("serial"));
+    println!("Result:          {}", text("result"));
+    let pending = diagnostics.pending.lock().unwrap();
+    if !pending.is_empty() {
+        println!("Stopped during:  {}", pending.join(", "));
+    }
+    println!();
+
+    println!("Steps:            start  duration");
+    for step in diagnostics.steps.lock().unwrap().iter() {
+        println!(
+            "  {:<15} +{:>5} ms {:>6} ms{}",
+            step.name,
+            step.start_ms,
+            step.millis,
+            step.error
+                .as_ref()
+                .map(|e| format!("  {}", e))
+                .unwrap_or_default()
+        );
+    }
+    println!();
+
+    let or_dash = |value: Option<String>| value.unwrap_or("-".to_string());
+    println!(
+        "Local port:      {}",
+        or_dash(diagnostics.local_port.map(|p| p.to_string()))
+    );
+    println!(
+        "Mapped address:  {}",
+        match diagnostics.punch.mapped_addresses.is_empty() {
+            true => "-".to_string(),
+            false => diagnostics
+                .punch
+                .mapped_addresses
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    );
+    println!("NAT mapping:     {}", text("nat_mapping"));
+    println!(
+        "Device public:   {}",
+        or_dash(diagnostics.device_public.clone())
+    );
+    println!(
+        "Device local:    {}",
+        or_dash(diagnostics.device_local.clone())
+    );
+    println!(
+        "Device request:  {}",
+        match diagnostics.punch.device_request {
+            true => "received",
+            false => "not received",
+        }
+    );
+    println!(
+        "Punch answer:    {}",
+        match (
+            diagnostics.punch.answered_candidate,
+            diagnostics.punch.answered_from,
+        ) {
+            (Some(candidate), Some(from)) => format!("from {} (candidate {})", from, candidate),
+            _ => "not received".to_string(),
+        }
+    );
+    println!();
+
+    println!("Recommendation:  {}", text("recommendation"));
+}
+
+/**
+ * Run the handshake and report how far it went, for troubleshooting
+ */
+pub async fn probe(serial: String, relay_mode: bool, bind: BindArgs, args: ProbeArgs) {
+    let mut diagnostics = Diagnostics::default();
+
+    let result = p2p_handshake_diagnosed(serial.clone(), relay_mode, &bind, &mut diagnostics);
+    let error = match tokio::time::timeout(PROBE_TIMEOUT, result).await {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some("Handshake timed out".to_string()),
+    };
+
+    let local_ip = local_ip(&bind).await;
+    let report = json!({
+        "serial": serial,
+        "result": error.as_deref().unwrap_or("connected"),
+        "nat_mapping": nat_mapping(&diagnostics, local_ip),
+        "recommendation": recommendation(&diagnostics, error.as_deref(), relay_mode),
+        "handshake": diagnostics,
+    });
+
+    match args.json {
+        true => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+        false => print_text(&report, &diagnostics),
+    }
+}
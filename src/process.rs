@@ -3,146 +3,164 @@ use std::{
     sync::{Arc, Mutex},
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::UdpSocket,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     sync::{mpsc, oneshot},
+    time::{self, Instant},
 };
 
-use crate::ptcp::{PTCPBody, PTCPEvent, PTCPPayload, PTCPSession, PTCP};
+use crate::ptcp::{PTCPBody, PTCPEvent, PTCPPayload, PTCPSession, Retransmissions, PTCP};
+
+/// Largest client read sent in one payload, so that the packet fits the minimum IPv6 MTU of
+/// 1280 bytes with the IPv6, UDP, PTCP and payload headers
+const MAX_PAYLOAD: usize = 1280 - 40 - 8 - 24 - 12;
 
 /**
  * Read data from the channel and write it back to the client
  */
-pub async fn process_writer(
-    mut writer: tokio::net::tcp::OwnedWriteHalf,
-    mut rx: mpsc::Receiver<Vec<u8>>,
-) {
-    loop {
-        let data = rx.recv().await.unwrap();
-        if writer.write_all(&data).await.is_err() {
-            println!("Writer: Socket closed by peer.");
-            break;
+pub async fn process_writer<W: AsyncWrite + Unpin>(mut writer: W, mut rx: mpsc::Receiver<Vec<u8>>) {
+    while let Some(data) = rx.recv().await {
+        if writer.write_all(&data).await.is_err() || writer.flush().await.is_err() {
+            eprintln!("Writer: Socket closed by peer.");
+            return;
         }
     }
+
+    // The realm was closed by the device
+    let _ = writer.shutdown().await;
 }
 
 /**
  * Read data from the client and send it to the channel
  */
-pub async fn process_reader(
-    mut reader: tokio::net::tcp::OwnedReadHalf,
+pub async fn process_reader<R: AsyncRead + Unpin>(
+    mut reader: R,
     realm_id: u32,
     dh_tx: mpsc::Sender<PTCPEvent>,
 ) {
-    let mut buf = [0u8; 4096];
+    let mut buf = [0u8; MAX_PAYLOAD];
 
     loop {
         let n = match reader.read(&mut buf).await {
             Ok(n) => {
                 if n == 0 {
-                    println!("Reader: Socket closed by peer.");
-                    dh_tx.send(PTCPEvent::Disconnect(realm_id)).await.unwrap();
+                    eprintln!("Reader: Socket closed by peer.");
+                    let _ = dh_tx.send(PTCPEvent::Disconnect(realm_id)).await;
                     break;
                 }
 
                 n
             }
             Err(e) => {
-                println!("Reader: {}", e);
-                dh_tx.send(PTCPEvent::Disconnect(realm_id)).await.unwrap();
+                eprintln!("Reader: {}", e);
+                let _ = dh_tx.send(PTCPEvent::Disconnect(realm_id)).await;
                 break;
             }
         };
 
-        dh_tx
+        if dh_tx
             .send(PTCPEvent::Data(realm_id, buf[0..n].to_vec()))
             .await
-            .unwrap();
+            .is_err()
+        {
+            eprintln!("Reader: PTCP session closed.");
+            break;
+        }
     }
 }
 
 /**
 * Read data from client and send it to devices
 */
-pub async fn dh_writer(
+pub async fn dh_writer<S: PTCP>(
     session: Arc<Mutex<PTCPSession>>,
-    socket: Arc<UdpSocket>,
+    socket: Arc<S>,
     mut dh_rx: mpsc::Receiver<PTCPEvent>,
-    remote_port: u32,
 ) {
-    loop {
-        let ev = dh_rx.recv().await.unwrap();
+    let mut unacked = Retransmissions::default();
 
-        match ev {
-            PTCPEvent::Heartbeat => {
-                let p = session.lock().unwrap().send(PTCPBody::Heartbeat);
-                socket.ptcp_request(p).await;
-            }
-            PTCPEvent::Connect(realm) => {
-                let p = session
-                    .lock()
-                    .unwrap()
-                    .send(PTCPBody::Bind(realm, remote_port));
-                socket.ptcp_request(p).await;
-            }
-            PTCPEvent::Disconnect(realm) => {
-                let p = session
-                    .lock()
-                    .unwrap()
-                    .send(PTCPBody::Status(realm, "DISC".to_string()));
-                socket.ptcp_request(p).await;
+    loop {
+        let due = unacked.next_due();
+        let ev = tokio::select! {
+            ev = dh_rx.recv() => ev.unwrap(),
+            _ = time::sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                unacked.acknowledge(session.lock().unwrap().acked());
+                for p in unacked.due() {
+                    socket.ptcp_request(p).await;
+                }
+                continue;
             }
-            PTCPEvent::Data(realm, data) => {
-                let p = session
-                    .lock()
-                    .unwrap()
-                    .send(PTCPBody::Payload(PTCPPayload { realm, data }));
-                socket.ptcp_request(p).await;
+        };
+
+        let body = match ev {
+            PTCPEvent::Heartbeat => PTCPBody::Heartbeat,
+            PTCPEvent::Connect(realm, remote_port) => PTCPBody::Bind(realm, remote_port),
+            PTCPEvent::Disconnect(realm) => PTCPBody::Status(realm, "DISC".to_string()),
+            PTCPEvent::Data(realm, data) => PTCPBody::Payload(PTCPPayload { realm, data }),
+            PTCPEvent::Flush(done) => {
+                let _ = done.send(());
+                continue;
             }
-        }
+        };
+
+        let p = session.lock().unwrap().send(body);
+        unacked.push(&p);
+        socket.ptcp_request(p).await;
     }
 }
 
 /**
  * Read data from devices and send it to clients
  */
-pub async fn dh_reader(
+pub async fn dh_reader<S: PTCP>(
     session: Arc<Mutex<PTCPSession>>,
-    socket: Arc<UdpSocket>,
+    socket: Arc<S>,
     channels: Arc<Mutex<HashMap<u32, mpsc::Sender<Vec<u8>>>>>,
     conn_channels: Arc<Mutex<HashMap<u32, oneshot::Sender<bool>>>>,
+    last_seen: Arc<Mutex<Instant>>,
 ) {
     loop {
         let packet = socket.ptcp_read().await;
-        let packet = session.lock().unwrap().recv(packet);
+        *last_seen.lock().unwrap() = Instant::now();
+        let empty = matches!(packet.body, PTCPBody::Empty);
+        let packets = session.lock().unwrap().receive(packet);
 
-        if let PTCPBody::Empty = packet.body {
+        if empty {
             continue;
         }
 
+        // Duplicates are acknowledged too, the acknowledgement may have been lost
         let p = session.lock().unwrap().send(PTCPBody::Empty);
         socket.ptcp_request(p).await;
 
-        match packet.body {
-            PTCPBody::Status(realm, status) => {
-                if status == "CONN" {
-                    conn_channels
-                        .lock()
-                        .unwrap()
-                        .remove(&realm)
-                        .unwrap()
-                        .send(true)
-                        .unwrap();
+        for packet in packets {
+            match packet.body {
+                PTCPBody::Status(realm, status) => {
+                    // A pending bind is answered with either CONN or DISC
+                    if let Some(conn_tx) = conn_channels.lock().unwrap().remove(&realm) {
+                        let _ = conn_tx.send(status == "CONN");
+                        continue;
+                    }
+
+                    if status == "DISC" {
+                        // Dropping the sender lets the writer shut the client down
+                        channels.lock().unwrap().remove(&realm);
+                    }
                 }
-            }
-            PTCPBody::Payload(p) => {
-                let tx = channels.lock().unwrap().get(&p.realm).unwrap().clone();
+                PTCPBody::Payload(p) => {
+                    let tx = channels.lock().unwrap().get(&p.realm).cloned();
+
+                    let Some(tx) = tx else {
+                        eprintln!("Realm {:08x} unknown", p.realm);
+                        continue;
+                    };
 
-                if tx.send(p.data).await.is_err() {
-                    println!("Realm {:08x} unavailable", p.realm);
+                    if tx.send(p.data).await.is_err() {
+                        eprintln!("Realm {:08x} unavailable", p.realm);
+                        channels.lock().unwrap().remove(&p.realm);
+                    }
                 }
+                _ => {}
             }
-            _ => {}
         }
     }
 }
@@ -1,21 +1,47 @@
 use async_trait::async_trait;
-use std::cmp;
-use tokio::net::UdpSocket;
+use std::{cmp, collections::VecDeque, fmt, io};
+use tokio::{
+    net::UdpSocket,
+    sync::oneshot,
+    time::{Duration, Instant},
+};
+
+/// Packets kept ahead of a gap in the sequence before giving up on the missing data
+const MAX_PENDING: usize = 64;
+/// First retransmission timeout of a packet, doubled at each retransmission
+const RTO_MIN: Duration = Duration::from_millis(500);
+/// Longest retransmission timeout, a dead session is caught by the session timeout
+const RTO_MAX: Duration = Duration::from_secs(2);
 
 pub enum PTCPEvent {
     Heartbeat,
-    Connect(u32),
+    Connect(u32, u32),
     Disconnect(u32),
     Data(u32, Vec<u8>),
+    /// Answered once every event queued before was sent
+    Flush(oneshot::Sender<()>),
 }
 
+#[derive(Clone, PartialEq, Eq)]
 pub struct PTCPPayload {
     pub realm: u32,
     pub data: Vec<u8>,
 }
 
+#[derive(Clone, PartialEq, Eq)]
 pub enum PTCPBody {
     Sync,
+    /// 0x17, asks the relay for a sign authenticating us to the device
+    SignRequest,
+    /// 0x18, the sign given by the relay
+    Sign(Vec<u8>),
+    /// 0x19, authentication to the device with the sign
+    Auth(Vec<u8>),
+    /// 0x1a, answer of the device to the authentication
+    AuthResponse(Vec<u8>),
+    /// 0x1b, confirmation of the answer, after which the session is ready
+    AuthConfirm,
+    /// Other common messages, raw
     Command(Vec<u8>),
     Payload(PTCPPayload),
     Bind(u32, u32),
@@ -24,6 +50,55 @@ pub enum PTCPBody {
     Empty,
 }
 
+/**
+ * Why a datagram is not a valid PTCP packet
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub enum PTCPError {
+    /// The datagram ends inside the named part
+    Truncated(&'static str),
+    /// The datagram does not start with `PTCP`, e.g. a late STUN message
+    Magic([u8; 4]),
+    /// The padding of a payload is not 0
+    Padding(u32),
+    /// The length of a payload does not match its data
+    Length { declared: usize, actual: usize },
+}
+
+impl fmt::Display for PTCPError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PTCPError::Truncated(part) => write!(f, "PTCP: truncated {}", part),
+            PTCPError::Magic(magic) => write!(f, "PTCP: invalid magic {:02x?}", magic),
+            PTCPError::Padding(padding) => write!(f, "PTCP: invalid padding 0x{:08x}", padding),
+            PTCPError::Length { declared, actual } => write!(
+                f,
+                "PTCP: payload of {} bytes declared with {} bytes",
+                actual, declared
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PTCPError {}
+
+/**
+ * Whether the counter `a` is past `b`, the counters wrapping around
+ */
+fn is_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/**
+ * The big-endian u32 at `offset`, or an error naming the truncated `part`
+ */
+fn be_u32(data: &[u8], offset: usize, part: &'static str) -> Result<u32, PTCPError> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(PTCPError::Truncated(part))
+}
+
+#[derive(Clone, PartialEq, Eq)]
 pub struct PTCPPacket {
     sent: u32,
     recv: u32,
@@ -34,24 +109,28 @@ pub struct PTCPPacket {
 }
 
 impl PTCPPayload {
-    fn parse(data: &[u8]) -> PTCPPayload {
-        assert!(data.len() >= 12, "Invalid payload");
-        assert_eq!(data[0], 0x10, "Invalid header");
-
+    pub fn parse(data: &[u8]) -> Result<PTCPPayload, PTCPError> {
         // first 4 bytes it header
-        let header = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
-        let length = header & 0xFFFF;
-        let realm = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
-        let padding = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+        let header = be_u32(data, 0, "payload header")?;
+        let length = (header & 0xFFFF) as usize;
+        let realm = be_u32(data, 4, "payload header")?;
+        let padding = be_u32(data, 8, "payload header")?;
         let data = data[12..].to_vec();
 
-        assert_eq!(padding, 0, "Invalid padding");
-        assert_eq!(length, data.len() as u32, "Invalid length");
+        if padding != 0 {
+            return Err(PTCPError::Padding(padding));
+        }
+        if length != data.len() {
+            return Err(PTCPError::Length {
+                declared: length,
+                actual: data.len(),
+            });
+        }
 
-        PTCPPayload { realm, data }
+        Ok(PTCPPayload { realm, data })
     }
 
-    fn serialize(&self) -> Vec<u8> {
+    pub fn serialize(&self) -> Vec<u8> {
         let length = self.data.len() as u32;
         let header = 0x10000000 | length;
         let header = header.to_be_bytes();
@@ -68,6 +147,26 @@ impl PTCPPayload {
     }
 }
 
+/// Length of the header of common messages, before their data
+const CONTROL_HEADER: usize = 12;
+
+/**
+ * A common message: its type, then a length, realm and padding left to 0
+ */
+fn control(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut message = vec![0u8; CONTROL_HEADER];
+    message[0] = kind;
+    message.extend_from_slice(data);
+    message
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 impl std::fmt::Debug for PTCPPayload {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -89,14 +188,12 @@ impl std::fmt::Debug for PTCPBody {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             PTCPBody::Sync => write!(f, "Sync"),
-            PTCPBody::Command(data) => write!(
-                f,
-                "Command([{}])",
-                data.iter()
-                    .map(|b| format!("{:02x}", b))
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            ),
+            PTCPBody::SignRequest => write!(f, "SignRequest"),
+            PTCPBody::Sign(sign) => write!(f, "Sign {{ sign: [{}] }}", hex(sign)),
+            PTCPBody::Auth(sign) => write!(f, "Auth {{ sign: [{}] }}", hex(sign)),
+            PTCPBody::AuthResponse(data) => write!(f, "AuthResponse {{ data: [{}] }}", hex(data)),
+            PTCPBody::AuthConfirm => write!(f, "AuthConfirm"),
+            PTCPBody::Command(data) => write!(f, "Command([{}])", hex(data)),
             PTCPBody::Payload(payload) => write!(f, "{:?}", payload),
             PTCPBody::Bind(realm, port) => {
                 write!(f, "Bind {{ realm: 0x{:08x}, port: {} }}", realm, port)
@@ -121,33 +218,51 @@ impl std::fmt::Debug for PTCPPacket {
 }
 
 impl PTCPBody {
-    fn parse(data: &[u8]) -> PTCPBody {
-        if data.len() == 0 {
-            return PTCPBody::Empty;
+    pub fn parse(data: &[u8]) -> Result<PTCPBody, PTCPError> {
+        if data.is_empty() {
+            return Ok(PTCPBody::Empty);
         }
 
-        assert!(data.len() >= 4, "Invalid body");
+        if data.len() < 4 {
+            return Err(PTCPError::Truncated("body"));
+        }
 
-        match data[0] {
+        Ok(match data[0] {
             0x00 => PTCPBody::Sync,
-            0x10 => PTCPBody::Payload(PTCPPayload::parse(data)),
-            0x11 => PTCPBody::Bind(
-                u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
-                u32::from_be_bytes([data[12], data[13], data[14], data[15]]),
-            ),
+            0x10 => PTCPBody::Payload(PTCPPayload::parse(data)?),
+            0x11 => PTCPBody::Bind(be_u32(data, 4, "bind")?, be_u32(data, 12, "bind")?),
             0x12 => {
-                let realm = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
-                let status = String::from_utf8_lossy(&data[12..]).to_string();
-                PTCPBody::Status(realm, status)
+                let realm = be_u32(data, 4, "status")?;
+                let status = data.get(12..).ok_or(PTCPError::Truncated("status"))?;
+                PTCPBody::Status(realm, String::from_utf8_lossy(status).to_string())
             }
             0x13 => PTCPBody::Heartbeat,
+            0x17..=0x1b => {
+                let fields = data
+                    .get(CONTROL_HEADER..)
+                    .ok_or(PTCPError::Truncated("control message"))?
+                    .to_vec();
+
+                match data[0] {
+                    0x17 => PTCPBody::SignRequest,
+                    0x18 => PTCPBody::Sign(fields),
+                    0x19 => PTCPBody::Auth(fields),
+                    0x1a => PTCPBody::AuthResponse(fields),
+                    _ => PTCPBody::AuthConfirm,
+                }
+            }
             _ => PTCPBody::Command(data.to_vec()),
-        }
+        })
     }
 
-    fn serialize(&self) -> Vec<u8> {
+    pub fn serialize(&self) -> Vec<u8> {
         match self {
             PTCPBody::Sync => b"\x00\x03\x01\x00".to_vec(),
+            PTCPBody::SignRequest => control(0x17, &[]),
+            PTCPBody::Sign(sign) => control(0x18, sign),
+            PTCPBody::Auth(sign) => control(0x19, sign),
+            PTCPBody::AuthResponse(data) => control(0x1a, data),
+            PTCPBody::AuthConfirm => control(0x1b, &[]),
             PTCPBody::Command(data) => data.to_vec(),
             PTCPBody::Payload(payload) => payload.serialize(),
             PTCPBody::Bind(realm, port) => [
@@ -170,9 +285,28 @@ impl PTCPBody {
         }
     }
 
+    /**
+     * Type and fields of a control message of any type, `None` for the other messages
+     */
+    pub fn control_fields(&self) -> Option<(u8, Vec<u8>)> {
+        match self {
+            PTCPBody::SignRequest => Some((0x17, Vec::new())),
+            PTCPBody::Sign(fields) => Some((0x18, fields.clone())),
+            PTCPBody::Auth(fields) => Some((0x19, fields.clone())),
+            PTCPBody::AuthResponse(fields) => Some((0x1a, fields.clone())),
+            PTCPBody::AuthConfirm => Some((0x1b, Vec::new())),
+            PTCPBody::Command(data) => Some((data[0], data.get(CONTROL_HEADER..)?.to_vec())),
+            _ => None,
+        }
+    }
+
     fn len(&self) -> usize {
         match self {
             PTCPBody::Sync => 4,
+            PTCPBody::SignRequest | PTCPBody::AuthConfirm => CONTROL_HEADER,
+            PTCPBody::Sign(data) | PTCPBody::Auth(data) | PTCPBody::AuthResponse(data) => {
+                CONTROL_HEADER + data.len()
+            }
             PTCPBody::Command(data) => data.len(),
             PTCPBody::Payload(payload) => payload.data.len() + 12,
             PTCPBody::Bind(_, _) => 20,
@@ -184,31 +318,30 @@ impl PTCPBody {
 }
 
 impl PTCPPacket {
-    fn parse(data: &[u8]) -> PTCPPacket {
-        assert!(data.len() >= 24, "Invalid packet");
-
-        let magic = &data[0..4];
-
-        assert_eq!(magic, b"PTCP", "Invalid magic");
+    pub fn parse(data: &[u8]) -> Result<PTCPPacket, PTCPError> {
+        let magic = be_u32(data, 0, "packet header")?.to_be_bytes();
+        if &magic != b"PTCP" {
+            return Err(PTCPError::Magic(magic));
+        }
 
-        let sent = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
-        let recv = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
-        let pid = u32::from_be_bytes([data[12], data[13], data[14], data[15]]);
-        let lmid = u32::from_be_bytes([data[16], data[17], data[18], data[19]]);
-        let rmid = u32::from_be_bytes([data[20], data[21], data[22], data[23]]);
-        let body = PTCPBody::parse(&data[24..]);
+        let sent = be_u32(data, 4, "packet header")?;
+        let recv = be_u32(data, 8, "packet header")?;
+        let pid = be_u32(data, 12, "packet header")?;
+        let lmid = be_u32(data, 16, "packet header")?;
+        let rmid = be_u32(data, 20, "packet header")?;
+        let body = PTCPBody::parse(&data[24..])?;
 
-        PTCPPacket {
+        Ok(PTCPPacket {
             sent,
             recv,
             pid,
             lmid,
             rmid,
             body,
-        }
+        })
     }
 
-    fn serialize(&self) -> Vec<u8> {
+    pub fn serialize(&self) -> Vec<u8> {
         [
             b"PTCP".to_vec(),
             self.sent.to_be_bytes().to_vec(),
@@ -224,18 +357,23 @@ impl PTCPPacket {
     fn try_print_data(&self) {
         if let PTCPBody::Payload(p) = &self.body {
             if p.data.len() > 4 && p.data.iter().all(|b| *b < 0x80) {
-                println!("{}", String::from_utf8_lossy(&p.data));
+                eprintln!("{}", String::from_utf8_lossy(&p.data));
             }
         }
     }
 }
 
+#[derive(Default)]
 pub struct PTCPSession {
     sent: u32,
     recv: u32,
     count: u32,
     id: u32,
     rmid: u32,
+    /// Latest `recv` counter of the peer, the data it acknowledged
+    acked: u32,
+    /// Packets received ahead of a gap, until it is filled
+    pending: Vec<PTCPPacket>,
 }
 
 impl PTCPSession {
@@ -246,6 +384,8 @@ impl PTCPSession {
             count: 0,
             id: 0,
             rmid: 0,
+            acked: 0,
+            pending: Vec::new(),
         }
     }
 
@@ -262,7 +402,7 @@ impl PTCPSession {
         /*
          * Update counters
          */
-        self.sent += body.len() as u32;
+        self.sent = self.sent.wrapping_add(body.len() as u32);
 
         self.id += 1;
         self.count += match body {
@@ -282,43 +422,498 @@ impl PTCPSession {
     }
 
     pub fn recv(&mut self, packet: PTCPPacket) -> PTCPPacket {
-        self.recv += packet.body.len() as u32;
+        self.recv = self.recv.wrapping_add(packet.body.len() as u32);
         self.rmid = packet.lmid;
 
         packet
     }
+
+    /**
+     * Take a packet of the data path, returning the packets now in sequence by the `sent`
+     * counter of the peer: none for a duplicate or a packet ahead of a gap, otherwise the
+     * packet and the ones it was missing for
+     */
+    pub fn receive(&mut self, packet: PTCPPacket) -> Vec<PTCPPacket> {
+        if is_after(packet.recv, self.acked) {
+            self.acked = packet.recv;
+        }
+        if is_after(packet.lmid, self.rmid) {
+            self.rmid = packet.lmid;
+        }
+
+        if let PTCPBody::Empty = packet.body {
+            return Vec::new();
+        }
+        if is_after(self.recv, packet.sent) || self.pending.iter().any(|p| p.sent == packet.sent) {
+            return Vec::new();
+        }
+        self.pending.push(packet);
+
+        // The peer gave up on the missing data, or counts it differently
+        if self.pending.len() > MAX_PENDING {
+            let next = self
+                .pending
+                .iter()
+                .map(|p| p.sent)
+                .min_by_key(|sent| sent.wrapping_sub(self.recv))
+                .unwrap();
+            eprintln!(
+                "PTCP: skipping {} missing bytes",
+                next.wrapping_sub(self.recv)
+            );
+            self.recv = next;
+        }
+
+        let mut ready = Vec::new();
+        while let Some(i) = self.pending.iter().position(|p| p.sent == self.recv) {
+            let packet = self.pending.swap_remove(i);
+            self.recv = self.recv.wrapping_add(packet.body.len() as u32);
+            ready.push(packet);
+        }
+
+        ready
+    }
+
+    /**
+     * The `recv` counter of the peer, up to which it acknowledged our packets
+     */
+    pub fn acked(&self) -> u32 {
+        self.acked
+    }
+}
+
+/**
+ * A packet waiting for its acknowledgement
+ */
+struct Unacked {
+    packet: PTCPPacket,
+    due: Instant,
+    rto: Duration,
+}
+
+/**
+ * Packets of the data path not acknowledged by the peer yet, sent again until they are
+ */
+#[derive(Default)]
+pub struct Retransmissions(VecDeque<Unacked>);
+
+impl Retransmissions {
+    /**
+     * Keep a copy of a packet being sent, unless it carries nothing to acknowledge
+     */
+    pub fn push(&mut self, packet: &PTCPPacket) {
+        if let PTCPBody::Empty = packet.body {
+            return;
+        }
+
+        self.0.push_back(Unacked {
+            packet: packet.clone(),
+            due: Instant::now() + RTO_MIN,
+            rto: RTO_MIN,
+        });
+    }
+
+    /**
+     * Forget the packets covered by the `recv` counter of the peer
+     */
+    pub fn acknowledge(&mut self, acked: u32) {
+        self.0.retain(|u| {
+            let end = u.packet.sent.wrapping_add(u.packet.body.len() as u32);
+            is_after(end, acked)
+        });
+    }
+
+    /**
+     * When the next retransmission is due
+     */
+    pub fn next_due(&self) -> Option<Instant> {
+        self.0.iter().map(|u| u.due).min()
+    }
+
+    /**
+     * The packets to send again now, backing off their timeouts
+     */
+    pub fn due(&mut self) -> Vec<PTCPPacket> {
+        let now = Instant::now();
+
+        self.0
+            .iter_mut()
+            .filter(|u| u.due <= now)
+            .map(|u| {
+                u.rto = cmp::min(u.rto * 2, RTO_MAX);
+                u.due = now + u.rto;
+                u.packet.clone()
+            })
+            .collect()
+    }
 }
 
 #[async_trait]
+#[allow(clippy::upper_case_acronyms)]
 pub trait PTCP {
     async fn ptcp_request(&self, packet: PTCPPacket);
     async fn ptcp_read(&self) -> PTCPPacket;
 }
 
+/**
+ * A connected datagram socket, carrying the PTCP packets
+ */
+#[async_trait]
+pub trait Datagram: Send + Sync {
+    async fn send_datagram(&self, data: &[u8]) -> io::Result<usize>;
+    async fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<usize>;
+    /// Remote end, for the logs
+    fn peer(&self) -> String;
+}
+
+#[async_trait]
+impl Datagram for UdpSocket {
+    async fn send_datagram(&self, data: &[u8]) -> io::Result<usize> {
+        self.send(data).await
+    }
+
+    async fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv(buf).await
+    }
+
+    fn peer(&self) -> String {
+        self.peer_addr().unwrap().to_string()
+    }
+}
+
 #[async_trait]
-impl PTCP for UdpSocket {
+impl<T: Datagram> PTCP for T {
     async fn ptcp_request(&self, packet: PTCPPacket) {
-        println!(">>> {}", self.peer_addr().unwrap());
-        println!("{:?}", packet);
+        eprintln!(">>> {}", self.peer());
+        eprintln!("{:?}", packet);
         packet.try_print_data();
-        println!("---");
+        eprintln!("---");
 
         let packet = packet.serialize();
-        self.send(&packet).await.unwrap();
+        self.send_datagram(&packet).await.unwrap();
     }
 
     async fn ptcp_read(&self) -> PTCPPacket {
-        println!("### {}", self.peer_addr().unwrap());
+        eprintln!("### {}", self.peer());
 
         let mut buf = [0u8; 4096];
-        let n = self.recv(&mut buf).await.unwrap();
-
-        println!("<<< {}", self.peer_addr().unwrap());
-        let packet = PTCPPacket::parse(&buf[0..n]);
-        println!("{:?}", packet);
+        let packet = loop {
+            let n = self.recv_datagram(&mut buf).await.unwrap();
+
+            eprintln!("<<< {}", self.peer());
+            match PTCPPacket::parse(&buf[0..n]) {
+                Ok(packet) => break packet,
+                // Leftovers of the handshake arrive on the same socket
+                Err(e) => eprintln!("Skipping datagram: {}", e),
+            }
+        };
+        eprintln!("{:?}", packet);
         packet.try_print_data();
-        println!("---");
+        eprintln!("---");
 
         packet
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(body: &[u8]) -> Vec<u8> {
+        [b"PTCP".as_slice(), &[0u8; 20], body].concat()
+    }
+
+    fn parse_body(body: &[u8]) -> Result<PTCPBody, PTCPError> {
+        PTCPPacket::parse(&packet(body)).map(|packet| packet.body)
+    }
+
+    fn every_body() -> Vec<PTCPBody> {
+        vec![
+            PTCPBody::Sync,
+            PTCPBody::SignRequest,
+            PTCPBody::Sign(vec![0xaa; 8]),
+            PTCPBody::Auth(vec![0xbb; 8]),
+            PTCPBody::AuthResponse(vec![0xcc; 4]),
+            PTCPBody::AuthConfirm,
+            PTCPBody::Command(vec![0x20, 0, 0, 0, 1, 2, 3, 4]),
+            PTCPBody::Payload(PTCPPayload {
+                realm: 0x12345678,
+                data: b"RTSP/1.0 200 OK".to_vec(),
+            }),
+            PTCPBody::Bind(0x12345678, 554),
+            PTCPBody::Status(0x12345678, "CONN".to_string()),
+            PTCPBody::Heartbeat,
+            PTCPBody::Empty,
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut session = PTCPSession::new();
+
+        for body in every_body() {
+            let expected = format!("{:?}", body);
+            let data = session.send(body).serialize();
+            let packet = PTCPPacket::parse(&data).unwrap();
+
+            assert_eq!(format!("{:?}", packet.body), expected);
+            assert_eq!(packet.serialize(), data);
+        }
+    }
+
+    #[test]
+    fn control_fields() {
+        let fields = |body: &[u8]| parse_body(body).unwrap().control_fields();
+
+        let mut sign = vec![0x18, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        sign.extend_from_slice(&[0xaa; 8]);
+        assert_eq!(fields(&sign), Some((0x18, vec![0xaa; 8])));
+
+        // Control messages of other types keep their fields
+        let mut other = sign.clone();
+        other[0] = 0x1c;
+        assert_eq!(fields(&other), Some((0x1c, vec![0xaa; 8])));
+        assert_eq!(fields(&[0x1c, 0, 0, 0]), None);
+
+        assert_eq!(fields(&[0x13; 12]), None);
+        assert_eq!(PTCPBody::Empty.control_fields(), None);
+    }
+
+    fn payload(session: &mut PTCPSession, data: &[u8]) -> PTCPPacket {
+        session.send(PTCPBody::Payload(PTCPPayload {
+            realm: 1,
+            data: data.to_vec(),
+        }))
+    }
+
+    fn data(packets: Vec<PTCPPacket>) -> Vec<Vec<u8>> {
+        packets
+            .into_iter()
+            .map(|packet| match packet.body {
+                PTCPBody::Payload(payload) => payload.data,
+                body => panic!("unexpected {:?}", body),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sequencing() {
+        let mut peer = PTCPSession::new();
+        let mut session = PTCPSession::new();
+
+        let first = payload(&mut peer, b"first");
+        let second = payload(&mut peer, b"second");
+        let third = payload(&mut peer, b"third");
+
+        // Held back until the gap is filled, then delivered in order
+        assert!(session.receive(third.clone()).is_empty());
+        assert!(session.receive(second.clone()).is_empty());
+        assert_eq!(
+            data(session.receive(first.clone())),
+            vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]
+        );
+
+        // Duplicates are dropped
+        assert!(session.receive(second).is_empty());
+        assert!(session.receive(third).is_empty());
+
+        // Acknowledged by the next packet sent
+        let ack = session.send(PTCPBody::Empty);
+        assert_eq!(ack.recv, peer.sent);
+        assert!(peer.receive(ack).is_empty());
+        assert_eq!(peer.acked(), peer.sent);
+    }
+
+    #[test]
+    fn sequencing_wraps() {
+        let mut peer = PTCPSession::new();
+        peer.sent = u32::MAX - 10;
+        let mut session = PTCPSession::new();
+        session.recv = u32::MAX - 10;
+
+        let first = payload(&mut peer, b"first");
+        let second = payload(&mut peer, b"second");
+        assert!(second.sent < first.sent);
+
+        assert!(session.receive(second).is_empty());
+        assert_eq!(data(session.receive(first)).len(), 2);
+        assert_eq!(session.recv, peer.sent);
+    }
+
+    #[test]
+    fn skips_a_gap_never_filled() {
+        let mut peer = PTCPSession::new();
+        let mut session = PTCPSession::new();
+
+        payload(&mut peer, b"lost");
+        for i in 0..MAX_PENDING as u8 {
+            assert!(session.receive(payload(&mut peer, &[i])).is_empty());
+        }
+
+        let ready = session.receive(payload(&mut peer, b"last"));
+        assert_eq!(ready.len(), MAX_PENDING + 1);
+        assert_eq!(session.recv, peer.sent);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retransmissions() {
+        let mut session = PTCPSession::new();
+        let mut unacked = Retransmissions::default();
+
+        let first = payload(&mut session, b"first");
+        let end = session.sent;
+        unacked.push(&first);
+        let second = payload(&mut session, b"second");
+        unacked.push(&second);
+        unacked.push(&session.send(PTCPBody::Empty));
+        assert_eq!(unacked.0.len(), 2);
+
+        assert!(unacked.due().is_empty());
+        tokio::time::advance(RTO_MIN).await;
+        assert_eq!(unacked.due(), vec![first, second]);
+
+        // Backed off
+        tokio::time::advance(RTO_MIN).await;
+        assert!(unacked.due().is_empty());
+        assert_eq!(unacked.next_due(), Some(Instant::now() + RTO_MIN));
+
+        unacked.acknowledge(end);
+        assert_eq!(unacked.0.len(), 1);
+        unacked.acknowledge(session.sent);
+        assert_eq!(unacked.next_due(), None);
+    }
+
+    #[test]
+    fn truncated_header() {
+        let data = packet(&[]);
+
+        for n in 0..24 {
+            assert_eq!(
+                PTCPPacket::parse(&data[..n]).err(),
+                Some(PTCPError::Truncated("packet header")),
+                "{} bytes",
+                n
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_magic() {
+        // An inverted STUN Binding request
+        let mut stun = vec![0xff, 0xfe, 0xff, 0xe7];
+        stun.extend_from_slice(&[0u8; 40]);
+
+        assert_eq!(
+            PTCPPacket::parse(&stun).err(),
+            Some(PTCPError::Magic([0xff, 0xfe, 0xff, 0xe7]))
+        );
+    }
+
+    #[test]
+    fn truncated_body() {
+        for n in 1..4 {
+            assert_eq!(
+                parse_body(&[0x00, 0x03, 0x01][..n]).err(),
+                Some(PTCPError::Truncated("body"))
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_payload() {
+        for n in 4..12 {
+            assert_eq!(
+                parse_body(&[0x10; 12][..n]).err(),
+                Some(PTCPError::Truncated("payload header"))
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_payload_padding() {
+        let mut body = PTCPPayload {
+            realm: 1,
+            data: vec![1, 2],
+        }
+        .serialize();
+        body[11] = 1;
+
+        assert_eq!(parse_body(&body).err(), Some(PTCPError::Padding(1)));
+    }
+
+    #[test]
+    fn invalid_payload_length() {
+        let body = PTCPPayload {
+            realm: 1,
+            data: vec![1, 2, 3],
+        }
+        .serialize();
+
+        assert_eq!(
+            parse_body(&body[..14]).err(),
+            Some(PTCPError::Length {
+                declared: 3,
+                actual: 2
+            })
+        );
+        assert_eq!(
+            parse_body(&[body.as_slice(), &[4]].concat()).err(),
+            Some(PTCPError::Length {
+                declared: 3,
+                actual: 4
+            })
+        );
+    }
+
+    #[test]
+    fn truncated_bind() {
+        let body = PTCPBody::Bind(1, 554).serialize();
+
+        for n in 4..16 {
+            assert_eq!(
+                parse_body(&body[..n]).err(),
+                Some(PTCPError::Truncated("bind"))
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_status() {
+        let body = PTCPBody::Status(1, "DISC".to_string()).serialize();
+
+        for n in 4..12 {
+            assert_eq!(
+                parse_body(&body[..n]).err(),
+                Some(PTCPError::Truncated("status"))
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_control() {
+        for kind in 0x17..=0x1b {
+            for n in 4..CONTROL_HEADER {
+                assert_eq!(
+                    parse_body(&control(kind, &[])[..n]).err(),
+                    Some(PTCPError::Truncated("control message")),
+                    "type 0x{:02x}, {} bytes",
+                    kind,
+                    n
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_prefix() {
+        let mut session = PTCPSession::new();
+
+        for body in every_body() {
+            let data = session.send(body).serialize();
+
+            for n in 0..data.len() {
+                let _ = PTCPPacket::parse(&data[..n]);
+            }
+        }
+    }
+}
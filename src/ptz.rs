@@ -0,0 +1,259 @@
+use clap::{Args, Subcommand, ValueEnum};
+use std::{io, time::Duration};
+
+use crate::{auth::Credentials, cgi::CgiClient, tunnel::Tunnel};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+    LeftUp,
+    RightUp,
+    LeftDown,
+    RightDown,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Zoom {
+    In,
+    Out,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum PresetAction {
+    Goto,
+    Set,
+    Clear,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum TourAction {
+    Start,
+    Stop,
+}
+
+/**
+ * A `ptz.cgi` command, speeds range from 1 to 8
+ */
+#[derive(Clone, Copy, Debug)]
+pub enum PtzCommand {
+    Move(Direction, u8),
+    Zoom(Zoom, u8),
+    Preset(PresetAction, u32),
+    Tour(TourAction, u32),
+}
+
+impl PtzCommand {
+    fn code(&self) -> &'static str {
+        match self {
+            PtzCommand::Move(direction, _) => match direction {
+                Direction::Up => "Up",
+                Direction::Down => "Down",
+                Direction::Left => "Left",
+                Direction::Right => "Right",
+                Direction::LeftUp => "LeftUp",
+                Direction::RightUp => "RightUp",
+                Direction::LeftDown => "LeftDown",
+                Direction::RightDown => "RightDown",
+            },
+            PtzCommand::Zoom(Zoom::In, _) => "ZoomTele",
+            PtzCommand::Zoom(Zoom::Out, _) => "ZoomWide",
+            PtzCommand::Preset(PresetAction::Goto, _) => "GotoPreset",
+            PtzCommand::Preset(PresetAction::Set, _) => "SetPreset",
+            PtzCommand::Preset(PresetAction::Clear, _) => "ClearPreset",
+            PtzCommand::Tour(TourAction::Start, _) => "StartTour",
+            PtzCommand::Tour(TourAction::Stop, _) => "StopTour",
+        }
+    }
+
+    /**
+     * `arg1`, `arg2` and `arg3` of the command
+     */
+    fn args(&self) -> (u32, u32, u32) {
+        match *self {
+            // Diagonal moves take the vertical and horizontal speeds
+            PtzCommand::Move(
+                Direction::LeftUp | Direction::RightUp | Direction::LeftDown | Direction::RightDown,
+                speed,
+            ) => (speed as u32, speed as u32, 0),
+            PtzCommand::Move(_, speed) | PtzCommand::Zoom(_, speed) => (0, speed as u32, 0),
+            PtzCommand::Preset(_, preset) => (0, preset, 0),
+            PtzCommand::Tour(_, tour) => (tour, 0, 0),
+        }
+    }
+
+    /**
+     * Whether the command runs until it is stopped
+     */
+    pub fn is_continuous(&self) -> bool {
+        matches!(self, PtzCommand::Move(..) | PtzCommand::Zoom(..))
+    }
+}
+
+/**
+ * PTZ control of a channel through the device's `ptz.cgi`
+ */
+pub struct Ptz {
+    client: CgiClient,
+    channel: u32,
+}
+
+impl Ptz {
+    pub fn new(client: CgiClient, channel: u32) -> Ptz {
+        Ptz { client, channel }
+    }
+
+    async fn control(&self, action: &str, command: PtzCommand) -> io::Result<()> {
+        let (arg1, arg2, arg3) = command.args();
+        let uri = format!(
+            "/cgi-bin/ptz.cgi?action={}&channel={}&code={}&arg1={}&arg2={}&arg3={}",
+            action,
+            self.channel,
+            command.code(),
+            arg1,
+            arg2,
+            arg3
+        );
+
+        let body = self.client.get(&uri).await?;
+        let body = String::from_utf8_lossy(&body);
+
+        // The device answers "OK" or an error description
+        if body.trim() != "OK" {
+            return Err(io::Error::other(format!(
+                "PTZ {} {} failed: {}",
+                action,
+                command.code(),
+                body.trim()
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub async fn start(&self, command: PtzCommand) -> io::Result<()> {
+        self.control("start", command).await
+    }
+
+    pub async fn stop(&self, command: PtzCommand) -> io::Result<()> {
+        self.control("stop", command).await
+    }
+
+    /**
+     * Run a command, continuous ones are stopped after `duration`
+     */
+    pub async fn run(&self, command: PtzCommand, duration: Duration) -> io::Result<()> {
+        self.start(command).await?;
+
+        if command.is_continuous() {
+            tokio::time::sleep(duration).await;
+            self.stop(command).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct PtzArgs {
+    /// Username of the camera
+    #[arg(short, long)]
+    username: Option<String>,
+    /// Password of the camera
+    #[arg(short, long)]
+    password: Option<String>,
+    /// Channel to control
+    #[arg(short, long, default_value_t = 1)]
+    channel: u32,
+    /// HTTP port of the device
+    #[arg(long, default_value_t = 80)]
+    remote_port: u16,
+    #[command(subcommand)]
+    action: PtzAction,
+}
+
+fn parse_duration(s: &str) -> Result<f64, String> {
+    let seconds: f64 = s.parse().map_err(|_| "expected a number of seconds")?;
+
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(_) => Ok(seconds),
+        Err(_) => Err("expected a finite number of seconds, 0 or more".to_string()),
+    }
+}
+
+#[derive(Subcommand)]
+enum PtzAction {
+    /// Pan and tilt
+    Move {
+        #[arg(value_enum)]
+        direction: Direction,
+        /// Speed from 1 to 8
+        #[arg(short, long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(1..=8))]
+        speed: u8,
+        /// Duration of the movement in seconds
+        #[arg(short, long, default_value_t = 1.0, value_parser = parse_duration)]
+        duration: f64,
+    },
+    /// Zoom in or out
+    Zoom {
+        #[arg(value_enum)]
+        direction: Zoom,
+        /// Speed from 1 to 8
+        #[arg(short, long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(1..=8))]
+        speed: u8,
+        /// Duration of the zoom in seconds
+        #[arg(short, long, default_value_t = 1.0, value_parser = parse_duration)]
+        duration: f64,
+    },
+    /// Go to, set or clear a preset
+    Preset {
+        #[arg(value_enum)]
+        action: PresetAction,
+        id: u32,
+    },
+    /// Start or stop a tour
+    Tour {
+        #[arg(value_enum)]
+        action: TourAction,
+        id: u32,
+    },
+}
+
+impl PtzArgs {
+    fn credentials(&self) -> Option<Credentials> {
+        Some(Credentials {
+            username: self.username.clone()?,
+            password: self.password.clone().unwrap_or_default(),
+        })
+    }
+}
+
+/**
+ * Run a PTZ command given on the command line
+ */
+pub async fn ptz(tunnel: Tunnel, args: PtzArgs) -> io::Result<()> {
+    let client = CgiClient::new(tunnel, args.remote_port, args.credentials());
+    let ptz = Ptz::new(client, args.channel);
+
+    let (command, duration) = match args.action {
+        PtzAction::Move {
+            direction,
+            speed,
+            duration,
+        } => (PtzCommand::Move(direction, speed), duration),
+        PtzAction::Zoom {
+            direction,
+            speed,
+            duration,
+        } => (PtzCommand::Zoom(direction, speed), duration),
+        PtzAction::Preset { action, id } => (PtzCommand::Preset(action, id), 0.0),
+        PtzAction::Tour { action, id } => (PtzCommand::Tour(action, id), 0.0),
+    };
+
+    ptz.run(command, Duration::from_secs_f64(duration)).await?;
+    eprintln!("PTZ: {} done", command.code());
+
+    Ok(())
+}
@@ -0,0 +1,340 @@
+use clap::Args;
+use std::{
+    io,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, DuplexStream},
+};
+
+use crate::{
+    auth::Credentials,
+    media::MediaSession,
+    net::BindArgs,
+    segment::{Chunk, Format, Segmenter},
+    shutdown::{self, Shutdown},
+    tunnel::Tunnel,
+};
+
+#[derive(Args)]
+pub struct RecordArgs {
+    /// Username of the camera
+    #[arg(short, long)]
+    username: Option<String>,
+    /// Password of the camera
+    #[arg(short, long)]
+    password: Option<String>,
+    /// Channel to record
+    #[arg(short, long, default_value_t = 1)]
+    channel: u32,
+    /// Stream subtype, 0 for main stream and 1 for sub stream
+    #[arg(short, long, default_value_t = 0)]
+    subtype: u32,
+    /// RTSP port of the device
+    #[arg(long, default_value_t = 554)]
+    remote_port: u16,
+    /// Container of the segments
+    #[arg(short, long, value_enum, default_value_t = Format::Ts)]
+    format: Format,
+    /// Duration of each segment in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    segment: u64,
+    /// Output directory
+    #[arg(short, long, default_value = ".")]
+    output: PathBuf,
+    /// Maximum number of segments to keep, oldest are removed first
+    #[arg(long, value_name = "COUNT")]
+    max_segments: Option<usize>,
+    /// Maximum age of segments to keep in seconds
+    #[arg(long, value_name = "SECONDS")]
+    max_age: Option<u64>,
+}
+
+impl RecordArgs {
+    fn credentials(&self) -> Option<Credentials> {
+        Some(Credentials {
+            username: self.username.clone()?,
+            password: self.password.clone().unwrap_or_default(),
+        })
+    }
+}
+
+/**
+ * Write a chunk into the current segment file, or start a new file
+ */
+async fn write_chunk(
+    args: &RecordArgs,
+    prefix: &str,
+    file: &mut Option<(File, PathBuf)>,
+    chunk: Chunk,
+) -> io::Result<()> {
+    match chunk {
+        Chunk::Segment { header, .. } => {
+            close(file).await?;
+
+            let (mut f, path) = create_segment(args, prefix).await?;
+            eprintln!("Recorder: writing {}", path.display());
+
+            f.write_all(&header).await?;
+            *file = Some((f, path));
+
+            enforce_retention(args, prefix).await?;
+        }
+        Chunk::Data(data) => {
+            if let Some((f, _)) = file.as_mut() {
+                f.write_all(&data).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Create the file of a new segment, named after its UTC start time so that names sort in
+ * order, a reconnection within the same second gets a numbered name instead of overwriting
+ */
+async fn create_segment(args: &RecordArgs, prefix: &str) -> io::Result<(File, PathBuf)> {
+    let start = chrono::Utc::now().format("%Y%m%d-%H%M%SZ");
+    let extension = match args.format {
+        Format::Ts => "ts",
+        Format::Mp4 => "mp4",
+    };
+
+    for n in 0.. {
+        let name = match n {
+            0 => format!("{}{}.{}", prefix, start, extension),
+            n => format!("{}{}_{}.{}", prefix, start, n, extension),
+        };
+        let path = args.output.join(name);
+
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+        {
+            Ok(f) => return Ok((f, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    unreachable!()
+}
+
+async fn close(file: &mut Option<(File, PathBuf)>) -> io::Result<()> {
+    if let Some((mut f, path)) = file.take() {
+        f.flush().await?;
+        eprintln!("Recorder: closed {}", path.display());
+    }
+
+    Ok(())
+}
+
+/**
+ * Remove the oldest segments beyond the configured limits
+ */
+async fn enforce_retention(args: &RecordArgs, prefix: &str) -> io::Result<()> {
+    if args.max_segments.is_none() && args.max_age.is_none() {
+        return Ok(());
+    }
+
+    let mut segments = Vec::new();
+    let mut entries = tokio::fs::read_dir(&args.output).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(prefix) && (name.ends_with(".ts") || name.ends_with(".mp4")) {
+            let modified = entry.metadata().await?.modified()?;
+            segments.push((name, entry.path(), modified));
+        }
+    }
+
+    // Names carry the start time
+    segments.sort();
+
+    let excess = args
+        .max_segments
+        .map_or(0, |max| segments.len().saturating_sub(max));
+    let now = SystemTime::now();
+
+    for (i, (_, path, modified)) in segments.iter().enumerate() {
+        let expired = args.max_age.is_some_and(|age| {
+            now.duration_since(*modified).unwrap_or_default() > Duration::from_secs(age)
+        });
+
+        if i < excess || expired {
+            eprintln!("Recorder: removing {}", path.display());
+            tokio::fs::remove_file(path).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Record until the stream fails or a shutdown is requested, finishing the last segment
+ */
+async fn record_session(
+    stream: DuplexStream,
+    args: &RecordArgs,
+    prefix: &str,
+    shutdown: &Shutdown,
+) -> io::Result<()> {
+    let url = format!(
+        "rtsp://127.0.0.1:{}/cam/realmonitor?channel={}&subtype={}",
+        args.remote_port, args.channel, args.subtype
+    );
+
+    let range = [("Range", "npt=0.000-".to_string())];
+    let play = MediaSession::play(stream, url, args.credentials(), false, &range);
+    let mut session = tokio::select! {
+        session = play => session?,
+        _ = shutdown.requested() => return Ok(()),
+    };
+
+    let mut segmenter = Segmenter::new(
+        args.format,
+        session.codec,
+        session.params.clone(),
+        None,
+        Duration::from_secs(args.segment),
+        session.rtptime,
+    );
+    let mut file = None;
+
+    let result = async {
+        loop {
+            let frames = tokio::select! {
+                frames = session.next() => frames?,
+                _ = shutdown.requested() => return Ok(()),
+            };
+
+            for frame in frames {
+                for chunk in segmenter.push(frame) {
+                    write_chunk(args, prefix, &mut file, chunk).await?;
+                }
+            }
+        }
+    }
+    .await;
+
+    for chunk in segmenter.finish() {
+        write_chunk(args, prefix, &mut file, chunk).await?;
+    }
+    close(&mut file).await?;
+
+    result
+}
+
+/**
+ * Record the RTSP stream of a channel into rolling segments until a shutdown is requested
+ */
+pub async fn record(
+    serial: String,
+    relay_mode: bool,
+    bind: BindArgs,
+    args: RecordArgs,
+    shutdown: &Shutdown,
+) {
+    tokio::fs::create_dir_all(&args.output).await.unwrap();
+
+    let prefix = format!("{}_ch{}_s{}_", serial, args.channel, args.subtype);
+    let mut tunnel: Option<Tunnel> = None;
+
+    while !shutdown.is_requested() {
+        if tunnel.as_ref().is_none_or(|t| t.is_closed()) {
+            let connect = Tunnel::connect(serial.clone(), relay_mode, &bind);
+            let result = tokio::select! {
+                result = connect => result,
+                _ = shutdown.requested() => break,
+            };
+
+            match result {
+                Ok(t) => tunnel = Some(t),
+                Err(e) => eprintln!("Recorder: handshake failed: {}", e),
+            }
+        }
+
+        if let Some(t) = &tunnel {
+            // A realm that can't be opened means the session is gone
+            let opened = tokio::select! {
+                opened = t.open(args.remote_port) => opened,
+                _ = shutdown.requested() => break,
+            };
+
+            match opened {
+                Ok(stream) => {
+                    if let Err(e) = record_session(stream, &args, &prefix, shutdown).await {
+                        eprintln!("Recorder: {}", e);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Recorder: {}, re-establishing the PTCP session", e);
+                    t.close();
+                    tunnel = None;
+                }
+            }
+        }
+
+        if shutdown.is_requested() {
+            break;
+        }
+
+        eprintln!("Recorder: reconnecting in 5 seconds");
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+            _ = shutdown.requested() => {}
+        }
+    }
+
+    if let Some(t) = &tunnel {
+        shutdown::close(t).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn segments_in_the_same_second() {
+        let output = std::env::temp_dir().join(format!("dh-p2p-record-{}", std::process::id()));
+        tokio::fs::create_dir_all(&output).await.unwrap();
+
+        let args = RecordArgs {
+            username: None,
+            password: None,
+            channel: 1,
+            subtype: 0,
+            remote_port: 554,
+            format: Format::Ts,
+            segment: 60,
+            output: output.clone(),
+            max_segments: None,
+            max_age: None,
+        };
+
+        let mut paths = Vec::new();
+        for _ in 0..3 {
+            let (_, path) = create_segment(&args, "serial_ch1_s0_").await.unwrap();
+            paths.push(path);
+        }
+
+        let names: Vec<String> = paths
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        sorted.dedup();
+
+        // Within a second the later segments get a suffix, still in order
+        assert_eq!(names, sorted);
+        assert!(names[0].ends_with("Z.ts"));
+
+        tokio::fs::remove_dir_all(&output).await.unwrap();
+    }
+}
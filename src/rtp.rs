@@ -0,0 +1,539 @@
+use crate::codec::VideoCodec;
+
+pub struct RtpPacket<'a> {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub payload: &'a [u8],
+}
+
+impl<'a> RtpPacket<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<RtpPacket<'a>> {
+        if data.len() < 12 || data[0] >> 6 != 2 {
+            return None;
+        }
+
+        let padding = data[0] & 0x20 != 0;
+        let extension = data[0] & 0x10 != 0;
+        let csrc_count = (data[0] & 0x0F) as usize;
+
+        let mut start = 12 + csrc_count * 4;
+        if extension {
+            let ext = data.get(start..start + 4)?;
+            start += 4 + u16::from_be_bytes([ext[2], ext[3]]) as usize * 4;
+        }
+
+        let mut end = data.len();
+        if padding {
+            end = end.checked_sub(*data.last()? as usize)?;
+        }
+
+        Some(RtpPacket {
+            marker: data[1] & 0x80 != 0,
+            payload_type: data[1] & 0x7F,
+            sequence: u16::from_be_bytes([data[2], data[3]]),
+            timestamp: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            payload: data.get(start..end)?,
+        })
+    }
+}
+
+/**
+ * Unwraps 32-bit RTP timestamps into a monotonic 64-bit timeline
+ */
+#[derive(Default)]
+pub struct Timeline {
+    last: Option<u32>,
+    current: i64,
+}
+
+impl Timeline {
+    /**
+     * Start the timeline at the `rtptime` announced by RTSP PLAY
+     */
+    pub fn starting_at(timestamp: u32) -> Timeline {
+        Timeline {
+            last: Some(timestamp),
+            current: 0,
+        }
+    }
+
+    pub fn extend(&mut self, timestamp: u32) -> i64 {
+        if let Some(last) = self.last {
+            self.current += timestamp.wrapping_sub(last) as i32 as i64;
+        }
+        self.last = Some(timestamp);
+
+        self.current
+    }
+}
+
+/**
+ * A complete access unit, NAL units without start codes
+ */
+pub struct Frame {
+    pub timestamp: u32,
+    pub keyframe: bool,
+    pub nalus: Vec<Vec<u8>>,
+}
+
+impl Frame {
+    /**
+     * Annex B byte stream, as used by MPEG-TS
+     */
+    pub fn to_annexb(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for nal in &self.nalus {
+            out.extend_from_slice(b"\x00\x00\x00\x01");
+            out.extend_from_slice(nal);
+        }
+        out
+    }
+
+    /**
+     * Length-prefixed NAL units, as used by MP4
+     */
+    pub fn to_avcc(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for nal in &self.nalus {
+            out.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            out.extend_from_slice(nal);
+        }
+        out
+    }
+}
+
+/**
+ * Reassembles H.264 (RFC 6184) and H.265 (RFC 7798) RTP payloads into frames
+ */
+pub struct VideoDepacketizer {
+    codec: VideoCodec,
+    timestamp: Option<u32>,
+    nalus: Vec<Vec<u8>>,
+    fragment: Option<Vec<u8>>,
+    sequence: Option<u16>,
+}
+
+impl VideoDepacketizer {
+    pub fn new(codec: VideoCodec) -> VideoDepacketizer {
+        VideoDepacketizer {
+            codec,
+            timestamp: None,
+            nalus: Vec::new(),
+            fragment: None,
+            sequence: None,
+        }
+    }
+
+    fn take_frame(&mut self) -> Option<Frame> {
+        let timestamp = self.timestamp?;
+        self.fragment = None;
+
+        if self.nalus.is_empty() {
+            return None;
+        }
+
+        let nalus = std::mem::take(&mut self.nalus);
+
+        Some(Frame {
+            timestamp,
+            keyframe: nalus.iter().any(|n| self.codec.is_keyframe(n)),
+            nalus,
+        })
+    }
+
+    /**
+     * Feed a packet, returns the frames it completes
+     */
+    pub fn push(&mut self, packet: &RtpPacket) -> Vec<Frame> {
+        let mut frames = Vec::new();
+
+        if let Some(seq) = self.sequence {
+            if packet.sequence != seq.wrapping_add(1) {
+                // Lost packet, the fragment being assembled is unusable
+                self.fragment = None;
+            }
+        }
+        self.sequence = Some(packet.sequence);
+
+        if self.timestamp != Some(packet.timestamp) {
+            frames.extend(self.take_frame());
+            self.timestamp = Some(packet.timestamp);
+        }
+
+        match self.codec {
+            VideoCodec::H264 => self.push_h264(packet.payload),
+            VideoCodec::H265 => self.push_h265(packet.payload),
+        }
+
+        if packet.marker {
+            frames.extend(self.take_frame());
+        }
+
+        frames
+    }
+
+    fn push_aggregate(&mut self, mut data: &[u8]) {
+        while data.len() > 2 {
+            let size = u16::from_be_bytes([data[0], data[1]]) as usize;
+            let Some(nal) = data.get(2..2 + size) else {
+                return;
+            };
+
+            if !nal.is_empty() {
+                self.nalus.push(nal.to_vec());
+            }
+            data = &data[2 + size..];
+        }
+    }
+
+    fn push_fragment(&mut self, header: &[u8], start: bool, end: bool, data: &[u8]) {
+        if start {
+            self.fragment = Some(header.to_vec());
+        }
+
+        if let Some(fragment) = self.fragment.as_mut() {
+            fragment.extend_from_slice(data);
+        }
+
+        if end {
+            self.nalus.extend(self.fragment.take());
+        }
+    }
+
+    fn push_h264(&mut self, payload: &[u8]) {
+        if payload.is_empty() {
+            return;
+        }
+
+        match payload[0] & 0x1F {
+            1..=23 => self.nalus.push(payload.to_vec()),
+            // STAP-A
+            24 => self.push_aggregate(&payload[1..]),
+            // FU-A
+            28 if payload.len() > 2 => {
+                let fu = payload[1];
+                let header = [(payload[0] & 0xE0) | (fu & 0x1F)];
+                self.push_fragment(&header, fu & 0x80 != 0, fu & 0x40 != 0, &payload[2..]);
+            }
+            _ => {}
+        }
+    }
+
+    fn push_h265(&mut self, payload: &[u8]) {
+        if payload.len() < 2 {
+            return;
+        }
+
+        match (payload[0] >> 1) & 0x3F {
+            0..=47 => self.nalus.push(payload.to_vec()),
+            // Aggregation packet
+            48 => self.push_aggregate(&payload[2..]),
+            // Fragmentation unit
+            49 if payload.len() > 3 => {
+                let fu = payload[2];
+                let header = [(payload[0] & 0x81) | ((fu & 0x3F) << 1), payload[1]];
+                self.push_fragment(&header, fu & 0x80 != 0, fu & 0x40 != 0, &payload[3..]);
+            }
+            _ => {}
+        }
+    }
+}
+
+pub struct AudioFrame {
+    pub timestamp: u32,
+    pub data: Vec<u8>,
+}
+
+/**
+ * Extracts AAC frames from MPEG-4 generic (RFC 3640) RTP payloads
+ */
+pub struct AacDepacketizer {
+    size_length: usize,
+    index_length: usize,
+    index_delta_length: usize,
+    fragment: Option<(u32, usize, Vec<u8>)>,
+}
+
+impl AacDepacketizer {
+    /**
+     * Depacketizer for the AU headers described by the fmtp, `None` when they can't be read
+     */
+    pub fn new(fmtp: &[(String, String)]) -> Option<AacDepacketizer> {
+        let get = |k: &str, default: usize| {
+            fmtp.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(k))
+                .and_then(|(_, v)| v.parse().ok())
+                .unwrap_or(default)
+        };
+
+        let depacketizer = AacDepacketizer {
+            size_length: get("sizelength", 13),
+            index_length: get("indexlength", 3),
+            index_delta_length: get("indexdeltalength", 3),
+            fragment: None,
+        };
+
+        // Each AU header must have a size, and fields fit the 32 bits they are read into
+        let widths = [
+            depacketizer.size_length,
+            depacketizer.index_length,
+            depacketizer.index_delta_length,
+        ];
+        if depacketizer.size_length == 0 || widths.iter().any(|w| *w > 32) {
+            return None;
+        }
+
+        Some(depacketizer)
+    }
+
+    pub fn push(&mut self, packet: &RtpPacket) -> Vec<AudioFrame> {
+        let payload = packet.payload;
+        let mut frames = Vec::new();
+
+        if payload.len() < 2 {
+            return frames;
+        }
+
+        let headers_bits = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+        let headers_len = headers_bits.div_ceil(8);
+        let Some(headers) = payload.get(2..2 + headers_len) else {
+            return frames;
+        };
+        let mut data = &payload[2 + headers_len..];
+
+        let mut reader = crate::codec::BitReader::new(headers);
+        let mut sizes = Vec::new();
+        let mut consumed = 0;
+        while consumed + self.size_length <= headers_bits {
+            let index_length = if sizes.is_empty() {
+                self.index_length
+            } else {
+                self.index_delta_length
+            };
+            let Some(size) = reader.bits(self.size_length) else {
+                break;
+            };
+            let _ = reader.bits(index_length);
+            consumed += self.size_length + index_length;
+            sizes.push(size as usize);
+        }
+
+        if sizes.len() != 1 {
+            self.fragment = None;
+        }
+
+        // A single AU larger than the packet is fragmented over several
+        if let [size] = sizes[..] {
+            if size > data.len() || self.fragment.is_some() {
+                let (timestamp, expected, fragment) =
+                    self.fragment
+                        .get_or_insert((packet.timestamp, size, Vec::new()));
+                fragment.extend_from_slice(data);
+
+                if fragment.len() >= *expected || packet.marker {
+                    if fragment.len() == *expected {
+                        frames.push(AudioFrame {
+                            timestamp: *timestamp,
+                            data: std::mem::take(fragment),
+                        });
+                    }
+                    self.fragment = None;
+                }
+
+                return frames;
+            }
+        }
+
+        for (i, size) in sizes.into_iter().enumerate() {
+            let Some(frame) = data.get(..size) else {
+                break;
+            };
+
+            frames.push(AudioFrame {
+                timestamp: packet.timestamp.wrapping_add(i as u32 * 1024),
+                data: frame.to_vec(),
+            });
+            data = &data[size..];
+        }
+
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fmtp(params: &[(&str, &str)]) -> Vec<(String, String)> {
+        params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn packet(timestamp: u32, marker: bool, payload: &[u8]) -> RtpPacket<'_> {
+        RtpPacket {
+            marker,
+            payload_type: 97,
+            sequence: 0,
+            timestamp,
+            payload,
+        }
+    }
+
+    fn video(sequence: u16, timestamp: u32, marker: bool, payload: &[u8]) -> RtpPacket<'_> {
+        RtpPacket {
+            sequence,
+            payload_type: 96,
+            ..packet(timestamp, marker, payload)
+        }
+    }
+
+    #[test]
+    fn parse() {
+        // Padding, a CSRC and a one word extension around a 3 byte payload
+        let data = [
+            0xB1, 0xE0, 0x12, 0x34, 0x00, 0x00, 0x03, 0xE8, 0, 0, 0, 1, // header
+            0, 0, 0, 2, // CSRC
+            0xBE, 0xDE, 0x00, 0x01, 0, 0, 0, 0, // extension
+            7, 8, 9, 0, 2, // payload and padding
+        ];
+
+        let packet = RtpPacket::parse(&data).unwrap();
+        assert!(packet.marker);
+        assert_eq!(packet.payload_type, 96);
+        assert_eq!(packet.sequence, 0x1234);
+        assert_eq!(packet.timestamp, 1000);
+        assert_eq!(packet.payload, [7, 8, 9]);
+
+        // Truncated extension and padding longer than the packet
+        assert!(RtpPacket::parse(&data[..18]).is_none());
+        assert!(RtpPacket::parse(&[0xA0, 0x60, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 20]).is_none());
+        assert!(RtpPacket::parse(&[0x40; 12]).is_none());
+    }
+
+    #[test]
+    fn timeline_wraps() {
+        let mut timeline = Timeline::starting_at(u32::MAX - 1000);
+
+        assert_eq!(timeline.extend(u32::MAX - 1000), 0);
+        assert_eq!(timeline.extend(2999), 4000);
+        assert_eq!(timeline.extend(1999), 3000);
+    }
+
+    #[test]
+    fn h264() {
+        let mut depacketizer = VideoDepacketizer::new(VideoCodec::H264);
+        let mut frames = Vec::new();
+
+        // SPS and PPS aggregated, then an IDR slice over two FU-A
+        let stap = [0x18, 0x00, 0x02, 0x67, 0x42, 0x00, 0x02, 0x68, 0xCE];
+        frames.extend(depacketizer.push(&video(1, 3000, false, &stap)));
+        frames.extend(depacketizer.push(&video(2, 3000, false, &[0x7C, 0x85, 1, 2])));
+        frames.extend(depacketizer.push(&video(3, 3000, true, &[0x7C, 0x45, 3])));
+        // A single NAL unit frame
+        frames.extend(depacketizer.push(&video(4, 6000, true, &[0x41, 4, 5])));
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].timestamp, 3000);
+        assert!(frames[0].keyframe);
+        assert_eq!(
+            frames[0].nalus,
+            vec![vec![0x67, 0x42], vec![0x68, 0xCE], vec![0x65, 1, 2, 3]]
+        );
+        assert_eq!(
+            frames[0].to_annexb(),
+            [0, 0, 0, 1, 0x67, 0x42, 0, 0, 0, 1, 0x68, 0xCE, 0, 0, 0, 1, 0x65, 1, 2, 3]
+        );
+        assert_eq!(frames[0].to_avcc()[..6], [0, 0, 0, 2, 0x67, 0x42]);
+
+        assert_eq!(frames[1].timestamp, 6000);
+        assert!(!frames[1].keyframe);
+        assert_eq!(frames[1].nalus, vec![vec![0x41, 4, 5]]);
+    }
+
+    #[test]
+    fn h264_lost_fragment() {
+        let mut depacketizer = VideoDepacketizer::new(VideoCodec::H264);
+
+        // The middle of the fragmented slice is lost
+        let mut frames = depacketizer.push(&video(1, 3000, false, &[0x7C, 0x85, 1]));
+        frames.extend(depacketizer.push(&video(3, 3000, true, &[0x7C, 0x45, 3])));
+        assert!(frames.is_empty());
+
+        // A frame without a marker ends with the next timestamp
+        frames.extend(depacketizer.push(&video(4, 6000, false, &[0x41, 4])));
+        frames.extend(depacketizer.push(&video(5, 9000, true, &[0x41, 5])));
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].nalus, vec![vec![0x41, 4]]);
+        assert_eq!(frames[1].nalus, vec![vec![0x41, 5]]);
+    }
+
+    #[test]
+    fn h265() {
+        let mut depacketizer = VideoDepacketizer::new(VideoCodec::H265);
+        let mut frames = Vec::new();
+
+        // VPS and SPS aggregated, then an IDR_W_RADL slice over two fragmentation units
+        let ap = [
+            0x60, 0x01, 0x00, 0x03, 0x40, 0x01, 0xAA, 0x00, 0x03, 0x42, 0x01, 0xBB,
+        ];
+        frames.extend(depacketizer.push(&video(1, 3000, false, &ap)));
+        frames.extend(depacketizer.push(&video(2, 3000, false, &[0x62, 0x01, 0x93, 1, 2])));
+        frames.extend(depacketizer.push(&video(3, 3000, true, &[0x62, 0x01, 0x53, 3])));
+
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].keyframe);
+        assert_eq!(
+            frames[0].nalus,
+            vec![
+                vec![0x40, 0x01, 0xAA],
+                vec![0x42, 0x01, 0xBB],
+                vec![0x26, 0x01, 1, 2, 3]
+            ]
+        );
+    }
+
+    #[test]
+    fn aac_header_widths() {
+        assert!(AacDepacketizer::new(&[]).is_some());
+        assert!(AacDepacketizer::new(&fmtp(&[
+            ("sizelength", "0"),
+            ("indexlength", "0"),
+            ("indexdeltalength", "0"),
+        ]))
+        .is_none());
+        assert!(AacDepacketizer::new(&fmtp(&[("sizelength", "0")])).is_none());
+        assert!(AacDepacketizer::new(&fmtp(&[("indexlength", "40")])).is_none());
+    }
+
+    #[test]
+    fn aac_frames() {
+        let mut aac = AacDepacketizer::new(&fmtp(&[("sizelength", "13")])).unwrap();
+
+        // Two AU headers of 16 bits, sizes 3 and 2
+        let payload = [0x00, 0x20, 0x00, 0x18, 0x00, 0x10, 1, 2, 3, 4, 5];
+        let frames = aac.push(&packet(1000, true, &payload));
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].data, [1, 2, 3]);
+        assert_eq!(frames[0].timestamp, 1000);
+        assert_eq!(frames[1].data, [4, 5]);
+        assert_eq!(frames[1].timestamp, 2024);
+    }
+
+    #[test]
+    fn aac_fragments() {
+        let mut aac = AacDepacketizer::new(&[]).unwrap();
+
+        // One AU of 4 bytes over two packets
+        let frames = aac.push(&packet(7, false, &[0x00, 0x10, 0x00, 0x20, 1, 2]));
+        assert!(frames.is_empty());
+
+        let frames = aac.push(&packet(7, true, &[0x00, 0x10, 0x00, 0x20, 3, 4]));
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].data, [1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,511 @@
+use std::io;
+use tokio::{
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf,
+        WriteHalf,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::auth::{Challenge, Credentials};
+
+/// Largest response body accepted, RTSP bodies are SDP descriptions
+const MAX_BODY: usize = 1024 * 1024;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Debug)]
+pub struct RtspResponse {
+    pub code: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl RtspResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn headers<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.headers
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub enum RtspMessage {
+    Response(RtspResponse),
+    /// Interleaved RTP/RTCP data: channel and packet
+    Data(u8, Vec<u8>),
+}
+
+/**
+ * A media section of an SDP description
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Media {
+    pub kind: String,
+    pub payload_type: u8,
+    pub encoding: String,
+    pub clock_rate: u32,
+    pub channels: Option<u32>,
+    pub control: Option<String>,
+    pub fmtp: Vec<(String, String)>,
+}
+
+pub fn parse_sdp(sdp: &str) -> Vec<Media> {
+    let mut media: Vec<Media> = Vec::new();
+
+    for line in sdp.lines() {
+        let line = line.trim();
+
+        if let Some(m) = line.strip_prefix("m=") {
+            let mut parts = m.split_whitespace();
+            media.push(Media {
+                kind: parts.next().unwrap_or_default().to_string(),
+                payload_type: parts.nth(2).and_then(|p| p.parse().ok()).unwrap_or(0),
+                ..Default::default()
+            });
+            continue;
+        }
+
+        // Session level attributes are not needed
+        let Some(current) = media.last_mut() else {
+            continue;
+        };
+
+        if let Some(control) = line.strip_prefix("a=control:") {
+            current.control = Some(control.to_string());
+        } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+            // a=rtpmap:96 H264/90000
+            let encoding = rtpmap.split_whitespace().nth(1).unwrap_or_default();
+            let mut parts = encoding.split('/');
+            current.encoding = parts.next().unwrap_or_default().to_uppercase();
+            current.clock_rate = parts.next().and_then(|r| r.parse().ok()).unwrap_or(0);
+            current.channels = parts.next().and_then(|c| c.parse().ok());
+        } else if let Some(fmtp) = line.strip_prefix("a=fmtp:") {
+            // a=fmtp:96 packetization-mode=1;sprop-parameter-sets=...
+            let params = fmtp.split_once(' ').map(|(_, p)| p).unwrap_or_default();
+            current.fmtp = params
+                .split(';')
+                .filter_map(|p| p.trim().split_once('='))
+                .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                .collect();
+        }
+    }
+
+    media
+}
+
+/**
+ * Minimal RTSP client using interleaved TCP transport
+ */
+pub struct RtspClient<S> {
+    reader: BufReader<ReadHalf<S>>,
+    writer: WriteHalf<S>,
+    url: String,
+    base: String,
+    cseq: u32,
+    session: Option<String>,
+    timeout: Duration,
+    last_keepalive: Instant,
+    credentials: Option<Credentials>,
+    challenge: Option<Challenge>,
+    nc: u32,
+}
+
+impl<S: AsyncRead + AsyncWrite> RtspClient<S> {
+    pub fn new(stream: S, url: String, credentials: Option<Credentials>) -> RtspClient<S> {
+        let (reader, writer) = tokio::io::split(stream);
+
+        RtspClient {
+            reader: BufReader::new(reader),
+            writer,
+            base: url.clone(),
+            url,
+            cseq: 0,
+            session: None,
+            timeout: Duration::from_secs(60),
+            last_keepalive: Instant::now(),
+            credentials,
+            challenge: None,
+            nc: 0,
+        }
+    }
+
+    async fn send(
+        &mut self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, String)],
+    ) -> io::Result<()> {
+        self.cseq += 1;
+
+        let mut req = format!("{} {} RTSP/1.0\r\nCSeq: {}\r\n", method, url, self.cseq);
+        req += "User-Agent: dh-p2p\r\n";
+
+        if let Some(session) = &self.session {
+            req += &format!("Session: {}\r\n", session);
+        }
+
+        if let (Some(credentials), Some(challenge)) = (&self.credentials, &self.challenge) {
+            self.nc += 1;
+            req += &format!(
+                "Authorization: {}\r\n",
+                credentials.authorization(challenge, method, url, self.nc)
+            );
+        }
+
+        for (k, v) in headers {
+            req += &format!("{}: {}\r\n", k, v);
+        }
+        req += "\r\n";
+
+        eprintln!(">>> RTSP");
+        eprintln!("{}", req);
+        eprintln!("---");
+
+        self.writer.write_all(req.as_bytes()).await
+    }
+
+    /**
+     * Read the next response or interleaved packet
+     */
+    pub async fn read_message(&mut self) -> io::Result<RtspMessage> {
+        let mut first = [0u8; 1];
+        self.reader.read_exact(&mut first).await?;
+
+        if first[0] == b'$' {
+            let mut header = [0u8; 3];
+            self.reader.read_exact(&mut header).await?;
+
+            let mut data = vec![0u8; u16::from_be_bytes([header[1], header[2]]) as usize];
+            self.reader.read_exact(&mut data).await?;
+
+            return Ok(RtspMessage::Data(header[0], data));
+        }
+
+        let mut status = vec![first[0]];
+        self.reader.read_until(b'\n', &mut status).await?;
+        let status = String::from_utf8_lossy(&status).trim().to_string();
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+
+            if let Some((k, v)) = line.split_once(':') {
+                headers.push((k.trim().to_string(), v.trim().to_string()));
+            }
+        }
+
+        let mut parts = status.splitn(3, ' ');
+        let version = parts.next().unwrap_or_default();
+        if !version.starts_with("RTSP/") {
+            return Err(invalid_data(format!(
+                "Invalid RTSP status line: {}",
+                status
+            )));
+        }
+        let code = parts
+            .next()
+            .and_then(|c| c.parse().ok())
+            .ok_or_else(|| invalid_data(format!("Invalid RTSP status line: {}", status)))?;
+        let reason = parts.next().unwrap_or_default().to_string();
+
+        let mut res = RtspResponse {
+            code,
+            reason,
+            headers,
+            body: Vec::new(),
+        };
+
+        if let Some(length) = res.header("Content-Length").and_then(|l| l.parse().ok()) {
+            if length > MAX_BODY {
+                return Err(invalid_data(format!(
+                    "RTSP body too large: {} bytes",
+                    length
+                )));
+            }
+            res.body = vec![0u8; length];
+            self.reader.read_exact(&mut res.body).await?;
+        }
+
+        eprintln!("<<< RTSP");
+        eprintln!("{} {}", res.code, res.reason);
+        eprintln!("---");
+
+        Ok(RtspMessage::Response(res))
+    }
+
+    async fn read_response(&mut self) -> io::Result<RtspResponse> {
+        loop {
+            // Skip data that is still in flight
+            if let RtspMessage::Response(res) = self.read_message().await? {
+                return Ok(res);
+            }
+        }
+    }
+
+    /**
+     * Send a request, answering an authentication challenge once
+     */
+    pub async fn request(
+        &mut self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, String)],
+    ) -> io::Result<RtspResponse> {
+        self.send(method, url, headers).await?;
+        let mut res = self.read_response().await?;
+
+        if res.code == 401 && self.credentials.is_some() && self.challenge.is_none() {
+            self.challenge = Challenge::parse(res.headers("WWW-Authenticate"));
+            if self.challenge.is_some() {
+                self.send(method, url, headers).await?;
+                res = self.read_response().await?;
+            }
+        }
+
+        if res.code >= 300 {
+            return Err(io::Error::other(format!(
+                "RTSP {} failed: {} {}",
+                method, res.code, res.reason
+            )));
+        }
+
+        Ok(res)
+    }
+
+    /**
+     * DESCRIBE the stream and return its media sections
+     */
+    pub async fn describe(&mut self) -> io::Result<Vec<Media>> {
+        let url = self.url.clone();
+        let res = self
+            .request(
+                "DESCRIBE",
+                &url,
+                &[("Accept", "application/sdp".to_string())],
+            )
+            .await?;
+
+        if let Some(base) = res.header("Content-Base") {
+            self.base = base.to_string();
+        }
+
+        Ok(parse_sdp(&String::from_utf8_lossy(&res.body)))
+    }
+
+    fn control_url(&self, media: &Media) -> String {
+        match media.control.as_deref() {
+            None | Some("*") => self.base.clone(),
+            Some(c) if c.starts_with("rtsp://") => c.to_string(),
+            Some(c) => format!("{}/{}", self.base.trim_end_matches('/'), c),
+        }
+    }
+
+    /**
+     * SETUP a media with RTP/RTCP on the interleaved channels `channel` and `channel + 1`
+     */
+    pub async fn setup(&mut self, media: &Media, channel: u8) -> io::Result<()> {
+        let url = self.control_url(media);
+        let transport = format!(
+            "RTP/AVP/TCP;unicast;interleaved={}-{}",
+            channel,
+            channel + 1
+        );
+        let res = self
+            .request("SETUP", &url, &[("Transport", transport)])
+            .await?;
+
+        if let Some(session) = res.header("Session") {
+            // Session: 12345678;timeout=60
+            let mut parts = session.split(';');
+            self.session = parts.next().map(|s| s.trim().to_string());
+
+            if let Some(timeout) = parts
+                .filter_map(|p| p.trim().strip_prefix("timeout="))
+                .find_map(|t| t.parse::<u64>().ok())
+            {
+                self.timeout = Duration::from_secs(timeout.max(2));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn play(&mut self, headers: &[(&str, String)]) -> io::Result<RtspResponse> {
+        let url = self.base.clone();
+        let res = self.request("PLAY", &url, headers).await?;
+        self.last_keepalive = Instant::now();
+
+        Ok(res)
+    }
+
+    /**
+     * RTP timestamp of a media at the start of playback, from the `RTP-Info` header
+     */
+    pub fn rtptime(&self, res: &RtspResponse, media: &Media) -> Option<u32> {
+        let url = self.control_url(media);
+
+        res.header("RTP-Info")?.split(',').find_map(|entry| {
+            let mut params = entry.split(';').map(|p| p.trim());
+            let entry_url = params.next()?.strip_prefix("url=")?;
+
+            let matches = entry_url == url
+                || media
+                    .control
+                    .as_deref()
+                    .is_some_and(|c| entry_url.ends_with(c));
+            if !matches {
+                return None;
+            }
+
+            params.find_map(|p| p.strip_prefix("rtptime=")?.parse().ok())
+        })
+    }
+
+    /**
+     * Keep the session alive while streaming, the response is read as a message later
+     */
+    pub async fn keepalive(&mut self) -> io::Result<()> {
+        if self.last_keepalive.elapsed() < self.timeout / 2 {
+            return Ok(());
+        }
+
+        self.last_keepalive = Instant::now();
+        let url = self.base.clone();
+        self.send("GET_PARAMETER", &url, &[]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Client reading the given server messages
+     */
+    async fn client(messages: &[u8]) -> RtspClient<tokio::io::DuplexStream> {
+        let (stream, mut server) = tokio::io::duplex(messages.len() + 1);
+        server.write_all(messages).await.unwrap();
+        drop(server);
+
+        RtspClient::new(stream, "rtsp://127.0.0.1/live".to_string(), None)
+    }
+
+    #[test]
+    fn sdp() {
+        let sdp = "v=0\r\n\
+            a=control:*\r\n\
+            m=video 0 RTP/AVP 96\r\n\
+            a=rtpmap:96 H264/90000\r\n\
+            a=fmtp:96 packetization-mode=1; profile-level-id=4D002A;sprop-parameter-sets=Z00AKp2oHgCJ+WbgICAoAAADAAgAAAMBlCA=,aO48gA==\r\n\
+            a=control:trackID=0\r\n\
+            m=audio 0 RTP/AVP 97\r\n\
+            a=rtpmap:97 MPEG4-GENERIC/16000/1\r\n\
+            a=control:trackID=1\r\n";
+
+        let media = parse_sdp(sdp);
+        assert_eq!(media.len(), 2);
+
+        assert_eq!(media[0].kind, "video");
+        assert_eq!(media[0].payload_type, 96);
+        assert_eq!(media[0].encoding, "H264");
+        assert_eq!(media[0].clock_rate, 90000);
+        assert_eq!(media[0].channels, None);
+        assert_eq!(media[0].control.as_deref(), Some("trackID=0"));
+        assert_eq!(
+            media[0].fmtp[2],
+            (
+                "sprop-parameter-sets".to_string(),
+                "Z00AKp2oHgCJ+WbgICAoAAADAAgAAAMBlCA=,aO48gA==".to_string()
+            )
+        );
+
+        assert_eq!(media[1].kind, "audio");
+        assert_eq!(media[1].encoding, "MPEG4-GENERIC");
+        assert_eq!(media[1].clock_rate, 16000);
+        assert_eq!(media[1].channels, Some(1));
+        assert!(media[1].fmtp.is_empty());
+    }
+
+    #[tokio::test]
+    async fn messages() {
+        let mut client = client(
+            b"$\x01\x00\x03abc\
+            RTSP/1.0 200 OK\r\nCSeq: 2\r\nContent-Length: 4\r\n\r\nv=0\n",
+        )
+        .await;
+
+        match client.read_message().await.unwrap() {
+            RtspMessage::Data(channel, data) => {
+                assert_eq!(channel, 1);
+                assert_eq!(data, b"abc");
+            }
+            RtspMessage::Response(_) => panic!("expected data"),
+        }
+
+        match client.read_message().await.unwrap() {
+            RtspMessage::Response(res) => {
+                assert_eq!(res.code, 200);
+                assert_eq!(res.reason, "OK");
+                assert_eq!(res.header("cseq"), Some("2"));
+                assert_eq!(res.body, b"v=0\n");
+            }
+            RtspMessage::Data(..) => panic!("expected a response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_status() {
+        let mut client = client(b"HTTP/1.1 200 OK\r\n\r\n").await;
+        let e = client.read_message().await.err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn body_too_large() {
+        let mut client = client(b"RTSP/1.0 200 OK\r\nContent-Length: 4294967296\r\n\r\n").await;
+        let e = client.read_message().await.err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rtptime() {
+        let client = client(b"").await;
+        let res = RtspResponse {
+            code: 200,
+            reason: "OK".to_string(),
+            headers: vec![(
+                "RTP-Info".to_string(),
+                "url=rtsp://127.0.0.1/live/trackID=0;seq=1;rtptime=1000,url=trackID=1;seq=5;rtptime=2000"
+                    .to_string(),
+            )],
+            body: Vec::new(),
+        };
+
+        let media = |control: &str| Media {
+            control: Some(control.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(client.rtptime(&res, &media("trackID=0")), Some(1000));
+        assert_eq!(client.rtptime(&res, &media("trackID=1")), Some(2000));
+        assert_eq!(client.rtptime(&res, &media("trackID=2")), None);
+    }
+}
@@ -0,0 +1,264 @@
+use clap::ValueEnum;
+use std::time::Duration;
+
+use crate::{
+    codec::{AacConfig, ParameterSets, VideoCodec},
+    fmp4::{init_segment, media_segment, AudioTrack, Sample, TrackRun, VideoTrack},
+    media::MediaFrame,
+    mpegts::TsMuxer,
+    rtp::{AudioFrame, Frame, Timeline},
+};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// MPEG transport stream
+    Ts,
+    /// Fragmented MP4
+    Mp4,
+}
+
+pub enum Chunk {
+    /// Start of a segment at `time` (90 kHz), with the container header
+    Segment {
+        time: i64,
+        header: Vec<u8>,
+    },
+    Data(Vec<u8>),
+}
+
+/**
+ * Muxes frames into segments starting on keyframes
+ */
+pub struct Segmenter {
+    format: Format,
+    codec: VideoCodec,
+    params: ParameterSets,
+    audio: Option<AacConfig>,
+    duration: i64,
+    video_timeline: Timeline,
+    audio_timeline: Timeline,
+    /// Added to timestamps (90 kHz), to continue an earlier stream
+    offset: i64,
+    start: Option<i64>,
+    ts: TsMuxer,
+    sequence: u32,
+    video_frames: Vec<(i64, Frame)>,
+    audio_frames: Vec<(i64, Vec<u8>)>,
+}
+
+fn timeline(rtptime: Option<u32>) -> Timeline {
+    rtptime.map(Timeline::starting_at).unwrap_or_default()
+}
+
+impl Segmenter {
+    pub fn new(
+        format: Format,
+        codec: VideoCodec,
+        params: ParameterSets,
+        audio: Option<AacConfig>,
+        duration: Duration,
+        rtptime: (Option<u32>, Option<u32>),
+    ) -> Segmenter {
+        Segmenter {
+            format,
+            codec,
+            params,
+            ts: TsMuxer::new(codec, audio.is_some()),
+            audio,
+            duration: (duration.as_millis() * 90) as i64,
+            video_timeline: timeline(rtptime.0),
+            audio_timeline: timeline(rtptime.1),
+            offset: 0,
+            start: None,
+            sequence: 0,
+            video_frames: Vec::new(),
+            audio_frames: Vec::new(),
+        }
+    }
+
+    pub fn offset(mut self, offset: i64) -> Segmenter {
+        self.offset = offset;
+        self
+    }
+
+    pub fn push(&mut self, frame: MediaFrame) -> Vec<Chunk> {
+        match frame {
+            MediaFrame::Video(frame) => self.push_video(frame),
+            MediaFrame::Audio(frame) => self.push_audio(frame),
+        }
+    }
+
+    fn header(&mut self) -> Option<Vec<u8>> {
+        match self.format {
+            Format::Ts => {
+                let mut buf = Vec::new();
+                self.ts.write_tables(&mut buf);
+                Some(buf)
+            }
+            Format::Mp4 => {
+                let Some(video) = VideoTrack::new(self.codec, &self.params) else {
+                    eprintln!("Segmenter: waiting for parameter sets");
+                    return None;
+                };
+                let audio = self.audio.clone().map(AudioTrack::new);
+
+                Some(init_segment(&video, audio.as_ref()))
+            }
+        }
+    }
+
+    fn push_video(&mut self, mut frame: Frame) -> Vec<Chunk> {
+        let time = self.video_timeline.extend(frame.timestamp) + self.offset;
+        let codec = self.codec;
+        let mut chunks = Vec::new();
+
+        for nal in &frame.nalus {
+            self.params.update(codec, nal);
+        }
+
+        if frame.keyframe {
+            if let Format::Mp4 = self.format {
+                chunks.extend(self.flush(time));
+            }
+
+            if self.start.is_none_or(|start| time - start >= self.duration) {
+                if let Some(header) = self.header() {
+                    self.start = Some(time);
+                    chunks.push(Chunk::Segment { time, header });
+                }
+            }
+        }
+
+        if self.start.is_none() {
+            // Wait for the first keyframe
+            return chunks;
+        }
+
+        match self.format {
+            Format::Ts => {
+                if frame.keyframe && !frame.nalus.iter().any(|n| codec.is_parameter_set(n)) {
+                    frame.nalus.splice(0..0, self.params.iter().cloned());
+                }
+
+                let mut buf = Vec::new();
+                self.ts.write_video(
+                    &mut buf,
+                    (time + 90000) as u64,
+                    frame.keyframe,
+                    &frame.to_annexb(),
+                );
+                chunks.push(Chunk::Data(buf));
+            }
+            Format::Mp4 => {
+                // Parameter sets are in the sample entry
+                frame.nalus.retain(|n| !codec.is_parameter_set(n));
+                self.video_frames.push((time, frame));
+            }
+        }
+
+        chunks
+    }
+
+    fn push_audio(&mut self, frame: AudioFrame) -> Vec<Chunk> {
+        let time = self.audio_timeline.extend(frame.timestamp);
+
+        let Some(config) = &self.audio else {
+            return Vec::new();
+        };
+        let time = time + self.offset * config.sample_rate as i64 / 90000;
+        if self.start.is_none() {
+            return Vec::new();
+        }
+
+        match self.format {
+            Format::Ts => {
+                let pts = time * 90000 / config.sample_rate as i64 + 90000;
+                let data = [&config.adts_header(frame.data.len())[..], &frame.data].concat();
+
+                let mut buf = Vec::new();
+                self.ts.write_audio(&mut buf, pts.max(0) as u64, &data);
+                vec![Chunk::Data(buf)]
+            }
+            Format::Mp4 => {
+                self.audio_frames.push((time, frame.data));
+                Vec::new()
+            }
+        }
+    }
+
+    /**
+     * Buffered MP4 samples as one fragment, video ending at `end`
+     */
+    fn flush(&mut self, end: i64) -> Option<Chunk> {
+        if self.video_frames.is_empty() {
+            return None;
+        }
+
+        let video: Vec<Sample> = self
+            .video_frames
+            .iter()
+            .enumerate()
+            .map(|(i, (time, frame))| {
+                let next = self.video_frames.get(i + 1).map_or(end, |(t, _)| *t);
+                Sample {
+                    duration: (next - time).max(0) as u32,
+                    keyframe: frame.keyframe,
+                    data: frame.to_avcc(),
+                }
+            })
+            .collect();
+
+        // AAC frames are 1024 samples long
+        let audio: Vec<Sample> = self
+            .audio_frames
+            .iter()
+            .enumerate()
+            .map(|(i, (time, data))| {
+                let next = self
+                    .audio_frames
+                    .get(i + 1)
+                    .map_or(time + 1024, |(t, _)| *t);
+                Sample {
+                    duration: (next - time).max(0) as u32,
+                    keyframe: true,
+                    data: data.clone(),
+                }
+            })
+            .collect();
+
+        let mut runs = vec![TrackRun {
+            track_id: 1,
+            base_time: self.video_frames[0].0.max(0) as u64,
+            samples: &video,
+        }];
+        if let Some((time, _)) = self.audio_frames.first() {
+            runs.push(TrackRun {
+                track_id: 2,
+                base_time: (*time).max(0) as u64,
+                samples: &audio,
+            });
+        }
+
+        self.sequence += 1;
+        let chunk = Chunk::Data(media_segment(self.sequence, &runs));
+
+        self.video_frames.clear();
+        self.audio_frames.clear();
+
+        Some(chunk)
+    }
+
+    /**
+     * Remaining data of the last segment
+     */
+    pub fn finish(&mut self) -> Vec<Chunk> {
+        // Repeat the last frame interval for the final sample
+        let end = match self.video_frames.as_slice() {
+            [.., (a, _), (b, _)] => b + (b - a),
+            [(t, _)] => t + 3600,
+            [] => 0,
+        };
+
+        self.flush(end).into_iter().collect()
+    }
+}
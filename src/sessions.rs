@@ -0,0 +1,89 @@
+use std::{io, sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{net::BindArgs, tunnel::Tunnel};
+
+/**
+ * Runs the P2P handshake when a tunnel is needed, and tears the session
+ * down once no realm has been open for the idle timeout
+ */
+pub struct Sessions {
+    serial: String,
+    relay_mode: bool,
+    bind: BindArgs,
+    /// Current session and when it was last used
+    current: Mutex<Option<(Tunnel, Instant)>>,
+}
+
+impl Sessions {
+    pub fn new(
+        serial: String,
+        relay_mode: bool,
+        bind: BindArgs,
+        idle_timeout: Option<Duration>,
+    ) -> Arc<Sessions> {
+        let sessions = Arc::new(Sessions {
+            serial,
+            relay_mode,
+            bind,
+            current: Mutex::new(None),
+        });
+
+        if let Some(idle_timeout) = idle_timeout {
+            tokio::spawn(sessions.clone().watch(idle_timeout));
+        }
+
+        sessions
+    }
+
+    /**
+     * Get the current session, establishing a new one if there is none or it died.
+     * A failed handshake is tried again on the next call
+     */
+    pub async fn tunnel(&self) -> io::Result<Tunnel> {
+        let mut current = self.current.lock().await;
+
+        if let Some((tunnel, last_used)) = current.as_mut() {
+            if !tunnel.is_closed() {
+                *last_used = Instant::now();
+                return Ok(tunnel.clone());
+            }
+
+            eprintln!("Sessions: PTCP session lost");
+            tunnel.close();
+        }
+
+        let tunnel = Tunnel::connect(self.serial.clone(), self.relay_mode, &self.bind).await?;
+        *current = Some((tunnel.clone(), Instant::now()));
+
+        Ok(tunnel)
+    }
+
+    /**
+     * Close the current session, telling the device about its open realms
+     */
+    pub async fn shutdown(&self) {
+        if let Some((tunnel, _)) = self.current.lock().await.take() {
+            tunnel.shutdown().await;
+        }
+    }
+
+    async fn watch(self: Arc<Self>, idle_timeout: Duration) {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+
+            let mut current = self.current.lock().await;
+            let Some((tunnel, last_used)) = current.as_mut() else {
+                continue;
+            };
+
+            if tunnel.active_realms() > 0 {
+                *last_used = Instant::now();
+            } else if last_used.elapsed() >= idle_timeout || tunnel.is_closed() {
+                eprintln!("Sessions: closing idle PTCP session");
+                tunnel.close();
+                *current = None;
+            }
+        }
+    }
+}
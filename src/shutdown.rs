@@ -0,0 +1,92 @@
+use tokio::{sync::watch, time::Duration};
+
+use crate::{sessions::Sessions, tunnel::Tunnel};
+
+/// Time given to close the realms before exiting
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/**
+ * Wait for SIGINT or SIGTERM
+ */
+#[cfg(unix)]
+async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/**
+ * Wait for Ctrl-C, there is no SIGTERM outside Unix
+ */
+#[cfg(not(unix))]
+async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/**
+ * Shutdown request shared by the tasks of a command, so that each can stop at a point
+ * where it can finish its output
+ */
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /**
+     * Start listening for the signals
+     */
+    pub fn listen() -> Shutdown {
+        let (tx, rx) = watch::channel(false);
+
+        tokio::spawn(async move {
+            signal().await;
+            eprintln!("Shutting down");
+            let _ = tx.send(true);
+        });
+
+        Shutdown(rx)
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /**
+     * Wait until a shutdown is requested
+     */
+    pub async fn requested(&self) {
+        let mut rx = self.0.clone();
+        if rx.wait_for(|requested| *requested).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/**
+ * Close the realms of a session, giving up when the device doesn't answer
+ */
+pub async fn close(tunnel: &Tunnel) {
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, tunnel.shutdown())
+        .await
+        .is_err()
+    {
+        eprintln!("Timed out closing the session");
+        tunnel.close();
+    }
+}
+
+/**
+ * Close the current session of the forward mode
+ */
+pub async fn close_sessions(sessions: &Sessions) {
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, sessions.shutdown())
+        .await
+        .is_err()
+    {
+        eprintln!("Timed out closing the session");
+    }
+}
@@ -0,0 +1,170 @@
+use async_trait::async_trait;
+use clap::Args;
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    auth::Credentials,
+    cgi::CgiClient,
+    http::{Handler, Request, Response},
+    sessions::Sessions,
+    tunnel::Tunnel,
+};
+
+#[derive(Args)]
+pub struct SnapshotArgs {
+    /// Username of the camera
+    #[arg(short, long)]
+    username: Option<String>,
+    /// Password of the camera
+    #[arg(short, long)]
+    password: Option<String>,
+    /// Channel to capture
+    #[arg(short, long, default_value_t = 1)]
+    channel: u32,
+    /// HTTP port of the device
+    #[arg(long, default_value_t = 80)]
+    remote_port: u16,
+    /// Output file. Default: {serial}_ch{channel}_{time}.jpg
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+impl SnapshotArgs {
+    fn credentials(&self) -> Option<Credentials> {
+        Some(Credentials {
+            username: self.username.clone()?,
+            password: self.password.clone().unwrap_or_default(),
+        })
+    }
+}
+
+/**
+ * Fetch a JPEG snapshot of a channel
+ */
+pub async fn capture(client: &CgiClient, channel: u32) -> io::Result<Vec<u8>> {
+    let image = client
+        .get(&format!("/cgi-bin/snapshot.cgi?channel={}", channel))
+        .await?;
+
+    if !image.starts_with(&[0xFF, 0xD8]) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Snapshot is not a JPEG image",
+        ));
+    }
+
+    Ok(image)
+}
+
+/**
+ * Save a snapshot of a channel to a file
+ */
+pub async fn snapshot(tunnel: Tunnel, serial: &str, args: SnapshotArgs) -> io::Result<()> {
+    let client = CgiClient::new(tunnel, args.remote_port, args.credentials());
+    let image = capture(&client, args.channel).await?;
+
+    let path = args.output.unwrap_or_else(|| {
+        PathBuf::from(format!(
+            "{}_ch{}_{}.jpg",
+            serial,
+            args.channel,
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        ))
+    });
+
+    tokio::fs::write(&path, &image)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    eprintln!("Snapshot: saved {} ({} bytes)", path.display(), image.len());
+
+    Ok(())
+}
+
+/**
+ * Serves snapshots at `/snapshot/{serial}/{channel}.jpg`
+ */
+pub struct Snapshot {
+    sessions: Arc<Sessions>,
+    serial: String,
+    remote_port: u16,
+    credentials: Option<Credentials>,
+    /// Client of the current session, keeping its digest challenge between requests
+    client: Mutex<Option<(Tunnel, Arc<CgiClient>)>>,
+}
+
+impl Snapshot {
+    pub fn new(
+        sessions: Arc<Sessions>,
+        serial: String,
+        remote_port: u16,
+        credentials: Option<Credentials>,
+    ) -> Snapshot {
+        Snapshot {
+            sessions,
+            serial,
+            remote_port,
+            credentials,
+            client: Mutex::new(None),
+        }
+    }
+
+    /**
+     * Capture over the current session, establishing it again if it died
+     */
+    async fn capture(&self, channel: u32) -> io::Result<Vec<u8>> {
+        let tunnel = self.sessions.tunnel().await?;
+
+        let client = {
+            let mut current = self.client.lock().unwrap();
+            match current.as_ref() {
+                Some((session, client)) if session.same_session(&tunnel) => client.clone(),
+                _ => {
+                    let client = Arc::new(CgiClient::new(
+                        tunnel.clone(),
+                        self.remote_port,
+                        self.credentials.clone(),
+                    ));
+                    *current = Some((tunnel, client.clone()));
+                    client
+                }
+            }
+        };
+
+        capture(&client, channel).await
+    }
+}
+
+#[async_trait]
+impl Handler for Snapshot {
+    async fn handle(&self, req: Request) -> Response {
+        if req.method != "GET" && req.method != "HEAD" {
+            return Response::error(405);
+        }
+
+        // /snapshot/{serial}/{channel}.jpg
+        let parts: Vec<&str> = req.path.trim_start_matches('/').split('/').collect();
+        let channel = match parts[..] {
+            ["snapshot", serial, file] if serial == self.serial => file
+                .strip_suffix(".jpg")
+                .and_then(|c| c.parse::<u32>().ok()),
+            _ => None,
+        };
+        let Some(channel) = channel else {
+            return Response::error(404);
+        };
+
+        match self.capture(channel).await {
+            Ok(image) => {
+                Response::new(200, "image/jpeg", image).header("Cache-Control", "no-cache")
+            }
+            Err(e) => {
+                eprintln!("Snapshot: channel {}: {}", channel, e);
+                Response::error(502)
+            }
+        }
+    }
+}
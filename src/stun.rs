@@ -0,0 +1,399 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+/// Header length, before the attributes
+const HEADER_LENGTH: usize = 20;
+
+/**
+ * Class of a STUN Binding message
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    Request,
+    Indication,
+    Response,
+    ErrorResponse,
+}
+
+impl MessageType {
+    fn code(self) -> u16 {
+        match self {
+            MessageType::Request => 0x0001,
+            MessageType::Indication => 0x0011,
+            MessageType::Response => 0x0101,
+            MessageType::ErrorResponse => 0x0111,
+        }
+    }
+
+    fn from_code(code: u16) -> Option<MessageType> {
+        match code {
+            0x0001 => Some(MessageType::Request),
+            0x0011 => Some(MessageType::Indication),
+            0x0101 => Some(MessageType::Response),
+            0x0111 => Some(MessageType::ErrorResponse),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Attribute {
+    /// 0x0001, address the message was received from
+    MappedAddress(SocketAddr),
+    /// 0x0004, address of the peer as known by the sender
+    SourceAddress(SocketAddr),
+    /// 0x802a, client ID (`Identify`) of the side starting the exchange
+    ControllingId([u8; 8]),
+    /// 0x8029, client ID of the answering side
+    ControlledId([u8; 8]),
+    Other(u16, Vec<u8>),
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("STUN: {}", message))
+}
+
+fn encode_address(addr: &SocketAddr) -> Vec<u8> {
+    let (family, ip) = match addr.ip() {
+        IpAddr::V4(ip) => (0x01, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (0x02, ip.octets().to_vec()),
+    };
+    [&[0x00, family][..], &addr.port().to_be_bytes(), &ip].concat()
+}
+
+fn decode_address(value: &[u8]) -> io::Result<SocketAddr> {
+    let (family, port, ip) = match value {
+        [_, family, p0, p1, ip @ ..] => (*family, u16::from_be_bytes([*p0, *p1]), ip),
+        _ => return Err(invalid("truncated address")),
+    };
+
+    let ip = match (family, ip.len()) {
+        (0x01, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap())),
+        (0x02, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap())),
+        _ => return Err(invalid("unsupported address")),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+impl Attribute {
+    fn encode(&self) -> (u16, Vec<u8>) {
+        match self {
+            Attribute::MappedAddress(addr) => (0x0001, encode_address(addr)),
+            Attribute::SourceAddress(addr) => (0x0004, encode_address(addr)),
+            Attribute::ControllingId(cid) => (0x802a, cid.to_vec()),
+            Attribute::ControlledId(cid) => (0x8029, cid.to_vec()),
+            Attribute::Other(kind, value) => (*kind, value.clone()),
+        }
+    }
+
+    fn decode(kind: u16, value: &[u8]) -> io::Result<Attribute> {
+        let cid = || -> io::Result<[u8; 8]> {
+            value.try_into().map_err(|_| invalid("invalid client ID"))
+        };
+
+        Ok(match kind {
+            0x0001 => Attribute::MappedAddress(decode_address(value)?),
+            0x0004 => Attribute::SourceAddress(decode_address(value)?),
+            0x802a => Attribute::ControllingId(cid()?),
+            0x8029 => Attribute::ControlledId(cid()?),
+            _ => Attribute::Other(kind, value.to_vec()),
+        })
+    }
+}
+
+/**
+ * A STUN Binding message, sent with every byte inverted.
+ * The cookie is chosen per session instead of the fixed magic cookie
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub kind: MessageType,
+    pub cookie: [u8; 4],
+    pub transaction: [u8; 12],
+    pub attributes: Vec<Attribute>,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut attributes = Vec::new();
+        for attribute in &self.attributes {
+            let (kind, value) = attribute.encode();
+            attributes.extend_from_slice(&kind.to_be_bytes());
+            attributes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            attributes.extend_from_slice(&value);
+            // Attributes are aligned on 4 bytes
+            attributes.resize(attributes.len().next_multiple_of(4), 0);
+        }
+
+        let data = [
+            &self.kind.code().to_be_bytes()[..],
+            &(attributes.len() as u16).to_be_bytes(),
+            &self.cookie,
+            &self.transaction,
+            &attributes,
+        ]
+        .concat();
+
+        data.iter().map(|b| !b).collect()
+    }
+
+    pub fn decode(data: &[u8]) -> io::Result<Message> {
+        let data: Vec<u8> = data.iter().map(|b| !b).collect();
+
+        if data.len() < HEADER_LENGTH {
+            return Err(invalid("truncated header"));
+        }
+
+        let kind = MessageType::from_code(u16::from_be_bytes([data[0], data[1]]))
+            .ok_or_else(|| invalid("not a Binding message"))?;
+        let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if data.len() != HEADER_LENGTH + length {
+            return Err(invalid("invalid length"));
+        }
+
+        let mut attributes = Vec::new();
+        let mut rest = &data[HEADER_LENGTH..];
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return Err(invalid("truncated attribute"));
+            }
+
+            let kind = u16::from_be_bytes([rest[0], rest[1]]);
+            let length = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            let value = rest
+                .get(4..4 + length)
+                .ok_or_else(|| invalid("truncated attribute"))?;
+            attributes.push(Attribute::decode(kind, value)?);
+
+            rest = rest
+                .get((4 + length).next_multiple_of(4)..)
+                .unwrap_or_default();
+        }
+
+        Ok(Message {
+            kind,
+            cookie: data[4..8].try_into().unwrap(),
+            transaction: data[8..20].try_into().unwrap(),
+            attributes,
+        })
+    }
+
+    /**
+     * Answer to this message, with the same transaction
+     */
+    pub fn response(&self, cookie: [u8; 4], attributes: Vec<Attribute>) -> Message {
+        Message {
+            kind: MessageType::Response,
+            cookie,
+            transaction: self.transaction,
+            attributes,
+        }
+    }
+
+    /**
+     * Whether this is the answer to `request`
+     */
+    pub fn answers(&self, request: &Message) -> bool {
+        matches!(
+            self.kind,
+            MessageType::Response | MessageType::ErrorResponse
+        ) && self.cookie == request.cookie
+            && self.transaction == request.transaction
+    }
+
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        self.attributes.iter().find_map(|a| match a {
+            Attribute::MappedAddress(addr) => Some(*addr),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> Message {
+        Message {
+            kind: MessageType::Request,
+            cookie: [1, 2, 3, 4],
+            transaction: [5; 12],
+            attributes: vec![
+                Attribute::ControllingId([0xaa; 8]),
+                Attribute::SourceAddress("192.0.2.1:37777".parse().unwrap()),
+            ],
+        }
+    }
+
+    /**
+     * A message with the given attribute bytes, inverted as on the wire
+     */
+    fn raw(attributes: &[u8]) -> Vec<u8> {
+        let data = [
+            &[0x01, 0x01][..],
+            &(attributes.len() as u16).to_be_bytes(),
+            &[0u8; 16],
+            attributes,
+        ]
+        .concat();
+
+        data.iter().map(|b| !b).collect()
+    }
+
+    fn decode_error(data: &[u8]) -> String {
+        Message::decode(data).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn round_trip() {
+        let kinds = [
+            MessageType::Request,
+            MessageType::Indication,
+            MessageType::Response,
+            MessageType::ErrorResponse,
+        ];
+
+        for kind in kinds {
+            let message = Message {
+                kind,
+                cookie: [0xde, 0xad, 0xbe, 0xef],
+                transaction: [0x11; 12],
+                attributes: vec![
+                    Attribute::MappedAddress("198.51.100.7:40000".parse().unwrap()),
+                    Attribute::SourceAddress("[2001:db8::1]:37777".parse().unwrap()),
+                    Attribute::ControllingId([0xaa; 8]),
+                    Attribute::ControlledId([0xbb; 8]),
+                    // Padded to 4 bytes on the wire
+                    Attribute::Other(0x8022, b"abc".to_vec()),
+                ],
+            };
+
+            let data = message.encode();
+            assert_eq!(data.len() % 4, 0);
+
+            let decoded = Message::decode(&data).unwrap();
+            assert_eq!(decoded, message);
+            assert_eq!(decoded.encode(), data);
+        }
+    }
+
+    #[test]
+    fn inverted_on_the_wire() {
+        let data = request().encode();
+
+        assert_eq!(&data[0..2], &[0xff, 0xfe]);
+        assert_eq!(&data[4..8], &[0xfe, 0xfd, 0xfc, 0xfb]);
+    }
+
+    #[test]
+    fn answers() {
+        let request = request();
+        let mapped = "203.0.113.9:5000".parse().unwrap();
+        let response = request.response([1, 2, 3, 4], vec![Attribute::MappedAddress(mapped)]);
+
+        assert!(response.answers(&request));
+        assert_eq!(response.mapped_address(), Some(mapped));
+
+        let error = Message {
+            kind: MessageType::ErrorResponse,
+            ..response.clone()
+        };
+        assert!(error.answers(&request));
+    }
+
+    #[test]
+    fn answers_rejects_other_exchanges() {
+        let request = request();
+        let response = request.response(request.cookie, vec![]);
+
+        let wrong_cookie = Message {
+            cookie: [4, 3, 2, 1],
+            ..response.clone()
+        };
+        assert!(!wrong_cookie.answers(&request));
+
+        let mut transaction = request.transaction;
+        transaction[11] ^= 1;
+        let wrong_transaction = Message {
+            transaction,
+            ..response.clone()
+        };
+        assert!(!wrong_transaction.answers(&request));
+
+        // A request of the device is not an answer, even with the same transaction
+        let device_request = Message {
+            kind: MessageType::Request,
+            ..response
+        };
+        assert!(!device_request.answers(&request));
+    }
+
+    #[test]
+    fn truncated_header() {
+        let data = request().encode();
+
+        for n in 0..HEADER_LENGTH {
+            assert_eq!(
+                decode_error(&data[..n]),
+                "STUN: truncated header",
+                "{} bytes",
+                n
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_length() {
+        let data = request().encode();
+
+        assert_eq!(
+            decode_error(&data[..data.len() - 4]),
+            "STUN: invalid length"
+        );
+        assert_eq!(
+            decode_error(&[data.as_slice(), &[0xff; 4]].concat()),
+            "STUN: invalid length"
+        );
+    }
+
+    #[test]
+    fn truncated_attributes() {
+        // Attribute header cut short
+        assert_eq!(
+            decode_error(&raw(&[0x80, 0x2a])),
+            "STUN: truncated attribute"
+        );
+
+        // Value shorter than its announced length
+        assert_eq!(
+            decode_error(&raw(&[0x80, 0x2a, 0x00, 0x08, 0xaa, 0xaa, 0xaa, 0xaa])),
+            "STUN: truncated attribute"
+        );
+
+        // Address without its IP
+        assert_eq!(
+            decode_error(&raw(&[0x00, 0x01, 0x00, 0x04, 0x00, 0x01, 0x94, 0x91])),
+            "STUN: unsupported address"
+        );
+        assert_eq!(
+            decode_error(&raw(&[0x00, 0x01, 0x00, 0x02, 0x00, 0x01, 0x00, 0x00])),
+            "STUN: truncated address"
+        );
+
+        // Client ID of the wrong size
+        assert_eq!(
+            decode_error(&raw(&[0x80, 0x29, 0x00, 0x04, 0xbb, 0xbb, 0xbb, 0xbb])),
+            "STUN: invalid client ID"
+        );
+    }
+
+    #[test]
+    fn not_binding() {
+        let mut data = request().encode();
+        data[1] = !0x02;
+
+        assert_eq!(decode_error(&data), "STUN: not a Binding message");
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream},
+    sync::{mpsc, oneshot},
+    task::{AbortHandle, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    dh::p2p_handshake,
+    net::BindArgs,
+    process::{dh_reader, dh_writer, process_reader, process_writer},
+    ptcp::{Datagram, PTCPEvent, PTCPSession},
+};
+
+type Channels = Arc<Mutex<HashMap<u32, mpsc::Sender<Vec<u8>>>>>;
+type ConnChannels = Arc<Mutex<HashMap<u32, oneshot::Sender<bool>>>>;
+
+/// Silence of the device after which the session is considered dead
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time given to the device to accept or refuse a realm
+const BIND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+ * Handle to an established PTCP session, used to open realms to the device
+ */
+#[derive(Clone)]
+pub struct Tunnel {
+    dh_tx: mpsc::Sender<PTCPEvent>,
+    channels: Channels,
+    conn_channels: ConnChannels,
+    tasks: Arc<Vec<AbortHandle>>,
+    last_seen: Arc<Mutex<Instant>>,
+    active: Arc<AtomicUsize>,
+}
+
+/**
+ * Counts a forwarded realm as active until both of its tasks are done
+ */
+struct RealmGuard(Arc<AtomicUsize>);
+
+impl Drop for RealmGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Tunnel {
+    /**
+     * Run the P2P handshake with the device and start the session tasks
+     */
+    pub async fn connect(serial: String, relay_mode: bool, bind: &BindArgs) -> io::Result<Tunnel> {
+        let (socket, session) = p2p_handshake(serial, relay_mode, bind).await?;
+
+        eprintln!("PTCP session established");
+
+        Ok(Tunnel::start(socket, session))
+    }
+
+    /**
+     * Spawn the heartbeat, reader and writer tasks of an established session
+     */
+    pub fn start<S: Datagram + 'static>(socket: S, session: PTCPSession) -> Tunnel {
+        let (dh_tx, dh_rx) = mpsc::channel::<PTCPEvent>(128);
+        let session = Arc::new(Mutex::new(session));
+
+        let channels: Channels = Arc::new(Mutex::new(HashMap::new()));
+        let conn_channels: ConnChannels = Arc::new(Mutex::new(HashMap::new()));
+
+        let reader = Arc::new(socket);
+        let writer = reader.clone();
+
+        let session2 = session.clone();
+        let channels2 = channels.clone();
+        let conn_channels2 = conn_channels.clone();
+        let last_seen = Arc::new(Mutex::new(Instant::now()));
+        let last_seen2 = last_seen.clone();
+
+        let hb_tx = dh_tx.clone();
+        let heartbeat = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                hb_tx.send(PTCPEvent::Heartbeat).await.unwrap();
+            }
+        });
+
+        let writer = tokio::spawn(async move {
+            dh_writer(session, writer, dh_rx).await;
+        });
+
+        let reader = tokio::spawn(async move {
+            dh_reader(session2, reader, channels2, conn_channels2, last_seen2).await;
+        });
+
+        Tunnel {
+            dh_tx,
+            channels,
+            conn_channels,
+            tasks: Arc::new(vec![
+                heartbeat.abort_handle(),
+                writer.abort_handle(),
+                reader.abort_handle(),
+            ]),
+            last_seen,
+            active: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /**
+     * Whether both handles are to the same session
+     */
+    pub fn same_session(&self, other: &Tunnel) -> bool {
+        Arc::ptr_eq(&self.tasks, &other.tasks)
+    }
+
+    /**
+     * Number of forwarded realms still open
+     */
+    pub fn active_realms(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /**
+     * Whether the session died, either silent for too long or its tasks stopped
+     */
+    pub fn is_closed(&self) -> bool {
+        self.tasks.iter().any(|t| t.is_finished())
+            || self.last_seen.lock().unwrap().elapsed() > SESSION_TIMEOUT
+    }
+
+    /**
+     * Wait until the session dies
+     */
+    pub async fn closed(&self) {
+        while !self.is_closed() {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    /**
+     * Stop the session tasks, realms still open are closed with it
+     */
+    pub fn close(&self) {
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+
+        // Dropping the senders shuts the clients down
+        self.channels.lock().unwrap().clear();
+        self.conn_channels.lock().unwrap().clear();
+    }
+
+    /**
+     * Close every open realm with the device, then stop the session
+     */
+    pub async fn shutdown(&self) {
+        let realms: Vec<u32> = self.channels.lock().unwrap().keys().copied().collect();
+
+        for realm in realms {
+            eprintln!("Closing realm {:08x}", realm);
+            let _ = self.dh_tx.send(PTCPEvent::Disconnect(realm)).await;
+        }
+
+        // Wait for the writer to send the DISC statuses
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.dh_tx.send(PTCPEvent::Flush(done_tx)).await.is_ok() {
+            let _ = done_rx.await;
+        }
+
+        self.close();
+    }
+
+    /**
+     * Bind a new realm to the remote port and wait for the device to accept it
+     */
+    async fn bind(&self, remote_port: u16) -> io::Result<(u32, mpsc::Receiver<Vec<u8>>)> {
+        // Create a channel for the realm
+        let (tx, rx) = mpsc::channel::<Vec<u8>>(128);
+        let (conn_tx, conn_rx) = oneshot::channel::<bool>();
+
+        let realm_id = rand::random::<u32>();
+
+        // Store the channel in the map
+        self.channels.lock().unwrap().insert(realm_id, tx);
+        self.conn_channels.lock().unwrap().insert(realm_id, conn_tx);
+
+        if self
+            .dh_tx
+            .send(PTCPEvent::Connect(realm_id, remote_port.into()))
+            .await
+            .is_err()
+        {
+            self.channels.lock().unwrap().remove(&realm_id);
+            self.conn_channels.lock().unwrap().remove(&realm_id);
+
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "PTCP session closed",
+            ));
+        }
+
+        let accepted = match tokio::time::timeout(BIND_TIMEOUT, conn_rx).await {
+            Ok(accepted) => accepted.unwrap_or(false),
+            Err(_) => {
+                self.channels.lock().unwrap().remove(&realm_id);
+                self.conn_channels.lock().unwrap().remove(&realm_id);
+
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "Device did not answer the connection to port {}",
+                        remote_port
+                    ),
+                ));
+            }
+        };
+
+        if !accepted {
+            self.channels.lock().unwrap().remove(&realm_id);
+
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("Device refused connection to port {}", remote_port),
+            ));
+        }
+
+        Ok((realm_id, rx))
+    }
+
+    /**
+     * Forward a client connection through a new realm, returns the reader and writer tasks
+     */
+    pub async fn forward<R, W>(
+        &self,
+        reader: R,
+        writer: W,
+        remote_port: u16,
+    ) -> io::Result<(JoinHandle<()>, JoinHandle<()>)>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (realm_id, rx) = self.bind(remote_port).await?;
+        let dh_tx = self.dh_tx.clone();
+        let channels = self.channels.clone();
+
+        self.active.fetch_add(1, Ordering::SeqCst);
+        let guard = Arc::new(RealmGuard(self.active.clone()));
+        let guard2 = guard.clone();
+
+        let reader = tokio::spawn(async move {
+            process_reader(reader, realm_id, dh_tx).await;
+
+            // The client is gone, let the writer finish
+            channels.lock().unwrap().remove(&realm_id);
+            drop(guard);
+        });
+
+        let writer = tokio::spawn(async move {
+            process_writer(writer, rx).await;
+            drop(guard2);
+        });
+
+        Ok((reader, writer))
+    }
+
+    /**
+     * Open a realm to the remote port as an in-process stream
+     */
+    pub async fn open(&self, remote_port: u16) -> io::Result<DuplexStream> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (reader, writer) = tokio::io::split(server);
+
+        self.forward(reader, writer, remote_port).await?;
+
+        Ok(client)
+    }
+}